@@ -60,7 +60,7 @@ impl Node<(), Payload, InjectedPayload> for BroadcastNode {
         //generate gossip events susign a thread
         std::thread::spawn(move || loop {
             std::thread::sleep(Duration::from_millis(300));
-            if let Err(_) = tx.send(Event::Injected(InjectedPayload::Gossip)) {
+            if tx.send(Event::Injected(InjectedPayload::Gossip)).is_err() {
                 break;
             }
         });
@@ -122,9 +122,7 @@ impl Node<(), Payload, InjectedPayload> for BroadcastNode {
                             .context("reply to topology message")?;
                     }
 
-                    Payload::TopologyOk { .. }
-                    | Payload::BroadcastOk { .. }
-                    | Payload::ReadOk { .. } => {}
+                    Payload::TopologyOk | Payload::BroadcastOk | Payload::ReadOk { .. } => {}
                 }
             }
             Event::Injected(payload) => match payload {
@@ -1,43 +1,121 @@
 /*
     Challenge 4 - counter
+    a pn-counter, so it takes the pn-counter workload's negative deltas too:
+    every node counts its own increments and decrements separately, those
+    totals only ever grow and are gossiped, and a merge keeps the larger of
+    each. the value is all increments minus all decrements.
+    COUNTER_MODE=seq-kv keeps a single shared total in seq-kv instead: adds
+    cas it from the last value seen, re-reading and retrying when another
+    node got there first. seq-kv may serve stale reads, so a read first
+    writes a unique value to a key of our own, which moves our view of the
+    store past everything that came before it. a seq-kv call left unanswered
+    for KV_TIMEOUT is sent again, except a cas: it may have gone through, so
+    the adds it carried fail with a timeout, which doesn't claim they didn't.
 */
 
 use std::{
-    collections::{HashMap, HashSet},
+    collections::HashMap,
+    io::Write,
     str,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use anyhow::Context;
-use ds_challenge::*;
+use ds_challenge::{
+    kv::{KvClient, KvPayload, SEQ_KV},
+    membership::{Membership, MembershipConfig, MembershipPayload},
+    *,
+};
 use serde::{Deserialize, Serialize};
 
+//counter messages plus the swim messages used to track live peers
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+enum Payload {
+    Counter(CounterPayload),
+    Membership(MembershipPayload),
+}
+
+//counter messages plus replies from seq-kv, kv first: its read_ok carries a value too
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+enum SeqKvPayload {
+    Kv(KvPayload),
+    Counter(CounterPayload),
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
-enum Payload {
+enum CounterPayload {
     Add {
-        delta: usize,
+        delta: i64,
     },
     AddOk,
     Read,
     ReadOk {
-        value: usize,
+        value: i64,
     },
     Gossip {
-        latest_values: HashMap<String, usize>,
+        counter: PnCounter,
+    },
+    Error {
+        code: usize,
+        text: String,
     },
 }
+
+//node -> total it has added (increments) or taken away (decrements)
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct PnCounter {
+    increments: HashMap<String, u64>,
+    decrements: HashMap<String, u64>,
+}
+
+impl PnCounter {
+    //totals saturate rather than wrap, a wrapped total would look smaller and lose the merge
+    fn add(&mut self, node: &str, delta: i64) {
+        let totals = if delta < 0 {
+            &mut self.decrements
+        } else {
+            &mut self.increments
+        };
+        let total = totals.entry(node.to_string()).or_insert(0);
+        *total = total.saturating_add(delta.unsigned_abs());
+    }
+
+    fn merge(&mut self, other: PnCounter) {
+        for (mine, theirs) in [
+            (&mut self.increments, other.increments),
+            (&mut self.decrements, other.decrements),
+        ] {
+            for (node, total) in theirs {
+                let own = mine.entry(node).or_insert(total);
+                *own = (*own).max(total);
+            }
+        }
+    }
+
+    //summed in i128, which can't overflow for any realistic number of nodes, then clamped
+    fn value(&self) -> i64 {
+        let sum = |totals: &HashMap<String, u64>| totals.values().map(|t| *t as i128).sum::<i128>();
+        let value = sum(&self.increments) - sum(&self.decrements);
+        value.clamp(i64::MIN as i128, i64::MAX as i128) as i64
+    }
+}
 enum InjectedPayload {
     Gossip,
+    Membership,
 }
 
 struct CounterNode {
     node: String,
     id: usize,
-    value: usize,
-    initial_nodes: HashSet<String>,
-    latest_values: HashMap<String, usize>,
+    //gossip goes to whichever peers the membership layer currently considers live
+    membership: Membership,
+    //asked a seed to admit us, done once on the first membership tick
+    joined: bool,
+    counter: PnCounter,
 }
 
 impl Node<(), Payload, InjectedPayload> for CounterNode {
@@ -50,24 +128,37 @@ impl Node<(), Payload, InjectedPayload> for CounterNode {
         Self: Sized,
     {
         //add thread to gossip lates counter value
+        let gossip_tx = tx.clone();
         std::thread::spawn(move || loop {
             std::thread::sleep(Duration::from_millis(500)); //play around with gossip interval
-            if let Err(_) = tx.send(Event::Injected(InjectedPayload::Gossip)) {
+            if gossip_tx
+                .send(Event::Injected(InjectedPayload::Gossip))
+                .is_err()
+            {
+                break;
+            }
+        });
+        //drive swim probes and timeouts
+        std::thread::spawn(move || loop {
+            std::thread::sleep(Duration::from_millis(50));
+            if tx
+                .send(Event::Injected(InjectedPayload::Membership))
+                .is_err()
+            {
                 break;
             }
         });
         //create node to keep track of own_details and all current known nodes
         Ok(Self {
-            initial_nodes: init
-                .node_ids
-                .clone()
-                .into_iter()
-                .filter(|n| n != &init.node_id)
-                .collect(),
+            membership: Membership::new(
+                init.node_id.clone(),
+                init.node_ids.clone(),
+                MembershipConfig::default(),
+            ),
             node: init.node_id,
             id: 1,
-            value: 0,
-            latest_values: init.node_ids.into_iter().map(|nid| (nid, 0)).collect(),
+            joined: false,
+            counter: PnCounter::default(),
         })
     }
 
@@ -78,54 +169,57 @@ impl Node<(), Payload, InjectedPayload> for CounterNode {
     ) -> anyhow::Result<()> {
         match input {
             Event::Message(input) => {
-                let mut response = input.derive_response(Some(&mut self.id));
-                match response.body.payload {
-                    Payload::Gossip { latest_values } => {
-                        // if self.node == response.src {
-                        //     eprintln!(
-                        //         "GOSSIP TO SELF!!?? self:{}, from:{}, expected dest:{} all_nodes:{:?}, gossiped nodes from src:{:?}",
-                        //         &self.node,
-                        //         &response.src,
-                        //         &response.dest,
-                        //         &self.latest_values,
-                        //         &latest_values,
-                        //     );
-                        // }
-                        for key in latest_values.keys() {
-                            let Some(val) = latest_values.get(key) else {
-                                panic!("erroneous key in know_latest");
-                            };
-                            let own_val =
-                                self.latest_values.entry(key.clone()).or_insert(val.clone());
-                            if val > own_val {
-                                *own_val = *val;
-                            }
-                        }
+                let (payload, input) = input.into_parts();
+                let payload = match payload {
+                    Payload::Counter(payload) => payload,
+                    Payload::Membership(payload) => {
+                        return self.membership.handle(
+                            input.with_payload(payload),
+                            &mut self.id,
+                            &mut *output,
+                        );
                     }
+                };
+                let mut response = input
+                    .with_payload(payload)
+                    .derive_response(Some(&mut self.id));
+                match response.body.payload {
+                    CounterPayload::Gossip { counter } => self.counter.merge(counter),
 
-                    Payload::Add { delta } => {
-                        response.body.payload = Payload::AddOk;
-                        self.value += delta;
+                    CounterPayload::Add { delta } => {
+                        self.counter.add(&self.node, delta);
+                        response.body.payload = CounterPayload::AddOk;
                         response
                             .send_self(&mut *output)
                             .context("add delta failure")?;
-
-                        *self.latest_values.entry(self.node.clone()).or_insert(0) += delta;
                     }
 
-                    Payload::Read => {
-                        let cumulative: usize = self.latest_values.clone().into_values().sum();
-                        response.body.payload = Payload::ReadOk { value: cumulative };
+                    CounterPayload::Read => {
+                        response.body.payload = CounterPayload::ReadOk {
+                            value: self.counter.value(),
+                        };
                         response.send_self(&mut *output).context("read failure")?
                     }
 
-                    Payload::AddOk | Payload::ReadOk { .. } => {}
+                    CounterPayload::AddOk
+                    | CounterPayload::ReadOk { .. }
+                    | CounterPayload::Error { .. } => {}
                 }
             }
 
             Event::Injected(payload) => match payload {
+                InjectedPayload::Membership => {
+                    //a restarted node is still dead to the others until a seed readmits it
+                    if !self.joined {
+                        self.joined = true;
+                        if let Some(seed) = self.membership.peers().into_iter().min() {
+                            self.membership.join(&seed, &mut self.id, &mut *output)?;
+                        }
+                    }
+                    self.membership.tick(&mut self.id, &mut *output)?;
+                }
                 InjectedPayload::Gossip => {
-                    for n in &self.initial_nodes {
+                    for n in &self.membership.peers() {
                         // if n == &self.node {
                         //     eprintln!(
                         //         "found self! {n}, {}, curr nodes: {:?}",
@@ -134,14 +228,14 @@ impl Node<(), Payload, InjectedPayload> for CounterNode {
                         //     continue;
                         // }
 
-                        let msg: Message<Payload> = Message {
+                        let msg: Message<CounterPayload> = Message {
                             src: self.node.clone(),
                             dest: n.clone(),
                             body: Body {
                                 id: Some(self.id),
                                 in_reply_to: None,
-                                payload: Payload::Gossip {
-                                    latest_values: self.latest_values.clone(),
+                                payload: CounterPayload::Gossip {
+                                    counter: self.counter.clone(),
                                 },
                             },
                         };
@@ -151,14 +245,614 @@ impl Node<(), Payload, InjectedPayload> for CounterNode {
                     }
                 }
             },
-            //TODO: handle EOF
-            Event::EOF => {}
+            //shutting down, tell the others instead of waiting to be declared dead
+            Event::EOF => self.membership.leave(&mut self.id, &mut *output)?,
         }
 
         Ok(())
     }
 }
+
+const TOTAL_KEY: &str = "counter";
+
+//how long a seq-kv call may go unanswered before the tick deals with it
+const KV_TIMEOUT: Duration = Duration::from_secs(1);
+
+//what a seq-kv request was for, by its msg_id
+#[derive(Debug)]
+enum Call {
+    //adding the in-flight deltas, `to` is the total if it goes through
+    Cas { to: i64 },
+    //fetching the total after our cas lost to another node's
+    Refresh,
+    //the unique write that brings our view up to date before a read
+    Sync,
+    Read,
+}
+
+struct SeqKvCounterNode {
+    node: String,
+    id: usize,
+    kv: KvClient,
+    //the total as we last saw it, what the next cas expects to find
+    total: i64,
+    //adds waiting for the one cas in flight, which carries all of `adding`
+    queued: Vec<(Message<()>, i64)>,
+    adding: Vec<(Message<()>, i64)>,
+    //reads waiting for the sync in flight, which answers all of `reading`
+    readers: Vec<Message<()>>,
+    reading: Vec<Message<()>>,
+    syncs: usize,
+    //seq-kv calls in flight, with when they were sent
+    calls: HashMap<usize, (Call, Instant)>,
+}
+
+impl SeqKvCounterNode {
+    fn new(node: String) -> Self {
+        Self {
+            kv: KvClient::new(node.clone(), SEQ_KV),
+            node,
+            id: 1,
+            total: 0,
+            queued: Vec::new(),
+            adding: Vec::new(),
+            readers: Vec::new(),
+            reading: Vec::new(),
+            syncs: 0,
+            calls: HashMap::new(),
+        }
+    }
+
+    fn handle(
+        &mut self,
+        input: Event<SeqKvPayload, ()>,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        let input = match input {
+            Event::Message(input) => input,
+            Event::Injected(()) => return self.tick(output),
+            Event::EOF => return Ok(()),
+        };
+        let (payload, input) = input.into_parts();
+        match payload {
+            SeqKvPayload::Kv(payload) => {
+                return self.kv_reply(input.body.in_reply_to, payload, output);
+            }
+            SeqKvPayload::Counter(CounterPayload::Add { delta }) => {
+                self.queued.push((input, delta));
+                self.flush_adds(output)?;
+            }
+            SeqKvPayload::Counter(CounterPayload::Read) => {
+                self.readers.push(input);
+                self.start_read(output)?;
+            }
+            SeqKvPayload::Counter(_) => {}
+        }
+        Ok(())
+    }
+
+    //batch everything queued into one cas, unless one is already out
+    fn flush_adds(&mut self, output: &mut impl Write) -> anyhow::Result<()> {
+        if !self.adding.is_empty() || self.queued.is_empty() {
+            return Ok(());
+        }
+        self.adding = std::mem::take(&mut self.queued);
+        self.send_cas(output)
+    }
+
+    fn send_cas(&mut self, output: &mut impl Write) -> anyhow::Result<()> {
+        let delta = self
+            .adding
+            .iter()
+            .fold(0i64, |sum, (_, delta)| sum.saturating_add(*delta));
+        let to = self.total.saturating_add(delta);
+        let msg_id = self.kv.cas(
+            TOTAL_KEY.to_string(),
+            self.total,
+            to,
+            true,
+            &mut self.id,
+            &mut *output,
+        )?;
+        self.calls
+            .insert(msg_id, (Call::Cas { to }, Instant::now()));
+        Ok(())
+    }
+
+    //`call` is Refresh or Read, both just want the current total
+    fn read_total(&mut self, call: Call, output: &mut impl Write) -> anyhow::Result<()> {
+        let msg_id = self
+            .kv
+            .read(TOTAL_KEY.to_string(), &mut self.id, &mut *output)?;
+        self.calls.insert(msg_id, (call, Instant::now()));
+        Ok(())
+    }
+
+    fn send_sync(&mut self, output: &mut impl Write) -> anyhow::Result<()> {
+        let key = format!("sync/{}", self.node);
+        let value = format!("{}-{}", self.node, self.syncs);
+        let msg_id = self.kv.write(key, value, &mut self.id, &mut *output)?;
+        self.calls.insert(msg_id, (Call::Sync, Instant::now()));
+        Ok(())
+    }
+
+    fn start_read(&mut self, output: &mut impl Write) -> anyhow::Result<()> {
+        if !self.reading.is_empty() || self.readers.is_empty() {
+            return Ok(());
+        }
+        self.reading = std::mem::take(&mut self.readers);
+        self.syncs += 1;
+        self.send_sync(output)
+    }
+
+    //calls seq-kv never answered. reads and syncs can simply be sent again, a cas may
+    //have been applied and only its reply lost, so retrying it could add twice
+    fn tick(&mut self, output: &mut impl Write) -> anyhow::Result<()> {
+        let expired: Vec<usize> = self
+            .calls
+            .iter()
+            .filter(|(_, (_, sent))| sent.elapsed() > KV_TIMEOUT)
+            .map(|(msg_id, _)| *msg_id)
+            .collect();
+        for msg_id in expired {
+            let Some((call, _)) = self.calls.remove(&msg_id) else {
+                continue;
+            };
+            match call {
+                Call::Cas { .. } => {
+                    let adding = std::mem::take(&mut self.adding);
+                    for (request, _) in adding {
+                        self.fail(request, ErrorCode::Timeout, output)?;
+                    }
+                    self.flush_adds(output)?;
+                }
+                Call::Refresh | Call::Read => self.read_total(call, output)?,
+                Call::Sync => self.send_sync(output)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn kv_reply(
+        &mut self,
+        in_reply_to: Option<usize>,
+        payload: KvPayload,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        let Some((call, _)) = in_reply_to.and_then(|id| self.calls.remove(&id)) else {
+            return Ok(());
+        };
+        let Some(result) = payload.into_result() else {
+            return Ok(());
+        };
+        match (call, result) {
+            (Call::Cas { to }, Ok(_)) => {
+                self.total = to;
+                let adding = std::mem::take(&mut self.adding);
+                for (request, _) in adding {
+                    self.respond(request, CounterPayload::AddOk, output)?;
+                }
+                self.flush_adds(output)?;
+            }
+            //someone else moved the total, find out where to and try again
+            (Call::Cas { .. }, Err(ErrorCode::PreconditionFailed)) => {
+                self.read_total(Call::Refresh, output)?;
+            }
+            (Call::Refresh, Ok(total)) => {
+                self.total = total.as_i64().unwrap_or(0);
+                self.send_cas(output)?;
+            }
+            (Call::Refresh, Err(ErrorCode::KeyDoesNotExist)) => {
+                self.total = 0;
+                self.send_cas(output)?;
+            }
+            (Call::Cas { .. } | Call::Refresh, Err(code)) => {
+                let adding = std::mem::take(&mut self.adding);
+                for (request, _) in adding {
+                    self.fail(request, code, output)?;
+                }
+                self.flush_adds(output)?;
+            }
+
+            (Call::Sync, Ok(_)) => self.read_total(Call::Read, output)?,
+            (Call::Read, Ok(total)) => self.answer_reads(total.as_i64().unwrap_or(0), output)?,
+            //nobody has added anything yet
+            (Call::Read, Err(ErrorCode::KeyDoesNotExist)) => self.answer_reads(0, output)?,
+            (Call::Sync | Call::Read, Err(code)) => {
+                let reading = std::mem::take(&mut self.reading);
+                for request in reading {
+                    self.fail(request, code, output)?;
+                }
+                self.start_read(output)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn answer_reads(&mut self, value: i64, output: &mut impl Write) -> anyhow::Result<()> {
+        //as fresh as anything we've seen, so a good guess for the next cas
+        self.total = value;
+        let reading = std::mem::take(&mut self.reading);
+        for request in reading {
+            self.respond(request, CounterPayload::ReadOk { value }, output)?;
+        }
+        self.start_read(output)
+    }
+
+    fn fail(
+        &mut self,
+        request: Message<()>,
+        code: ErrorCode,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        let error = CounterPayload::Error {
+            code: code.code(),
+            text: format!("{SEQ_KV} request failed"),
+        };
+        self.respond(request, error, output)
+    }
+
+    fn respond(
+        &mut self,
+        request: Message<()>,
+        payload: CounterPayload,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        request
+            .with_payload(payload)
+            .derive_response(Some(&mut self.id))
+            .send_self(&mut *output)
+            .context("respond to counter client")
+    }
+}
+
+//the only thing injected is the tick that times out seq-kv calls
+impl Node<(), SeqKvPayload, ()> for SeqKvCounterNode {
+    fn from_init(
+        _state: (),
+        init: Init,
+        tx: std::sync::mpsc::Sender<Event<SeqKvPayload, ()>>,
+    ) -> anyhow::Result<Self>
+    where
+        Self: Sized,
+    {
+        std::thread::spawn(move || loop {
+            std::thread::sleep(Duration::from_millis(100));
+            if tx.send(Event::Injected(())).is_err() {
+                break;
+            }
+        });
+
+        Ok(Self::new(init.node_id))
+    }
+
+    fn handle_input(
+        &mut self,
+        input: Event<SeqKvPayload, ()>,
+        output: &mut std::io::StdoutLock,
+    ) -> anyhow::Result<()> {
+        self.handle(input, output)
+    }
+}
+
 fn main() -> anyhow::Result<()> {
     //'_' represent unused state, node, Payload and InjectedPayload generics for <S, N, P>
-    main_loop::<_, CounterNode, _, _>(())
+    match std::env::var("COUNTER_MODE").as_deref() {
+        Ok("seq-kv") => main_loop::<_, SeqKvCounterNode, _, _>(()),
+        _ => main_loop::<_, CounterNode, _, _>(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use serde_json::Value;
+
+    use super::*;
+
+    fn counter(adds: &[(&str, i64)]) -> PnCounter {
+        let mut counter = PnCounter::default();
+        for (node, delta) in adds {
+            counter.add(node, *delta);
+        }
+        counter
+    }
+
+    //n0 adds i64::MAX `times` times
+    fn counter_of_max(times: usize) -> PnCounter {
+        counter(&vec![("n0", i64::MAX); times])
+    }
+
+    #[test]
+    fn signed_deltas_go_to_separate_totals() {
+        let counter = counter(&[("n0", 5), ("n0", -2), ("n1", -4), ("n1", 0)]);
+        assert_eq!(counter.increments["n0"], 5);
+        assert_eq!(counter.decrements["n0"], 2);
+        assert_eq!(counter.decrements["n1"], 4);
+        assert_eq!(counter.value(), -1);
+    }
+
+    #[test]
+    fn merge_keeps_the_larger_total_per_node() {
+        let mut a = counter(&[("n0", 5), ("n0", -1), ("n1", 2)]);
+        //n1 has moved on since a heard from it, n0's totals in b are stale
+        let b = counter(&[("n0", 3), ("n1", 2), ("n1", 4), ("n1", -3)]);
+        a.merge(b.clone());
+        assert_eq!(a.increments["n0"], 5);
+        assert_eq!(a.decrements["n0"], 1);
+        assert_eq!(a.increments["n1"], 6);
+        assert_eq!(a.decrements["n1"], 3);
+        assert_eq!(a.value(), 7);
+
+        //merging again, or the other way round, ends up in the same place
+        let merged = a.clone();
+        a.merge(b.clone());
+        assert_eq!(a.value(), merged.value());
+        let mut b = b;
+        b.merge(merged);
+        assert_eq!(b.value(), 7);
+    }
+
+    #[test]
+    fn extreme_deltas_saturate_instead_of_wrapping() {
+        let counter = counter(&[("n0", i64::MIN)]);
+        assert_eq!(counter.decrements["n0"], i64::MIN.unsigned_abs());
+        assert_eq!(counter.value(), i64::MIN);
+
+        let counter = counter_of_max(3);
+        assert_eq!(counter.increments["n0"], u64::MAX);
+        //clamped rather than wrapped
+        assert_eq!(counter.value(), i64::MAX);
+
+        let mut both = counter_of_max(2);
+        both.add("n1", i64::MIN);
+        both.add("n1", i64::MIN);
+        //2^64 - 2 added, 2^64 taken away but that total stops at 2^64 - 1
+        assert_eq!(both.decrements["n1"], u64::MAX);
+        assert_eq!(both.value(), -1);
+    }
+
+    #[test]
+    fn a_stale_merge_never_lowers_a_saturated_total() {
+        let mut a = counter_of_max(3);
+        a.merge(counter(&[("n0", 1)]));
+        assert_eq!(a.increments["n0"], u64::MAX);
+        assert_eq!(a.value(), i64::MAX);
+    }
+
+    //a seq-kv counter node n0 with an in-memory seq-kv, which answers only when asked to
+    struct SeqKv {
+        node: SeqKvCounterNode,
+        store: HashMap<String, Value>,
+        //requests to seq-kv not answered yet, oldest first
+        requests: VecDeque<Message<KvPayload>>,
+        //what the client got back, by the msg_id of its request
+        replies: HashMap<usize, CounterPayload>,
+        next_id: usize,
+    }
+
+    impl SeqKv {
+        fn new() -> Self {
+            Self {
+                node: SeqKvCounterNode::new("n0".to_string()),
+                store: HashMap::new(),
+                requests: VecDeque::new(),
+                replies: HashMap::new(),
+                next_id: 1000,
+            }
+        }
+
+        //feed the node a message as it would come off the wire
+        fn deliver<P: Serialize>(&mut self, message: Message<P>) {
+            let line = serde_json::to_string(&message).unwrap();
+            let event = Event::Message(serde_json::from_str(&line).unwrap());
+            self.handle(event);
+        }
+
+        fn handle(&mut self, event: Event<SeqKvPayload, ()>) {
+            let mut output = Vec::new();
+            self.node.handle(event, &mut output).unwrap();
+            for line in output.split(|b| *b == b'\n').filter(|l| !l.is_empty()) {
+                let message: Message<Value> = serde_json::from_slice(line).unwrap();
+                if message.dest == SEQ_KV {
+                    self.requests
+                        .push_back(serde_json::from_slice(line).unwrap());
+                } else {
+                    let reply: Message<CounterPayload> = serde_json::from_slice(line).unwrap();
+                    let request = reply.body.in_reply_to.unwrap();
+                    self.replies.insert(request, reply.body.payload);
+                }
+            }
+        }
+
+        fn client(&mut self, payload: CounterPayload) -> usize {
+            self.next_id += 1;
+            let id = self.next_id;
+            self.deliver(Message {
+                src: "c1".to_string(),
+                dest: "n0".to_string(),
+                body: Body {
+                    id: Some(id),
+                    in_reply_to: None,
+                    payload,
+                },
+            });
+            id
+        }
+
+        fn apply(&mut self, request: KvPayload) -> KvPayload {
+            let error = |code: ErrorCode| KvPayload::Error {
+                code: code.code(),
+                text: String::new(),
+            };
+            match request {
+                KvPayload::Read { key } => match self.store.get(&key) {
+                    Some(value) => KvPayload::ReadOk {
+                        value: value.clone(),
+                    },
+                    None => error(ErrorCode::KeyDoesNotExist),
+                },
+                KvPayload::Write { key, value } => {
+                    self.store.insert(key, value);
+                    KvPayload::WriteOk
+                }
+                KvPayload::Cas {
+                    key,
+                    from,
+                    to,
+                    create_if_not_exists,
+                } => match self.store.get(&key) {
+                    Some(current) if *current == from => {
+                        self.store.insert(key, to);
+                        KvPayload::CasOk
+                    }
+                    Some(_) => error(ErrorCode::PreconditionFailed),
+                    None if create_if_not_exists => {
+                        self.store.insert(key, to);
+                        KvPayload::CasOk
+                    }
+                    None => error(ErrorCode::KeyDoesNotExist),
+                },
+                reply => panic!("{reply:?} sent to seq-kv"),
+            }
+        }
+
+        //apply the oldest request, `answer` false loses the reply on the way back
+        fn step(&mut self, answer: bool) -> KvPayload {
+            let request = self.requests.pop_front().expect("a request to seq-kv");
+            let sent = request.body.payload.clone();
+            let reply = self.apply(request.body.payload);
+            if answer {
+                self.deliver(Message {
+                    src: SEQ_KV.to_string(),
+                    dest: "n0".to_string(),
+                    body: Body {
+                        id: None,
+                        in_reply_to: request.body.id,
+                        payload: reply,
+                    },
+                });
+            }
+            sent
+        }
+
+        //answer everything until the node stops asking
+        fn settle(&mut self) -> Vec<KvPayload> {
+            let mut sent = Vec::new();
+            while !self.requests.is_empty() {
+                sent.push(self.step(true));
+            }
+            sent
+        }
+
+        //every call in flight has waited past KV_TIMEOUT, then the tick runs
+        fn expire(&mut self) {
+            let long_ago = Instant::now().checked_sub(KV_TIMEOUT * 2).unwrap();
+            for (_, sent) in self.node.calls.values_mut() {
+                *sent = long_ago;
+            }
+            self.handle(Event::Injected(()));
+        }
+
+        fn total(&self) -> Option<i64> {
+            self.store.get(TOTAL_KEY)?.as_i64()
+        }
+    }
+
+    fn is_error(reply: Option<&CounterPayload>, code: ErrorCode) -> bool {
+        matches!(reply, Some(CounterPayload::Error { code: c, .. }) if *c == code.code())
+    }
+
+    #[test]
+    fn read_after_an_acknowledged_add_sees_it() {
+        let mut kv = SeqKv::new();
+        let read = kv.client(CounterPayload::Read);
+        kv.settle();
+        assert!(matches!(
+            kv.replies[&read],
+            CounterPayload::ReadOk { value: 0 }
+        ));
+
+        let add = kv.client(CounterPayload::Add { delta: 5 });
+        kv.settle();
+        assert!(matches!(kv.replies[&add], CounterPayload::AddOk));
+        //another node adds behind our back
+        kv.store.insert(TOTAL_KEY.to_string(), 12.into());
+
+        let read = kv.client(CounterPayload::Read);
+        let sent = kv.settle();
+        assert!(matches!(
+            kv.replies[&read],
+            CounterPayload::ReadOk { value: 12 }
+        ));
+        //the read went out behind a write to our own sync key
+        assert!(matches!(&sent[0], KvPayload::Write { key, .. } if key == "sync/n0"));
+        assert!(matches!(&sent[1], KvPayload::Read { key } if key == TOTAL_KEY));
+        assert_eq!(kv.store["sync/n0"], "n0-2");
+    }
+
+    #[test]
+    fn cas_lost_to_another_node_is_retried_from_the_new_total() {
+        let mut kv = SeqKv::new();
+        kv.store.insert(TOTAL_KEY.to_string(), 10.into());
+        let add = kv.client(CounterPayload::Add { delta: -3 });
+        let sent = kv.settle();
+        assert!(matches!(kv.replies[&add], CounterPayload::AddOk));
+        assert_eq!(kv.total(), Some(7));
+        //cas from 0, read the total, cas again from 10
+        assert_eq!(sent.len(), 3);
+        assert!(matches!(&sent[2], KvPayload::Cas { from, to, .. } if *from == 10 && *to == 7));
+    }
+
+    #[test]
+    fn adds_while_a_cas_is_out_go_together_in_the_next_one() {
+        let mut kv = SeqKv::new();
+        let first = kv.client(CounterPayload::Add { delta: 1 });
+        let second = kv.client(CounterPayload::Add { delta: 2 });
+        let third = kv.client(CounterPayload::Add { delta: 3 });
+        assert_eq!(kv.requests.len(), 1);
+        let sent = kv.settle();
+        assert_eq!(sent.len(), 2);
+        for add in [first, second, third] {
+            assert!(matches!(kv.replies[&add], CounterPayload::AddOk));
+        }
+        assert_eq!(kv.total(), Some(6));
+    }
+
+    #[test]
+    fn unanswered_syncs_and_reads_are_sent_again() {
+        let mut kv = SeqKv::new();
+        kv.store.insert(TOTAL_KEY.to_string(), 4.into());
+        let read = kv.client(CounterPayload::Read);
+        //the sync is lost, then the read of the total
+        kv.step(false);
+        kv.expire();
+        kv.step(true);
+        kv.step(false);
+        assert!(kv.replies.is_empty());
+        kv.expire();
+        kv.settle();
+        assert!(matches!(
+            kv.replies[&read],
+            CounterPayload::ReadOk { value: 4 }
+        ));
+    }
+
+    #[test]
+    fn unanswered_cas_fails_its_adds_without_adding_twice() {
+        let mut kv = SeqKv::new();
+        let lost = kv.client(CounterPayload::Add { delta: 2 });
+        let queued = kv.client(CounterPayload::Add { delta: 5 });
+        //applied, but the node never hears so
+        kv.step(false);
+        assert_eq!(kv.total(), Some(2));
+        kv.expire();
+        assert!(is_error(kv.replies.get(&lost), ErrorCode::Timeout));
+
+        //the add queued behind it still goes through, from the real total
+        kv.settle();
+        assert!(matches!(kv.replies[&queued], CounterPayload::AddOk));
+        assert_eq!(kv.total(), Some(7));
+    }
 }
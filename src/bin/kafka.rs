@@ -0,0 +1,1935 @@
+/*
+    challenge 5b/5c kafka style log, keys owned by replica sets on a hash ring.
+    a key's leader hands out offsets and acks a record once every in-sync
+    replica has it (log_replication.rs), who leads is agreed on in lin-kv and
+    an in-sync follower takes over from a dead leader (leadership.rs), each
+    replica keeps, retains and persists its keys' logs (log_partition.rs).
+    other nodes forward requests to a key's leader and relay the reply.
+    a commit_offsets asks every owner first and writes nothing if any key
+    would go backwards. a send may carry a producer id and sequence number
+    to deduplicate retries. a poll with `wait_ms` is parked until records
+    arrive or the wait is over. send_batch is a transaction across keys,
+    coordinated by the node that gets it. its records show up on every key
+    or on none: a leader holds polls while a batch that may be committed
+    is undecided on its key, and a poll over several keys is cut short of
+    any batch it can't show whole. lin-kv is required.
+    KAFKA_REPLICAS sets the number of replicas per key (default 3)
+*/
+
+use ds_challenge::{
+    kv::{KvClient, KvPayload, KvResult, LIN_KV},
+    leadership::{can_propose, is_live, leadership_key, Change, Leadership},
+    log_partition::{
+        dir_key, key_dir, storage_from_env, BatchSpan, Partition, Record, Retention, Sequenced,
+        TxnId, Waiter,
+    },
+    log_replication::Txns,
+    membership::{Membership, MembershipConfig, MembershipPayload},
+    partition::HashRing,
+    records::PollLimits,
+    segment::{SegmentConfig, SegmentLog},
+    *,
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+//client and replica messages, lin-kv replies and the swim messages that tell which
+//nodes are up. kv first: an owner's error reply reads as a kv error and is handed on
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+enum Payload {
+    Kv(KvPayload),
+    Kafka(KafkaPayload),
+    Membership(MembershipPayload),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+enum KafkaPayload {
+    Send {
+        key: String,
+        msg: usize,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        producer: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<usize>,
+    },
+    SendOk {
+        offset: usize,
+    },
+    Poll {
+        offsets: HashMap<String, usize>,
+        #[serde(flatten)]
+        limits: PollLimits,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        wait_ms: Option<u64>,
+    },
+    PollOk {
+        msgs: HashMap<String, Vec<(usize, usize)>>,
+        //keys whose requested offset was already dropped, with their first offset left
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        truncated: HashMap<String, usize>,
+        //between nodes only: where each polled key's records were visible to, and per
+        //key the batches spanning keys its records belong to, where each starts on the
+        //key and ends on every key
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        reach: HashMap<String, usize>,
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        batches: HashMap<String, Spans>,
+    },
+    CommitOffsets {
+        offsets: HashMap<String, usize>,
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        group: String,
+        //set between nodes once the coordinator found nothing would go backwards:
+        //owners just keep the higher offset
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        checked: bool,
+    },
+    CommitOffsetsOk,
+    ListCommittedOffsets {
+        keys: Vec<String>,
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        group: String,
+    },
+    ListCommittedOffsetsOk {
+        offsets: HashMap<String, usize>,
+    },
+    //leader -> follower: records from the follower's log end on, plus the leader's progress
+    Replicate {
+        key: String,
+        leadership: Leadership,
+        //where the leader's log starts, a follower missing everything before it skips ahead
+        start: usize,
+        records: Vec<(usize, Record)>,
+        hwm: usize,
+        //group -> committed offset
+        committed: HashMap<String, usize>,
+        //transaction state of the records, so a follower that takes over knows it.
+        //pairs rather than a map, integer map keys don't survive the untagged payload
+        pending: Vec<(usize, TxnId)>,
+        aborted: BTreeSet<usize>,
+        batches: Vec<(usize, BatchSpan)>,
+    },
+    //also the answer to a deposed leader, whose leadership is older than the follower's
+    ReplicateOk {
+        key: String,
+        end: usize,
+        leadership: Leadership,
+    },
+    //a key's leader changed in lin-kv, sent by whoever changed it to the other replicas
+    Elected {
+        key: String,
+        leadership: Leadership,
+    },
+    SendBatch {
+        msgs: HashMap<String, Vec<usize>>,
+    },
+    SendBatchOk {
+        offsets: HashMap<String, Vec<usize>>,
+    },
+    //coordinator -> leader: append these as one transaction's records, hidden until decided
+    TxnPrepare {
+        txn: usize,
+        msgs: HashMap<String, Vec<usize>>,
+    },
+    //leader -> coordinator: one key's share is appended and replicated
+    TxnPrepareOk {
+        txn: usize,
+        key: String,
+        offsets: Vec<usize>,
+    },
+    TxnDecide {
+        txn: usize,
+        commit: bool,
+        //a commit's last offset on each key
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        ends: HashMap<String, usize>,
+    },
+    //leader -> coordinator: what became of a transaction we still hold records for
+    TxnStatus {
+        txn: usize,
+    },
+    //leader -> coordinator: every in-sync replica of the key has a committed
+    //transaction's records visible, nobody will ask about it for that key again
+    TxnResolved {
+        txn: usize,
+        key: String,
+    },
+    Error {
+        code: usize,
+        text: String,
+    },
+}
+
+enum InjectedPayload {
+    Tick,
+}
+
+const DEFAULT_REPLICAS: usize = 3;
+//a batch not prepared on every key by then is aborted
+const TXN_TIMEOUT: Duration = Duration::from_millis(2000);
+//how long a leader holds undecided records before asking the coordinator about them
+const TXN_RESOLVE: Duration = Duration::from_millis(1000);
+//a forwarded request some owner hasn't answered by then gets an error instead
+const FORWARD_TIMEOUT: Duration = Duration::from_millis(2000);
+
+//a send_batch this node coordinates
+struct Batch {
+    request: Message<()>,
+    //keys not prepared yet
+    waiting: usize,
+    offsets: HashMap<String, Vec<usize>>,
+    leaders: Vec<String>,
+    started: Instant,
+}
+
+//a committed send_batch this node coordinated
+struct Decided {
+    //its last offset on each key
+    ends: HashMap<String, usize>,
+    //keys whose leader hasn't resolved it everywhere yet
+    unresolved: HashSet<String>,
+}
+
+//batches on a key: where each starts there, and its last offset on every key
+type Spans = Vec<(usize, HashMap<String, usize>)>;
+//keyed values of a request, grouped by the node that owns each key
+type Shares<T> = HashMap<String, Vec<(String, T)>>;
+//the keys we own ourselves and everyone else's shares
+type Split<T> = (Vec<(String, T)>, Shares<T>);
+
+//a poll waiting for a transaction to be resolved on one of our keys
+struct Held {
+    request: Message<()>,
+    offsets: HashMap<String, usize>,
+    limits: PollLimits,
+    wait_ms: Option<u64>,
+    //it fails if still held by then
+    deadline: Instant,
+}
+
+//a client request waiting on the owners of some of its keys
+struct Forwarded {
+    request: Message<()>,
+    waiting: usize,
+    //replies merged so far, starting with the keys we own ourselves
+    reply: KafkaPayload,
+    //set for a parked poll: our own share of it, looked at again as records arrive
+    long_poll: Option<(Vec<(String, usize)>, PollLimits)>,
+    //checked every tick: a parked poll is answered with whatever it has, anything
+    //else with an error
+    deadline: Instant,
+    //set for the two rounds of a commit_offsets
+    commit: Option<Commit>,
+}
+
+enum Commit {
+    //asking the owners where the group is, with the offsets and group to commit after
+    Check(HashMap<String, usize>, String),
+    //owners committing, some may have by the time another fails
+    Apply,
+}
+
+//fold an owner's reply into what a forwarded request has gathered so far
+fn merge(reply: &mut KafkaPayload, payload: KafkaPayload) {
+    match (reply, payload) {
+        (
+            KafkaPayload::PollOk {
+                msgs,
+                truncated,
+                reach,
+                batches,
+            },
+            KafkaPayload::PollOk {
+                msgs: their_msgs,
+                truncated: their_truncated,
+                reach: their_reach,
+                batches: their_batches,
+            },
+        ) => {
+            msgs.extend(their_msgs);
+            truncated.extend(their_truncated);
+            reach.extend(their_reach);
+            batches.extend(their_batches);
+        }
+        (
+            KafkaPayload::ListCommittedOffsetsOk { offsets },
+            KafkaPayload::ListCommittedOffsetsOk { offsets: theirs },
+        ) => offsets.extend(theirs),
+        //one owner failing fails the whole request
+        (KafkaPayload::Error { .. }, _) => {}
+        (reply, payload) => *reply = payload,
+    }
+}
+
+//cut each key's records short of any batch the reply can't show whole: one ending past
+//where another polled key was visible to, it wasn't resolved there yet when that key
+//was read. a cut key is visible to less, which can do the same to another batch, so
+//this goes on until nothing changes
+fn settle(reply: &mut KafkaPayload) {
+    let KafkaPayload::PollOk {
+        msgs,
+        reach,
+        batches,
+        ..
+    } = reply
+    else {
+        return;
+    };
+    loop {
+        let cut = batches.iter().find_map(|(key, spans)| {
+            spans
+                .iter()
+                .filter(|(_, ends)| {
+                    ends.iter()
+                        .any(|(other, last)| reach.get(other).is_some_and(|reach| last >= reach))
+                })
+                .map(|(first, _)| *first)
+                .min()
+                .map(|first| (key.clone(), first))
+        });
+        let Some((key, first)) = cut else {
+            return;
+        };
+        if let Some(records) = msgs.get_mut(&key) {
+            records.retain(|(offset, _)| *offset < first);
+            if records.is_empty() {
+                msgs.remove(&key);
+            }
+        }
+        if let Some(spans) = batches.get_mut(&key) {
+            spans.retain(|(start, _)| *start < first);
+        }
+        let reach = reach.entry(key).or_insert(first);
+        *reach = (*reach).min(first);
+    }
+}
+
+fn has_msgs(reply: &KafkaPayload) -> bool {
+    matches!(reply, KafkaPayload::PollOk { msgs, .. } if !msgs.is_empty())
+}
+
+struct KafkaNode {
+    node: String,
+    id: usize,
+    //over the nodes membership says are up, rebuilt when that changes
+    ring: HashRing,
+    members: Vec<String>,
+    replicas: usize,
+    retention: Retention,
+    storage: Option<(PathBuf, SegmentConfig)>,
+    partitions: HashMap<String, Partition>,
+    ops: HashMap<usize, Forwarded>,
+    next_op: usize,
+    //send_batches we coordinate, by txn
+    batches: HashMap<usize, Batch>,
+    next_txn: usize,
+    //committed txns we coordinated and haven't forgotten yet. any other txn of ours
+    //that isn't running was aborted
+    decided: HashMap<usize, Decided>,
+    //leader side: undecided transactions, and when we last asked about them
+    in_doubt: HashMap<TxnId, Instant>,
+    //forwarded msg_id -> op
+    calls: HashMap<usize, usize>,
+    //polls waiting on a transaction, see poll()
+    held: Vec<Held>,
+    //tells when a key's leader is gone
+    membership: Membership,
+    //asked a seed to admit us, done once on the first tick
+    joined: bool,
+    //where leaderships are agreed on
+    lin_kv: KvClient,
+    //lin-kv msg_id -> key and the leadership proposed, None for a re-read
+    elections: HashMap<usize, (String, Option<Leadership>)>,
+}
+
+impl KafkaNode {
+    //where requests for a key go: its leader as far as we know. a replica takes the key
+    //on here so it can stand in for a leader that's down, other nodes try the replicas in
+    //order. a key the ring moved away from us is still ours to route while we hold it
+    fn owner(&mut self, key: &str) -> anyhow::Result<String> {
+        let replicas = self.replicas_of(key);
+        if replicas.contains(&self.node) || self.partitions.contains_key(key) {
+            return Ok(self.partition(key)?.leadership.leader.clone());
+        }
+        let owner = replicas
+            .iter()
+            .find(|node| is_live(&self.membership, node))
+            .or(replicas.first())
+            .expect("hash ring has no nodes");
+        Ok(owner.clone())
+    }
+
+    fn replicas_of(&self, key: &str) -> Vec<String> {
+        self.ring
+            .owners(key, self.replicas)
+            .into_iter()
+            .map(String::from)
+            .collect()
+    }
+
+    //get a key's partition. until lin-kv says otherwise the first replica leads with
+    //every replica in sync, a key nobody has changed that for isn't in lin-kv at all
+    fn partition(&mut self, key: &str) -> anyhow::Result<&mut Partition> {
+        if !self.partitions.contains_key(key) {
+            let replicas = self.replicas_of(key);
+            let mut partition = Partition::new(Leadership::initial(replicas.clone()));
+            if let Some((dir, config)) = &self.storage {
+                let store = SegmentLog::open(dir.join(key_dir(key)), config.clone())
+                    .context(format!("open log of key {key}"))?;
+                partition.recover(store)?;
+            }
+            if partition.leadership.leader == self.node {
+                partition.lead(&replicas);
+            }
+            self.partitions.insert(key.to_string(), partition);
+        }
+        Ok(self
+            .partitions
+            .get_mut(key)
+            .expect("partition just inserted"))
+    }
+
+    //load every key a previous run stored
+    fn recover(&mut self) -> anyhow::Result<()> {
+        let Some((dir, _)) = &self.storage else {
+            return Ok(());
+        };
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            return Ok(());
+        };
+        let mut keys = Vec::new();
+        for entry in entries {
+            let entry = entry.context("list kafka data directory")?;
+            keys.extend(entry.file_name().to_str().and_then(dir_key));
+        }
+        for key in keys {
+            let partition = self.partition(&key)?;
+            let undecided: Vec<TxnId> = partition.pending.values().cloned().collect();
+            for txn in undecided {
+                self.in_doubt.insert(txn, Instant::now());
+            }
+        }
+        Ok(())
+    }
+
+    //split keyed values by owner, our own share separately
+    fn by_owner<T>(
+        &mut self,
+        items: impl IntoIterator<Item = (String, T)>,
+    ) -> anyhow::Result<Split<T>> {
+        let mut local = Vec::new();
+        let mut remote: Shares<T> = HashMap::new();
+        for (key, value) in items {
+            let owner = self.owner(&key)?;
+            if owner == self.node {
+                local.push((key, value));
+            } else {
+                remote.entry(owner).or_default().push((key, value));
+            }
+        }
+        Ok((local, remote))
+    }
+
+    //leader side of a send: the client hears back once the record is fully replicated
+    fn append(
+        &mut self,
+        key: String,
+        msg: usize,
+        sequence: Option<(String, usize)>,
+        request: Message<()>,
+        output: &mut std::io::StdoutLock,
+    ) -> anyhow::Result<()> {
+        let partition = self.partition(&key)?;
+        if let Some((producer, seq)) = &sequence {
+            match partition.sequenced(producer, *seq) {
+                Sequenced::New => {}
+                //a retry, it hears back with the original offset once that's replicated
+                Sequenced::Retry(offset) => {
+                    partition.unacked.push((offset, Waiter::Send(request)));
+                    return self.ack(&key, output);
+                }
+                Sequenced::Forgotten => {
+                    let error = KafkaPayload::Error {
+                    code: ErrorCode::PreconditionFailed.code(),
+                        text: format!(
+                            "sequence {seq} of producer {producer} is too old to tell if it's a retry"
+                        ),
+                    };
+                    return self.reply(request, error, output);
+                }
+            }
+        }
+        let offset = partition.push(msg, sequence.clone())?;
+        partition.unacked.push((offset, Waiter::Send(request)));
+        let record = Record {
+            msg,
+            producer: sequence,
+        };
+        let followers: Vec<String> = partition.followers.keys().cloned().collect();
+        for follower in followers {
+            self.replicate(&key, follower, vec![(offset, record.clone())], output)?;
+        }
+        self.ack(&key, output)
+    }
+
+    fn replicate(
+        &mut self,
+        key: &str,
+        follower: String,
+        records: Vec<(usize, Record)>,
+        output: &mut std::io::StdoutLock,
+    ) -> anyhow::Result<()> {
+        let partition = self.partition(key)?;
+        let payload = KafkaPayload::Replicate {
+            key: key.to_string(),
+            leadership: partition.leadership.clone(),
+            start: partition.start,
+            records,
+            hwm: partition.hwm,
+            committed: partition.committed.clone(),
+            pending: partition.pending.clone().into_iter().collect(),
+            aborted: partition.aborted.clone(),
+            batches: partition.batches.clone().into_iter().collect(),
+        };
+        Message::new(self.node.clone(), follower, &mut self.id, payload)
+            .send_self(&mut *output)
+            .context("replicate kafka records")
+    }
+
+    fn ack(&mut self, key: &str, output: &mut std::io::StdoutLock) -> anyhow::Result<()> {
+        let Some(partition) = self
+            .partitions
+            .get_mut(key)
+            .filter(|partition| partition.leadership.leader == self.node)
+        else {
+            return Ok(());
+        };
+        let visible = partition.visible_end();
+        for (offset, waiter) in partition.advance_hwm() {
+            match waiter {
+                Waiter::Send(request) => {
+                    self.reply(request, KafkaPayload::SendOk { offset }, output)?
+                }
+                Waiter::Prepare((coordinator, txn)) => {
+                    let offsets = self.partitions[key].pending_of(&(coordinator.clone(), txn));
+                    if coordinator == self.node {
+                        self.prepared(txn, key.to_string(), offsets, output)?;
+                    } else {
+                        let key = key.to_string();
+                        let prepared = KafkaPayload::TxnPrepareOk { txn, key, offsets };
+                        Message::new(self.node.clone(), coordinator, &mut self.id, prepared)
+                            .send_self(&mut *output)
+                            .context("tell coordinator a txn is prepared")?;
+                    }
+                }
+            }
+        }
+        if self.partitions[key].visible_end() > visible {
+            self.wake(key, output)?;
+        }
+        Ok(())
+    }
+
+    //ask lin-kv to move a key from one leadership to another. `from` is None for a
+    //replica that may be missing records: it can only start a key nobody led yet
+    fn propose(
+        &mut self,
+        key: &str,
+        from: Option<Leadership>,
+        to: Leadership,
+        output: &mut std::io::StdoutLock,
+    ) -> anyhow::Result<()> {
+        let from = match from {
+            Some(from) => serde_json::to_value(from).context("serialize leadership")?,
+            None => Value::Null,
+        };
+        let value = serde_json::to_value(&to).context("serialize leadership")?;
+        //a key that isn't in lin-kv has the default leadership, creating it replaces that
+        let call = self
+            .lin_kv
+            .cas(leadership_key(key), from, value, true, &mut self.id, output)?;
+        self.elections.insert(call, (key.to_string(), Some(to)));
+        self.partition(key)?.change = Some(Change::new(call));
+        Ok(())
+    }
+
+    //lin-kv answered a leadership change or a re-read of one
+    fn elected(
+        &mut self,
+        call: usize,
+        result: KvResult,
+        output: &mut std::io::StdoutLock,
+    ) -> anyhow::Result<()> {
+        let Some((key, proposed)) = self.elections.remove(&call) else {
+            return Ok(());
+        };
+        let Some(partition) = self.partitions.get_mut(&key) else {
+            return Ok(());
+        };
+        let Some(change) = partition
+            .change
+            .as_mut()
+            .filter(|change| change.call == Some(call))
+        else {
+            return Ok(());
+        };
+        let epoch = partition.leadership.epoch;
+        let ours = proposed.is_some();
+        let leadership = match (proposed, result) {
+            (Some(to), Ok(_)) => {
+                partition.change = None;
+                to
+            }
+            //lost to another change, or can't tell if ours went through: see what's there.
+            //the change stays open so we don't try again right away
+            (Some(_), Err(_)) => {
+                let call = self
+                    .lin_kv
+                    .read(leadership_key(&key), &mut self.id, output)?;
+                change.call = Some(call);
+                self.elections.insert(call, (key, None));
+                return Ok(());
+            }
+            (None, Ok(value)) => {
+                change.call = None;
+                serde_json::from_value(value).context("parse leadership from lin-kv")?
+            }
+            (None, Err(ErrorCode::KeyDoesNotExist)) => {
+                change.call = None;
+                Leadership::initial(self.replicas_of(&key))
+            }
+            (None, Err(_)) => {
+                change.call = None;
+                return Ok(());
+            }
+        };
+        self.adopt(&key, leadership.clone(), true, output)?;
+        self.confirm(&key, &leadership);
+        //the other replicas may not hear it from the new leader soon, or at all
+        if ours && leadership.epoch > epoch {
+            for replica in self.replicas_of(&key) {
+                if replica == self.node {
+                    continue;
+                }
+                let key = key.clone();
+                let leadership = leadership.clone();
+                Message::new(
+                    self.node.clone(),
+                    replica,
+                    &mut self.id,
+                    KafkaPayload::Elected { key, leadership },
+                )
+                .send_self(&mut *output)
+                .context("announce new kafka leader")?;
+            }
+        }
+        Ok(())
+    }
+
+    //take on what lin-kv or another replica says about a key's leadership. a leader
+    //only learns of newer epochs this way, changes to its own isr come back from lin-kv
+    fn adopt(
+        &mut self,
+        key: &str,
+        leadership: Leadership,
+        from_kv: bool,
+        output: &mut std::io::StdoutLock,
+    ) -> anyhow::Result<()> {
+        let replicas = self.replicas_of(key);
+        let node = self.node.clone();
+        let partition = self.partition(key)?;
+        let current = &partition.leadership;
+        if !leadership.replaces(current, &node, from_kv) {
+            return Ok(());
+        }
+        let was_leading = current.leader == node;
+        let new_epoch = leadership.epoch > current.epoch;
+        partition.leadership = leadership;
+        let leading = partition.leadership.leader == node;
+        //a leader lin-kv never agreed to may be replaced within its own epoch
+        let mut dropped = Vec::new();
+        if (new_epoch || was_leading) && !leading {
+            dropped = partition.follow()?;
+        }
+        let mut undecided = Vec::new();
+        if leading && !was_leading {
+            partition.lead(&replicas);
+            undecided.extend(partition.pending.values().cloned());
+        }
+        partition.save_state()?;
+        //the old leader's undecided transactions are ours to chase now
+        for txn in undecided {
+            self.in_doubt.entry(txn).or_insert_with(Instant::now);
+        }
+        //the new leader may or may not have a send we can't ack any more, a prepare's
+        //batch times out and is aborted
+        for (_, waiter) in dropped {
+            if let Waiter::Send(request) = waiter {
+                let error = KafkaPayload::Error {
+                    code: ErrorCode::Timeout.code(),
+                    text: format!("no longer the leader of key {key}, the send may be lost"),
+                };
+                self.reply(request, error, output)?;
+            }
+        }
+        if leading && !was_leading {
+            for follower in replicas.into_iter().filter(|replica| *replica != node) {
+                self.replicate(key, follower, Vec::new(), output)?;
+            }
+        }
+        Ok(())
+    }
+
+    //lin-kv has `leadership`, if that makes us the current leader we may ack
+    fn confirm(&mut self, key: &str, leadership: &Leadership) {
+        if leadership.leader != self.node {
+            return;
+        }
+        if let Some(partition) = self
+            .partitions
+            .get_mut(key)
+            .filter(|partition| partition.leadership == *leadership)
+        {
+            partition.confirmed = true;
+        }
+    }
+
+    //leader: get lin-kv to agree with our leadership, then keep its isr up to date.
+    //follower: take over a key whose leader is down, if we're in its isr
+    fn tick_leadership(&mut self, output: &mut std::io::StdoutLock) -> anyhow::Result<()> {
+        let mut proposals = Vec::new();
+        for (key, partition) in &self.partitions {
+            let leadership = &partition.leadership;
+            if !can_propose(partition.change.as_ref()) {
+                continue;
+            }
+            if leadership.leader == self.node {
+                if !partition.confirmed {
+                    proposals.push((key.clone(), Some(leadership.clone()), leadership.clone()));
+                    continue;
+                }
+                let isr = partition.in_sync_replicas(&self.replicas_of(key), &self.node);
+                if isr != leadership.isr {
+                    let to = Leadership {
+                        isr,
+                        ..leadership.clone()
+                    };
+                    proposals.push((key.clone(), Some(leadership.clone()), to));
+                }
+            } else if !is_live(&self.membership, &leadership.leader)
+                && leadership.isr.contains(&self.node)
+            {
+                let to = leadership.handed_to(self.node.clone());
+                let from = partition.synced.then(|| leadership.clone());
+                proposals.push((key.clone(), from, to));
+            }
+        }
+        for (key, from, to) in proposals {
+            self.propose(&key, from, to, output)?;
+        }
+        Ok(())
+    }
+
+    //a follower has records we don't, so this process came back without our log:
+    //hand the key to that follower, which the isr says has everything acknowledged
+    fn resign(
+        &mut self,
+        key: &str,
+        follower: String,
+        output: &mut std::io::StdoutLock,
+    ) -> anyhow::Result<()> {
+        let partition = self.partition(key)?;
+        let leadership = partition.leadership.clone();
+        if !can_propose(partition.change.as_ref()) || !leadership.isr.contains(&follower) {
+            return Ok(());
+        }
+        let to = leadership.handed_to(follower);
+        self.propose(key, Some(leadership), to, output)
+    }
+
+    //coordinator side of a send_batch
+    fn send_batch(
+        &mut self,
+        request: Message<()>,
+        msgs: HashMap<String, Vec<usize>>,
+        output: &mut std::io::StdoutLock,
+    ) -> anyhow::Result<()> {
+        let msgs: Vec<(String, Vec<usize>)> = msgs
+            .into_iter()
+            .filter(|(_, msgs)| !msgs.is_empty())
+            .collect();
+        if msgs.is_empty() {
+            let offsets = HashMap::new();
+            return self.reply(request, KafkaPayload::SendBatchOk { offsets }, output);
+        }
+        let txn = self.next_txn;
+        self.next_txn += 1;
+        let waiting = msgs.len();
+        let (local, remote) = self.by_owner(msgs)?;
+        let mut leaders: Vec<String> = remote.keys().cloned().collect();
+        if !local.is_empty() {
+            leaders.push(self.node.clone());
+        }
+        let batch = Batch {
+            request,
+            waiting,
+            offsets: HashMap::new(),
+            leaders,
+            started: Instant::now(),
+        };
+        self.batches.insert(txn, batch);
+        for (leader, msgs) in remote {
+            let msgs = msgs.into_iter().collect();
+            Message::new(
+                self.node.clone(),
+                leader,
+                &mut self.id,
+                KafkaPayload::TxnPrepare { txn, msgs },
+            )
+            .send_self(&mut *output)
+            .context("send txn prepare")?;
+        }
+        for (key, msgs) in local {
+            self.prepare((self.node.clone(), txn), key, msgs, output)?;
+        }
+        Ok(())
+    }
+
+    //leader side: append a transaction's records for one key, hidden until it's decided
+    fn prepare(
+        &mut self,
+        id: TxnId,
+        key: String,
+        msgs: Vec<usize>,
+        output: &mut std::io::StdoutLock,
+    ) -> anyhow::Result<()> {
+        let partition = self.partition(&key)?;
+        let mut records = Vec::new();
+        for msg in msgs {
+            let offset = partition.push(msg, None)?;
+            partition.pending.insert(offset, id.clone());
+            records.push((
+                offset,
+                Record {
+                    msg,
+                    producer: None,
+                },
+            ));
+        }
+        let Some((last, _)) = records.last() else {
+            return Ok(());
+        };
+        partition.unacked.push((*last, Waiter::Prepare(id.clone())));
+        partition.save_state()?;
+        let followers: Vec<String> = partition.followers.keys().cloned().collect();
+        for follower in followers {
+            self.replicate(&key, follower, records.clone(), output)?;
+        }
+        self.in_doubt.entry(id).or_insert_with(Instant::now);
+        self.ack(&key, output)
+    }
+
+    //coordinator side: one more key is prepared, commit once they all are
+    fn prepared(
+        &mut self,
+        txn: usize,
+        key: String,
+        offsets: Vec<usize>,
+        output: &mut std::io::StdoutLock,
+    ) -> anyhow::Result<()> {
+        //already aborted
+        let Some(batch) = self.batches.get_mut(&txn) else {
+            return Ok(());
+        };
+        batch.offsets.insert(key, offsets);
+        batch.waiting -= 1;
+        if batch.waiting > 0 {
+            return Ok(());
+        }
+        let batch = self.batches.remove(&txn).expect("batch just found");
+        let ends: HashMap<String, usize> = batch
+            .offsets
+            .iter()
+            .filter_map(|(key, offsets)| Some((key.clone(), *offsets.iter().max()?)))
+            .collect();
+        let decided = Decided {
+            ends: ends.clone(),
+            unresolved: batch.offsets.keys().cloned().collect(),
+        };
+        self.decided.insert(txn, decided);
+        self.decide(txn, true, &ends, batch.leaders, output)?;
+        let offsets = batch.offsets;
+        self.reply(batch.request, KafkaPayload::SendBatchOk { offsets }, output)
+    }
+
+    fn decide(
+        &mut self,
+        txn: usize,
+        commit: bool,
+        ends: &HashMap<String, usize>,
+        leaders: Vec<String>,
+        output: &mut std::io::StdoutLock,
+    ) -> anyhow::Result<()> {
+        for leader in leaders {
+            if leader == self.node {
+                self.resolve((self.node.clone(), txn), commit, ends, output)?;
+            } else {
+                let ends = ends.clone();
+                Message::new(
+                    self.node.clone(),
+                    leader,
+                    &mut self.id,
+                    KafkaPayload::TxnDecide { txn, commit, ends },
+                )
+                .send_self(&mut *output)
+                .context("send txn decision")?;
+            }
+        }
+        Ok(())
+    }
+
+    //leader side: a transaction's records become visible, or are skipped from now on.
+    //leaders hear the decision one by one, but a poll never shows a batch on one key
+    //and not another: until it's resolved here, polls of the key wait (see poll()),
+    //and a poll over several keys is cut short of a batch it can't show whole
+    fn resolve(
+        &mut self,
+        id: TxnId,
+        commit: bool,
+        ends: &HashMap<String, usize>,
+        output: &mut std::io::StdoutLock,
+    ) -> anyhow::Result<()> {
+        self.in_doubt.remove(&id);
+        let mut woken = Vec::new();
+        let mut resolving = Vec::new();
+        for (key, partition) in &mut self.partitions {
+            let visible = partition.visible_end();
+            if !partition.resolve(&id, commit, ends)? {
+                continue;
+            }
+            if partition.visible_end() > visible {
+                woken.push(key.clone());
+            }
+            if commit && partition.leadership.leader == self.node {
+                let followers: BTreeSet<String> = partition
+                    .leadership
+                    .isr
+                    .iter()
+                    .filter(|node| **node != self.node)
+                    .cloned()
+                    .collect();
+                resolving.push((key.clone(), followers));
+            }
+        }
+        for key in woken {
+            self.wake(&key, output)?;
+        }
+        self.retry_held(output)?;
+        //the coordinator can forget a committed txn once the in-sync followers have it
+        //resolved too, any of them may take over the key and must not ask again
+        let from = self.id;
+        for (key, followers) in resolving {
+            if followers.is_empty() {
+                self.report_resolved(id.clone(), key, output)?;
+                continue;
+            }
+            let partition = self.partition(&key)?;
+            partition
+                .resolving
+                .push((id.clone(), from, followers.clone()));
+            for follower in followers {
+                self.replicate(&key, follower, Vec::new(), output)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn report_resolved(
+        &mut self,
+        (coordinator, txn): TxnId,
+        key: String,
+        output: &mut std::io::StdoutLock,
+    ) -> anyhow::Result<()> {
+        if coordinator == self.node {
+            self.forget(txn, &key);
+            return Ok(());
+        }
+        Message::new(
+            self.node.clone(),
+            coordinator,
+            &mut self.id,
+            KafkaPayload::TxnResolved { txn, key },
+        )
+        .send_self(&mut *output)
+        .context("tell coordinator a txn is resolved")
+    }
+
+    //coordinator side: one key's leader is done with a committed txn
+    fn forget(&mut self, txn: usize, key: &str) {
+        let Some(decided) = self.decided.get_mut(&txn) else {
+            return;
+        };
+        decided.unresolved.remove(key);
+        if decided.unresolved.is_empty() {
+            self.decided.remove(&txn);
+        }
+    }
+
+    //coordinator: abort batches that took too long. leader: chase undecided transactions,
+    //and make sure the in-sync followers have the resolved ones
+    fn tick_txns(&mut self, output: &mut std::io::StdoutLock) -> anyhow::Result<()> {
+        let expired: Vec<usize> = self
+            .batches
+            .iter()
+            .filter(|(_, batch)| batch.started.elapsed() > TXN_TIMEOUT)
+            .map(|(txn, _)| *txn)
+            .collect();
+        for txn in expired {
+            let batch = self.batches.remove(&txn).expect("batch just found");
+            self.decide(txn, false, &HashMap::new(), batch.leaders, output)?;
+            let error = KafkaPayload::Error {
+                code: ErrorCode::Abort.code(),
+                text: format!("txn {txn} timed out before every key was prepared, aborted"),
+            };
+            self.reply(batch.request, error, output)?;
+        }
+
+        let stale: Vec<TxnId> = self
+            .in_doubt
+            .iter()
+            .filter(|(_, asked)| asked.elapsed() > TXN_RESOLVE)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for (coordinator, txn) in stale {
+            self.in_doubt
+                .insert((coordinator.clone(), txn), Instant::now());
+            if coordinator == self.node {
+                if let Some(commit) = self.txn_status(txn) {
+                    let ends = self.ends_of(txn);
+                    self.resolve((coordinator, txn), commit, &ends, output)?;
+                }
+            } else {
+                Message::new(
+                    self.node.clone(),
+                    coordinator,
+                    &mut self.id,
+                    KafkaPayload::TxnStatus { txn },
+                )
+                .send_self(&mut *output)
+                .context("ask coordinator about txn")?;
+            }
+        }
+
+        let mut done = Vec::new();
+        let mut unsure = Vec::new();
+        for (key, partition) in &mut self.partitions {
+            if partition.leadership.leader != self.node {
+                continue;
+            }
+            done.extend(
+                partition
+                    .drain_resolved()
+                    .into_iter()
+                    .map(|id| (id, key.clone())),
+            );
+            let waiting: BTreeSet<&String> = partition
+                .resolving
+                .iter()
+                .flat_map(|(_, _, waiting)| waiting)
+                .collect();
+            unsure.extend(
+                waiting
+                    .into_iter()
+                    .map(|follower| (key.clone(), follower.clone())),
+            );
+        }
+        for (id, key) in done {
+            self.report_resolved(id, key, output)?;
+        }
+        //the replicate carrying the resolution or its ack may have been lost
+        for (key, follower) in unsure {
+            self.replicate(&key, follower, Vec::new(), output)?;
+        }
+        Ok(())
+    }
+
+    //a txn we aren't running and don't remember committing is presumed aborted. txn
+    //numbers aren't reused, so it stays that way
+    fn txn_status(&self, txn: usize) -> Option<bool> {
+        if self.batches.contains_key(&txn) {
+            return None;
+        }
+        Some(self.decided.contains_key(&txn))
+    }
+
+    fn ends_of(&self, txn: usize) -> HashMap<String, usize> {
+        self.decided
+            .get(&txn)
+            .map(|decided| decided.ends.clone())
+            .unwrap_or_default()
+    }
+
+    //visibility on `key` stops at a transaction that may be committed elsewhere. not one
+    //of ours that's still being prepared
+    fn held_back(&self, key: &str) -> bool {
+        let Some((coordinator, txn)) = self.partitions.get(key).and_then(Partition::held_by) else {
+            return false;
+        };
+        *coordinator != self.node || !self.batches.contains_key(txn)
+    }
+
+    //held polls are tried again whenever a transaction is resolved, and on every tick
+    fn retry_held(&mut self, output: &mut std::io::StdoutLock) -> anyhow::Result<()> {
+        for held in std::mem::take(&mut self.held) {
+            let Held {
+                request,
+                offsets,
+                limits,
+                wait_ms,
+                deadline,
+            } = held;
+            self.poll(request, offsets, limits, wait_ms, deadline, output)?;
+        }
+        Ok(())
+    }
+
+    //new records are visible on `key`, answer the parked polls that wanted them
+    fn wake(&mut self, key: &str, output: &mut std::io::StdoutLock) -> anyhow::Result<()> {
+        let waiting: Vec<usize> = self
+            .ops
+            .iter()
+            .filter(|(_, op)| {
+                op.long_poll
+                    .as_ref()
+                    .is_some_and(|(offsets, _)| offsets.iter().any(|(k, _)| k == key))
+            })
+            .map(|(op_id, _)| *op_id)
+            .collect();
+        for op_id in waiting {
+            if self.repoll(op_id) {
+                self.finish(op_id, output)?;
+            }
+        }
+        Ok(())
+    }
+
+    //parked polls whose wait is over are answered with whatever they have. other ops
+    //an owner didn't answer in time fail: definitely for reads and a commit still
+    //checking, which changed nothing, indefinitely for the rest
+    fn expire_ops(&mut self, output: &mut std::io::StdoutLock) -> anyhow::Result<()> {
+        let now = Instant::now();
+        let expired: Vec<usize> = self
+            .ops
+            .iter()
+            .filter(|(_, op)| op.deadline <= now)
+            .map(|(op_id, _)| *op_id)
+            .collect();
+        for op_id in expired {
+            let Some(op) = self.ops.get_mut(&op_id) else {
+                continue;
+            };
+            if op.long_poll.is_some() {
+                self.repoll(op_id);
+                self.finish(op_id, output)?;
+                continue;
+            }
+            let missing = format!("{} owners didn't answer in time", op.waiting);
+            let (code, text) = match (&op.commit, &op.reply) {
+                (Some(Commit::Apply), _) => (
+                    ErrorCode::Timeout,
+                    format!("commit may be partly applied: {missing}"),
+                ),
+                (None, KafkaPayload::SendOk { .. }) => (ErrorCode::Timeout, missing),
+                _ => (ErrorCode::TemporarilyUnavailable, missing),
+            };
+            op.reply = KafkaPayload::Error {
+                code: code.code(),
+                text,
+            };
+            op.commit = None;
+            self.finish(op_id, output)?;
+        }
+        Ok(())
+    }
+
+    //look at a parked poll's own share again, true once it has something to hand out.
+    //not while a transaction holds one of its keys
+    fn repoll(&mut self, op_id: usize) -> bool {
+        let Some((offsets, limits)) = self.ops.get(&op_id).and_then(|op| op.long_poll.clone())
+        else {
+            return false;
+        };
+        if offsets.iter().any(|(key, _)| self.held_back(key)) {
+            return false;
+        }
+        let local = self.poll_local(offsets, limits);
+        let op = self.ops.get_mut(&op_id).expect("op just found");
+        merge(&mut op.reply, local);
+        has_msgs(&op.reply)
+    }
+
+    fn finish(&mut self, op_id: usize, output: &mut std::io::StdoutLock) -> anyhow::Result<()> {
+        let Some(op) = self.ops.remove(&op_id) else {
+            return Ok(());
+        };
+        //owners still to answer are ignored from here on
+        self.calls.retain(|_, waiting_op| *waiting_op != op_id);
+        match (op.commit, op.reply) {
+            (
+                Some(Commit::Check(offsets, group)),
+                KafkaPayload::ListCommittedOffsetsOk { offsets: committed },
+            ) => self.commit(op.request, offsets, group, committed, output),
+            (Some(Commit::Apply), KafkaPayload::Error { text, .. }) => {
+                let reply = KafkaPayload::Error {
+                    code: ErrorCode::Crash.code(),
+                    text: format!("commit may be partly applied: {text}"),
+                };
+                self.reply(op.request, reply, output)
+            }
+            (_, reply) => self.reply(op.request, reply, output),
+        }
+    }
+
+    //spread keys over the nodes that are up. a leader replicates to replicas new to a key,
+    //which join the isr once caught up, and stops replicating to the ones that are gone.
+    //those hold the hwm back until lin-kv agrees to drop them from the isr
+    fn rebuild_ring(&mut self) {
+        let members = self.membership.members();
+        if members == self.members {
+            return;
+        }
+        self.ring.rebuild(&members);
+        self.members = members;
+        let led: Vec<String> = self
+            .partitions
+            .iter()
+            .filter(|(_, partition)| partition.leadership.leader == self.node)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in led {
+            let replicas = self.replicas_of(&key);
+            if let Some(partition) = self.partitions.get_mut(&key) {
+                partition.rebalance(&replicas);
+            }
+        }
+    }
+
+    //resend whatever followers are missing, keep the isr and leaderships up to date,
+    //apply retention and answer parked polls that waited long enough
+    fn tick(&mut self, output: &mut std::io::StdoutLock) -> anyhow::Result<()> {
+        let mut behind = Vec::new();
+        for (key, partition) in &mut self.partitions {
+            partition.retain(&self.retention)?;
+            for (follower, records) in partition.lagging() {
+                behind.push((key.clone(), follower, records));
+            }
+        }
+        for (key, follower, records) in behind {
+            self.replicate(&key, follower, records, output)?;
+        }
+        let keys: Vec<String> = self.partitions.keys().cloned().collect();
+        for key in keys {
+            self.ack(&key, output)?;
+        }
+        //a restarted node is still dead to the others until a seed readmits it
+        if !self.joined {
+            self.joined = true;
+            if let Some(seed) = self.membership.peers().into_iter().min() {
+                self.membership.join(&seed, &mut self.id, &mut *output)?;
+            }
+        }
+        self.membership.tick(&mut self.id, &mut *output)?;
+        self.rebuild_ring();
+        self.expire_ops(output)?;
+        self.retry_held(output)?;
+        self.tick_leadership(output)?;
+        self.tick_txns(output)
+    }
+
+    //a PollOk for the keys we lead
+    fn poll_local(&self, offsets: Vec<(String, usize)>, limits: PollLimits) -> KafkaPayload {
+        let mut msgs = HashMap::new();
+        let mut truncated = HashMap::new();
+        let mut reach = HashMap::new();
+        let mut batches = HashMap::new();
+        for (key, from) in offsets {
+            let Some(partition) = self.partitions.get(&key) else {
+                continue;
+            };
+            if from < partition.start {
+                truncated.insert(key.clone(), partition.start);
+            }
+            reach.insert(key.clone(), partition.visible_end());
+            let window = limits.window(partition.visible_from(from));
+            let (Some((first, _)), Some((last, _))) = (window.first(), window.last()) else {
+                continue;
+            };
+            let spans: Vec<_> = partition
+                .batches_in(*first, *last)
+                .into_iter()
+                .map(|(start, span)| (start, span.ends))
+                .collect();
+            if !spans.is_empty() {
+                batches.insert(key.clone(), spans);
+            }
+            msgs.insert(key, window);
+        }
+        KafkaPayload::PollOk {
+            msgs,
+            truncated,
+            reach,
+            batches,
+        }
+    }
+
+    //commit the keys we lead. a commit that raced past this one since the check
+    //is kept, offsets only move forward
+    fn commit_local(
+        &mut self,
+        offsets: Vec<(String, usize)>,
+        group: &str,
+        output: &mut std::io::StdoutLock,
+    ) -> anyhow::Result<()> {
+        for (key, offset) in offsets {
+            let partition = self.partition(&key)?;
+            let committed = partition.committed.entry(group.to_string()).or_default();
+            if offset <= *committed {
+                continue;
+            }
+            *committed = offset;
+            partition.save_state()?;
+            let followers: Vec<String> = partition.followers.keys().cloned().collect();
+            for follower in followers {
+                self.replicate(&key, follower, Vec::new(), output)?;
+            }
+        }
+        Ok(())
+    }
+
+    //second round of a commit, once every owner said where the group is. a key that
+    //would go backwards fails the whole commit before anything is written
+    fn commit(
+        &mut self,
+        request: Message<()>,
+        offsets: HashMap<String, usize>,
+        group: String,
+        committed: HashMap<String, usize>,
+        output: &mut std::io::StdoutLock,
+    ) -> anyhow::Result<()> {
+        let backwards: Vec<String> = offsets
+            .iter()
+            .filter_map(|(key, offset)| {
+                let committed = committed.get(key).filter(|committed| *committed > offset)?;
+                Some(format!("{key}: {offset} < {committed}"))
+            })
+            .collect();
+        if !backwards.is_empty() {
+            let group = match group.as_str() {
+                "" => "the default group".to_string(),
+                group => format!("group {group}"),
+            };
+            let reply = KafkaPayload::Error {
+                code: ErrorCode::PreconditionFailed.code(),
+                text: format!(
+                    "commit would move {group} backwards on {}",
+                    backwards.join(", ")
+                ),
+            };
+            return self.reply(request, reply, output);
+        }
+        let (local, remote) = self.by_owner(offsets)?;
+        self.commit_local(local, &group, output)?;
+        let parts: Vec<_> = remote
+            .into_iter()
+            .map(|(owner, offsets)| {
+                let offsets = offsets.into_iter().collect();
+                let group = group.clone();
+                let commit = KafkaPayload::CommitOffsets {
+                    offsets,
+                    group,
+                    checked: true,
+                };
+                (owner, commit)
+            })
+            .collect();
+        if parts.is_empty() {
+            return self.reply(request, KafkaPayload::CommitOffsetsOk, output);
+        }
+        let reply = KafkaPayload::CommitOffsetsOk;
+        let op_id = self.start_op(request, reply, parts, None, output)?;
+        if let Some(op) = self.ops.get_mut(&op_id) {
+            op.commit = Some(Commit::Apply);
+        }
+        Ok(())
+    }
+
+    fn list_local(&self, keys: Vec<(String, ())>, group: &str) -> HashMap<String, usize> {
+        keys.into_iter()
+            .filter_map(|(key, _)| {
+                let committed = *self.partitions.get(&key)?.committed.get(group)?;
+                Some((key, committed))
+            })
+            .collect()
+    }
+
+    //our own keys are read here, the rest by their owners. a key held back by a batch
+    //that may be committed, but isn't resolved here yet, waits for it: answering without
+    //it could miss a batch another poll already saw on another key
+    fn poll(
+        &mut self,
+        request: Message<()>,
+        offsets: HashMap<String, usize>,
+        limits: PollLimits,
+        wait_ms: Option<u64>,
+        deadline: Instant,
+        output: &mut std::io::StdoutLock,
+    ) -> anyhow::Result<()> {
+        let (local, remote) = self.by_owner(offsets.clone())?;
+        if local.iter().any(|(key, _)| self.held_back(key)) {
+            if deadline <= Instant::now() {
+                let error = KafkaPayload::Error {
+                    code: ErrorCode::TemporarilyUnavailable.code(),
+                    text: "a transaction is still undecided on some keys".to_string(),
+                };
+                return self.reply(request, error, output);
+            }
+            let held = Held {
+                request,
+                offsets,
+                limits,
+                wait_ms,
+                deadline,
+            };
+            self.held.push(held);
+            return Ok(());
+        }
+        let reply = self.poll_local(local.clone(), limits);
+        //with records at hand it's an ordinary poll
+        let wait_ms = wait_ms.filter(|_| !has_msgs(&reply));
+        let parts = remote
+            .into_iter()
+            .map(|(owner, offsets)| {
+                let offsets = offsets.into_iter().collect();
+                let poll = KafkaPayload::Poll {
+                    offsets,
+                    limits,
+                    wait_ms,
+                };
+                (owner, poll)
+            })
+            .collect();
+        match wait_ms {
+            Some(wait_ms) => {
+                let wait = Duration::from_millis(wait_ms);
+                self.park(request, reply, parts, (local, limits), wait, output)?;
+            }
+            None => self.forward(request, reply, parts, output)?,
+        }
+        Ok(())
+    }
+
+    //send each owner its share of the request, reply once they've all answered
+    fn forward(
+        &mut self,
+        request: Message<()>,
+        reply: KafkaPayload,
+        parts: Vec<(String, KafkaPayload)>,
+        output: &mut std::io::StdoutLock,
+    ) -> anyhow::Result<()> {
+        if parts.is_empty() {
+            return self.reply(request, reply, output);
+        }
+        self.start_op(request, reply, parts, None, output)?;
+        Ok(())
+    }
+
+    //hold a poll that found nothing, it's answered by wake() or its deadline
+    fn park(
+        &mut self,
+        request: Message<()>,
+        reply: KafkaPayload,
+        parts: Vec<(String, KafkaPayload)>,
+        local: (Vec<(String, usize)>, PollLimits),
+        wait: Duration,
+        output: &mut std::io::StdoutLock,
+    ) -> anyhow::Result<()> {
+        let op_id = self.start_op(request, reply, parts, Some(local), output)?;
+        if let Some(op) = self.ops.get_mut(&op_id) {
+            op.deadline = Instant::now() + wait;
+        }
+        Ok(())
+    }
+
+    fn start_op(
+        &mut self,
+        request: Message<()>,
+        reply: KafkaPayload,
+        parts: Vec<(String, KafkaPayload)>,
+        long_poll: Option<(Vec<(String, usize)>, PollLimits)>,
+        output: &mut std::io::StdoutLock,
+    ) -> anyhow::Result<usize> {
+        let op_id = self.next_op;
+        self.next_op += 1;
+        self.ops.insert(
+            op_id,
+            Forwarded {
+                request,
+                waiting: parts.len(),
+                reply,
+                long_poll,
+                deadline: Instant::now() + FORWARD_TIMEOUT,
+                commit: None,
+            },
+        );
+        for (owner, payload) in parts {
+            let msg_id = self.id;
+            Message::new(self.node.clone(), owner.clone(), &mut self.id, payload)
+                .send_self(&mut *output)
+                .context(format!("forward kafka request to owner {owner}"))?;
+            self.calls.insert(msg_id, op_id);
+        }
+        Ok(op_id)
+    }
+
+    //an owner answered its share of a forwarded request
+    fn relay(
+        &mut self,
+        in_reply_to: Option<usize>,
+        payload: KafkaPayload,
+        output: &mut std::io::StdoutLock,
+    ) -> anyhow::Result<()> {
+        let Some(op_id) = in_reply_to.and_then(|id| self.calls.remove(&id)) else {
+            return Ok(());
+        };
+        let Some(op) = self.ops.get_mut(&op_id) else {
+            return Ok(());
+        };
+        merge(&mut op.reply, payload);
+        op.waiting -= 1;
+        //a long poll goes out with the first records found, otherwise it waits for everyone
+        let done = match op.long_poll {
+            Some(_) => has_msgs(&op.reply),
+            None => op.waiting == 0,
+        };
+        if done {
+            self.finish(op_id, output)?;
+        }
+        Ok(())
+    }
+
+    fn reply(
+        &mut self,
+        request: Message<()>,
+        mut payload: KafkaPayload,
+        output: &mut std::io::StdoutLock,
+    ) -> anyhow::Result<()> {
+        settle(&mut payload);
+        let client = self.membership.state_of(&request.src).is_none();
+        if let (true, KafkaPayload::PollOk { reach, batches, .. }) = (client, &mut payload) {
+            reach.clear();
+            batches.clear();
+        }
+        request
+            .with_payload(payload)
+            .derive_response(Some(&mut self.id))
+            .send_self(&mut *output)
+            .context("respond to kafka request")
+    }
+}
+
+impl Node<(), Payload, InjectedPayload> for KafkaNode {
+    fn from_init(
+        _state: (),
+        init: Init,
+        tx: std::sync::mpsc::Sender<Event<Payload, InjectedPayload>>,
+    ) -> anyhow::Result<Self>
+    where
+        Self: Sized,
+    {
+        std::thread::spawn(move || loop {
+            std::thread::sleep(Duration::from_millis(50));
+            if tx.send(Event::Injected(InjectedPayload::Tick)).is_err() {
+                break;
+            }
+        });
+
+        let replicas = std::env::var("KAFKA_REPLICAS")
+            .ok()
+            .and_then(|replicas| replicas.parse().ok())
+            .unwrap_or(DEFAULT_REPLICAS)
+            .max(1);
+        let membership = Membership::new(
+            init.node_id.clone(),
+            init.node_ids.clone(),
+            MembershipConfig::default(),
+        );
+        let members = membership.members();
+        let mut node = Self {
+            ring: HashRing::new(&members, partition::DEFAULT_VNODES),
+            members,
+            replicas,
+            retention: Retention::from_env(),
+            storage: storage_from_env(&init.node_id),
+            node: init.node_id.clone(),
+            id: 1,
+            partitions: HashMap::new(),
+            ops: HashMap::new(),
+            next_op: 0,
+            batches: HashMap::new(),
+            //txn numbers start from the clock, so a restarted coordinator doesn't reuse
+            //numbers that leaders may still hold undecided records for
+            next_txn: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |since| since.as_micros() as usize),
+            decided: HashMap::new(),
+            in_doubt: HashMap::new(),
+            calls: HashMap::new(),
+            held: Vec::new(),
+            membership,
+            joined: false,
+            lin_kv: KvClient::new(init.node_id.clone(), LIN_KV),
+            elections: HashMap::new(),
+        };
+        node.recover()?;
+        Ok(node)
+    }
+
+    fn handle_input(
+        &mut self,
+        input: Event<Payload, InjectedPayload>,
+        output: &mut std::io::StdoutLock,
+    ) -> anyhow::Result<()> {
+        let input = match input {
+            Event::Message(input) => input,
+            Event::Injected(InjectedPayload::Tick) => return self.tick(output),
+            //shutting down, tell the others instead of waiting to be declared dead
+            Event::EOF => return self.membership.leave(&mut self.id, &mut *output),
+        };
+        let (payload, request) = input.into_parts();
+        let payload = match payload {
+            Payload::Kafka(payload) => payload,
+            Payload::Membership(payload) => {
+                return self.membership.handle(
+                    request.with_payload(payload),
+                    &mut self.id,
+                    &mut *output,
+                );
+            }
+            Payload::Kv(payload) => {
+                let in_reply_to = request.body.in_reply_to;
+                if let Some(call) = in_reply_to.filter(|id| self.elections.contains_key(id)) {
+                    if let Some(result) = payload.into_result() {
+                        self.elected(call, result, output)?;
+                    }
+                } else if let KvPayload::Error { code, text } = payload {
+                    //an owner failing a request we forwarded
+                    let error = KafkaPayload::Error { code, text };
+                    self.relay(in_reply_to, error, output)?;
+                }
+                return Ok(());
+            }
+        };
+        match payload {
+            KafkaPayload::Send {
+                key,
+                msg,
+                producer,
+                seq,
+            } => {
+                let sequence = match (producer.clone(), seq) {
+                    (Some(producer), Some(seq)) => Some((producer, seq)),
+                    (None, None) => None,
+                    _ => {
+                        let error = KafkaPayload::Error {
+                            code: ErrorCode::MalformedRequest.code(),
+                            text: "producer and seq go together".to_string(),
+                        };
+                        return self.reply(request, error, output);
+                    }
+                };
+                let owner = self.owner(&key)?;
+                if owner == self.node {
+                    self.append(key, msg, sequence, request, output)?;
+                } else {
+                    let send = KafkaPayload::Send {
+                        key,
+                        msg,
+                        producer,
+                        seq,
+                    };
+                    let part = (owner, send);
+                    self.forward(
+                        request,
+                        KafkaPayload::SendOk { offset: 0 },
+                        vec![part],
+                        output,
+                    )?;
+                }
+            }
+
+            KafkaPayload::Poll {
+                offsets,
+                limits,
+                wait_ms,
+            } => {
+                let deadline = Instant::now() + FORWARD_TIMEOUT;
+                self.poll(request, offsets, limits, wait_ms, deadline, output)?;
+            }
+
+            KafkaPayload::CommitOffsets {
+                offsets,
+                group,
+                checked: true,
+            } => {
+                //a key that changed owner since the check is passed on to the new one
+                let committed = HashMap::new();
+                self.commit(request, offsets, group, committed, output)?;
+            }
+
+            KafkaPayload::CommitOffsets { offsets, group, .. } => {
+                //first ask every owner where the group is, so a commit either moves
+                //all its keys forward or fails without writing any
+                let keys = offsets.keys().map(|key| (key.clone(), ()));
+                let (local, remote) = self.by_owner(keys)?;
+                let committed = self.list_local(local, &group);
+                if remote.is_empty() {
+                    return self.commit(request, offsets, group, committed, output);
+                }
+                let parts = remote
+                    .into_iter()
+                    .map(|(owner, keys)| {
+                        let keys = keys.into_iter().map(|(k, _)| k).collect();
+                        let group = group.clone();
+                        (owner, KafkaPayload::ListCommittedOffsets { keys, group })
+                    })
+                    .collect();
+                let reply = KafkaPayload::ListCommittedOffsetsOk { offsets: committed };
+                let op_id = self.start_op(request, reply, parts, None, output)?;
+                if let Some(op) = self.ops.get_mut(&op_id) {
+                    op.commit = Some(Commit::Check(offsets, group));
+                }
+            }
+
+            KafkaPayload::ListCommittedOffsets { keys, group } => {
+                let (local, remote) = self.by_owner(keys.into_iter().map(|k| (k, ())))?;
+                let offsets = self.list_local(local, &group);
+                let parts = remote
+                    .into_iter()
+                    .map(|(owner, keys)| {
+                        let keys = keys.into_iter().map(|(k, _)| k).collect();
+                        let group = group.clone();
+                        (owner, KafkaPayload::ListCommittedOffsets { keys, group })
+                    })
+                    .collect();
+                self.forward(
+                    request,
+                    KafkaPayload::ListCommittedOffsetsOk { offsets },
+                    parts,
+                    output,
+                )?;
+            }
+
+            //follower side: take the records that extend our log, report how far we've got
+            KafkaPayload::Replicate {
+                key,
+                leadership,
+                start,
+                records,
+                hwm,
+                committed,
+                pending,
+                aborted,
+                batches,
+            } => {
+                self.adopt(&key, leadership.clone(), false, output)?;
+                let partition = self.partition(&key)?;
+                let current = &partition.leadership;
+                if (current.epoch, &current.leader) != (leadership.epoch, &request.src) {
+                    //a deposed leader, tell it who leads now
+                    let reply = KafkaPayload::ReplicateOk {
+                        key,
+                        end: partition.next_offset,
+                        leadership: current.clone(),
+                    };
+                    return self.reply(request, reply, output);
+                }
+                let txns = Txns {
+                    pending: pending.into_iter().collect(),
+                    aborted,
+                    batches: batches.into_iter().collect(),
+                };
+                let end = partition.take(start, records, hwm, committed, txns)?;
+                let leadership = leadership.clone();
+                let reply = KafkaPayload::ReplicateOk {
+                    key,
+                    end,
+                    leadership,
+                };
+                self.reply(request, reply, output)?;
+            }
+
+            KafkaPayload::ReplicateOk {
+                key,
+                end,
+                leadership,
+            } => {
+                self.adopt(&key, leadership.clone(), false, output)?;
+                let Some(partition) = self
+                    .partitions
+                    .get_mut(&key)
+                    .filter(|partition| partition.leadership.leader == self.node)
+                else {
+                    return Ok(());
+                };
+                if leadership.epoch != partition.leadership.epoch {
+                    return Ok(());
+                }
+                if end > partition.next_offset {
+                    return self.resign(&key, request.src, output);
+                }
+                let msg_id = request.body.in_reply_to.unwrap_or_default();
+                for id in partition.acked(&request.src, end, msg_id) {
+                    self.report_resolved(id, key.clone(), output)?;
+                }
+                self.ack(&key, output)?;
+            }
+
+            KafkaPayload::Elected { key, leadership } => {
+                self.adopt(&key, leadership.clone(), false, output)?;
+                //that leadership came out of lin-kv, no need to confirm it again
+                self.confirm(&key, &leadership);
+            }
+
+            KafkaPayload::SendBatch { msgs } => self.send_batch(request, msgs, output)?,
+
+            KafkaPayload::TxnPrepare { txn, msgs } => {
+                for (key, msgs) in msgs {
+                    //we no longer lead it, the batch times out and is aborted
+                    if self.owner(&key)? != self.node {
+                        continue;
+                    }
+                    self.prepare((request.src.clone(), txn), key, msgs, output)?;
+                }
+            }
+
+            KafkaPayload::TxnPrepareOk { txn, key, offsets } => {
+                self.prepared(txn, key, offsets, output)?;
+            }
+
+            KafkaPayload::TxnDecide { txn, commit, ends } => {
+                self.resolve((request.src, txn), commit, &ends, output)?;
+            }
+
+            KafkaPayload::TxnResolved { txn, key } => self.forget(txn, &key),
+
+            KafkaPayload::TxnStatus { txn } => {
+                if let Some(commit) = self.txn_status(txn) {
+                    let ends = self.ends_of(txn);
+                    let decision = KafkaPayload::TxnDecide { txn, commit, ends };
+                    self.reply(request, decision, output)?;
+                }
+            }
+
+            //owners answering requests we forwarded
+            KafkaPayload::SendBatchOk { .. } => {}
+
+            payload @ (KafkaPayload::SendOk { .. }
+            | KafkaPayload::PollOk { .. }
+            | KafkaPayload::CommitOffsetsOk
+            | KafkaPayload::ListCommittedOffsetsOk { .. }
+            | KafkaPayload::Error { .. }) => {
+                self.relay(request.body.in_reply_to, payload, output)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    main_loop::<_, KafkaNode, _, _>(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //a batch's last offset on each key
+    type Ends<'a> = &'a [(&'a str, usize)];
+
+    //a poll's reply: per key its records and where it was visible to, and the batches
+    //among the records, each by where it starts on its key and its ends
+    fn poll_ok(keys: &[(&str, &[usize], usize)], batches: &[(&str, usize, Ends)]) -> KafkaPayload {
+        let mut spans: HashMap<String, Spans> = HashMap::new();
+        for (key, first, ends) in batches {
+            let ends = ends
+                .iter()
+                .map(|(k, last)| (k.to_string(), *last))
+                .collect();
+            spans
+                .entry(key.to_string())
+                .or_default()
+                .push((*first, ends));
+        }
+        KafkaPayload::PollOk {
+            msgs: keys
+                .iter()
+                .filter(|(_, offsets, _)| !offsets.is_empty())
+                .map(|(key, offsets, _)| {
+                    (key.to_string(), offsets.iter().map(|o| (*o, 0)).collect())
+                })
+                .collect(),
+            truncated: HashMap::new(),
+            reach: keys
+                .iter()
+                .map(|(key, _, reach)| (key.to_string(), *reach))
+                .collect(),
+            batches: spans,
+        }
+    }
+
+    fn offsets(reply: &KafkaPayload) -> HashMap<String, Vec<usize>> {
+        let KafkaPayload::PollOk { msgs, .. } = reply else {
+            panic!("not a poll_ok");
+        };
+        msgs.iter()
+            .map(|(key, records)| (key.clone(), records.iter().map(|(o, _)| *o).collect()))
+            .collect()
+    }
+
+    #[test]
+    fn batch_not_yet_visible_on_another_key_is_cut() {
+        //a batch at a:1-2 and b:3, b was read before it was resolved there
+        let batch: Ends = &[("a", 2), ("b", 3)];
+        let mut reply = poll_ok(&[("a", &[0, 1, 2], 3), ("b", &[2], 3)], &[("a", 1, batch)]);
+        settle(&mut reply);
+        let expected = HashMap::from([("a".to_string(), vec![0]), ("b".to_string(), vec![2])]);
+        assert_eq!(offsets(&reply), expected);
+        //once it's visible there too it goes out whole, limits may still split it
+        let mut reply = poll_ok(&[("a", &[0, 1], 3), ("b", &[2, 3], 4)], &[("a", 1, batch)]);
+        settle(&mut reply);
+        assert_eq!(offsets(&reply)["a"], [0, 1]);
+    }
+
+    #[test]
+    fn cutting_one_key_can_cut_another() {
+        //x spans a and b, y spans b and c, c doesn't show y yet
+        let x: Ends = &[("a", 0), ("b", 1)];
+        let y: Ends = &[("b", 0), ("c", 5)];
+        let mut reply = poll_ok(
+            &[("a", &[0], 1), ("b", &[0, 1], 2), ("c", &[4], 5)],
+            &[("a", 0, x), ("b", 1, x), ("b", 0, y)],
+        );
+        settle(&mut reply);
+        assert_eq!(offsets(&reply), HashMap::from([("c".to_string(), vec![4])]));
+    }
+}
@@ -0,0 +1,586 @@
+/*
+    challenge 5b/5c kafka style log, built on maelstrom's kv services
+    offsets are allocated per key with a cas on a counter in lin-kv, so they are
+    unique and monotonic across nodes. messages go to seq-kv under their offset,
+    committed offsets live in lin-kv. every node can serve every key and keeps
+    no state of its own beyond a cache, at the cost of kv round trips on every
+    request. kafka.rs is the variant that owns keys on nodes instead.
+    an offset can be allocated and never filled: the cas took effect but its
+    reply was lost, or the sender crashed before storing the message. a poll
+    that finds such a hole with later offsets filled puts a SKIPPED tombstone
+    there once HOLE_TIMEOUT has passed, and messages only go into empty slots,
+    so a slow sender that finds its slot tombstoned takes a new offset.
+*/
+
+use ds_challenge::{
+    kv::{KvClient, KvPayload, KvResult, LIN_KV, SEQ_KV},
+    *,
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::{Duration, Instant},
+};
+
+//client messages plus replies from the kv services
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+enum Payload {
+    Kafka(KafkaPayload),
+    Kv(KvPayload),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+enum KafkaPayload {
+    Send {
+        key: String,
+        msg: usize,
+    },
+    SendOk {
+        offset: usize,
+    },
+    Poll {
+        offsets: HashMap<String, usize>,
+    },
+    PollOk {
+        msgs: HashMap<String, Vec<(usize, usize)>>,
+    },
+    CommitOffsets {
+        offsets: HashMap<String, usize>,
+    },
+    CommitOffsetsOk,
+    ListCommittedOffsets {
+        keys: Vec<String>,
+    },
+    ListCommittedOffsetsOk {
+        offsets: HashMap<String, usize>,
+    },
+}
+
+//offsets fetched from seq-kv past what's cached, per key per poll
+const POLL_WINDOW: usize = 10;
+
+//a sender stores its message right after allocating the offset, a slot still
+//empty this long after a later one was filled is never going to be
+const HOLE_TIMEOUT: Duration = Duration::from_secs(1);
+
+//stored in a hole so polls can read past it
+const SKIPPED: &str = "skipped";
+
+fn counter_key(key: &str) -> String {
+    format!("offset-{key}")
+}
+
+fn msg_key(key: &str, offset: usize) -> String {
+    format!("msg-{key}-{offset}")
+}
+
+fn commit_key(key: &str) -> String {
+    format!("commit-{key}")
+}
+
+//a client request in progress, possibly waiting on several kv calls
+enum Op {
+    Send {
+        request: Message<()>,
+        key: String,
+        msg: usize,
+    },
+    Poll {
+        request: Message<()>,
+        offsets: HashMap<String, usize>,
+        waiting: usize,
+    },
+    Commit {
+        request: Message<()>,
+        waiting: usize,
+    },
+    List {
+        request: Message<()>,
+        offsets: HashMap<String, usize>,
+        waiting: usize,
+    },
+}
+
+//what an outstanding kv call was for
+enum Call {
+    //cas on the key's offset counter, expecting it to be at `from`
+    Allocate { from: usize },
+    //re-read the offset counter after a lost or indeterminate cas
+    ReadCounter,
+    StoreMsg { offset: usize },
+    //the slot wasn't empty, see whether it holds our message or a tombstone
+    CheckMsg { offset: usize },
+    FetchMsg { key: String, offset: usize },
+    Skip { key: String, offset: usize },
+    CommitKey,
+    ListKey { key: String },
+}
+
+struct KafkaNode {
+    id: usize,
+    lin_kv: KvClient,
+    seq_kv: KvClient,
+    //messages are immutable once written, so everything seen is cached.
+    //None is a skipped offset
+    log: HashMap<String, BTreeMap<usize, Option<usize>>>,
+    //per key, the first offset found missing below a filled one and since when
+    holes: HashMap<String, (usize, Instant)>,
+    //best guess at each key's next offset, saves a read before the cas
+    next_offset: HashMap<String, usize>,
+    ops: HashMap<usize, Op>,
+    next_op: usize,
+    //kv msg_id -> (op, call)
+    calls: HashMap<usize, (usize, Call)>,
+}
+
+impl KafkaNode {
+    fn start_op(&mut self, op: Op) -> usize {
+        let op_id = self.next_op;
+        self.next_op += 1;
+        self.ops.insert(op_id, op);
+        op_id
+    }
+
+    fn allocate(
+        &mut self,
+        op_id: usize,
+        key: &str,
+        from: usize,
+        output: &mut std::io::StdoutLock,
+    ) -> anyhow::Result<()> {
+        let msg_id = self.lin_kv.cas(
+            counter_key(key),
+            from,
+            from + 1,
+            from == 0,
+            &mut self.id,
+            &mut *output,
+        )?;
+        self.calls.insert(msg_id, (op_id, Call::Allocate { from }));
+        Ok(())
+    }
+
+    //fill the slot only if it is still empty, so a tombstone can't be overwritten
+    fn store(
+        &mut self,
+        op_id: usize,
+        key: &str,
+        offset: usize,
+        msg: usize,
+        output: &mut std::io::StdoutLock,
+    ) -> anyhow::Result<()> {
+        let msg_id = self.seq_kv.cas(
+            msg_key(key, offset),
+            Value::Null,
+            msg,
+            true,
+            &mut self.id,
+            &mut *output,
+        )?;
+        self.calls
+            .insert(msg_id, (op_id, Call::StoreMsg { offset }));
+        Ok(())
+    }
+
+    fn reply(
+        &mut self,
+        request: Message<()>,
+        payload: KafkaPayload,
+        output: &mut std::io::StdoutLock,
+    ) -> anyhow::Result<()> {
+        request
+            .with_payload(payload)
+            .derive_response(Some(&mut self.id))
+            .send_self(&mut *output)
+            .context("respond to kafka client")
+    }
+
+    //contiguous run of cached offsets from `from`, never passing a missing one,
+    //and the offset after the run. skipped offsets are left out of the messages
+    fn cached_from(&self, key: &str, from: usize) -> (Vec<(usize, usize)>, usize) {
+        let Some(messages) = self.log.get(key) else {
+            return (Vec::new(), from);
+        };
+        let run: Vec<_> = messages
+            .range(from..)
+            .enumerate()
+            .take_while(|(i, (offset, _))| **offset == from + i)
+            .map(|(_, (offset, msg))| (*offset, *msg))
+            .collect();
+        let next = from + run.len();
+        let messages = run
+            .into_iter()
+            .filter_map(|(offset, msg)| Some((offset, msg?)))
+            .collect();
+        (messages, next)
+    }
+
+    //a poll stopped at `next` although a later offset is filled, tombstone it
+    //once it has stayed empty for HOLE_TIMEOUT
+    fn check_hole(
+        &mut self,
+        op_id: usize,
+        key: &str,
+        next: usize,
+        output: &mut std::io::StdoutLock,
+    ) -> anyhow::Result<()> {
+        let later = self
+            .log
+            .get(key)
+            .is_some_and(|messages| messages.range(next..).next().is_some());
+        if !later {
+            self.holes.remove(key);
+            return Ok(());
+        }
+        match self.holes.get(key) {
+            Some((offset, since)) if *offset == next => {
+                if since.elapsed() < HOLE_TIMEOUT {
+                    return Ok(());
+                }
+            }
+            _ => {
+                self.holes.insert(key.to_string(), (next, Instant::now()));
+                return Ok(());
+            }
+        }
+        let msg_id = self.seq_kv.cas(
+            msg_key(key, next),
+            Value::Null,
+            SKIPPED,
+            true,
+            &mut self.id,
+            &mut *output,
+        )?;
+        let key = key.to_string();
+        self.calls
+            .insert(msg_id, (op_id, Call::Skip { key, offset: next }));
+        Ok(())
+    }
+
+    fn handle_client(
+        &mut self,
+        request: Message<()>,
+        payload: KafkaPayload,
+        output: &mut std::io::StdoutLock,
+    ) -> anyhow::Result<()> {
+        match payload {
+            KafkaPayload::Send { key, msg } => {
+                let from = self.next_offset.get(&key).copied().unwrap_or(0);
+                let op_id = self.start_op(Op::Send {
+                    request,
+                    key: key.clone(),
+                    msg,
+                });
+                self.allocate(op_id, &key, from, output)?;
+            }
+
+            KafkaPayload::Poll { offsets } => {
+                let mut fetches = Vec::new();
+                for (key, from) in &offsets {
+                    let (_, next) = self.cached_from(key, *from);
+                    fetches.extend((next..next + POLL_WINDOW).map(|o| (key.clone(), o)));
+                }
+                let op_id = self.start_op(Op::Poll {
+                    request,
+                    offsets,
+                    waiting: fetches.len(),
+                });
+                if fetches.is_empty() {
+                    return self.finish(op_id, output);
+                }
+                for (key, offset) in fetches {
+                    let msg_id =
+                        self.seq_kv
+                            .read(msg_key(&key, offset), &mut self.id, &mut *output)?;
+                    self.calls
+                        .insert(msg_id, (op_id, Call::FetchMsg { key, offset }));
+                }
+            }
+
+            KafkaPayload::CommitOffsets { offsets } => {
+                let op_id = self.start_op(Op::Commit {
+                    request,
+                    waiting: offsets.len(),
+                });
+                if offsets.is_empty() {
+                    return self.finish(op_id, output);
+                }
+                for (key, offset) in offsets {
+                    let msg_id =
+                        self.lin_kv
+                            .write(commit_key(&key), offset, &mut self.id, &mut *output)?;
+                    self.calls.insert(msg_id, (op_id, Call::CommitKey));
+                }
+            }
+
+            KafkaPayload::ListCommittedOffsets { keys } => {
+                let op_id = self.start_op(Op::List {
+                    request,
+                    offsets: HashMap::new(),
+                    waiting: keys.len(),
+                });
+                if keys.is_empty() {
+                    return self.finish(op_id, output);
+                }
+                for key in keys {
+                    let msg_id = self
+                        .lin_kv
+                        .read(commit_key(&key), &mut self.id, &mut *output)?;
+                    self.calls.insert(msg_id, (op_id, Call::ListKey { key }));
+                }
+            }
+
+            KafkaPayload::SendOk { .. }
+            | KafkaPayload::PollOk { .. }
+            | KafkaPayload::CommitOffsetsOk
+            | KafkaPayload::ListCommittedOffsetsOk { .. } => {}
+        }
+        Ok(())
+    }
+
+    fn handle_kv(
+        &mut self,
+        in_reply_to: Option<usize>,
+        result: KvResult,
+        output: &mut std::io::StdoutLock,
+    ) -> anyhow::Result<()> {
+        let Some((op_id, call)) = in_reply_to.and_then(|id| self.calls.remove(&id)) else {
+            return Ok(());
+        };
+        match call {
+            Call::Allocate { from } => {
+                let Some(Op::Send { key, .. }) = self.ops.get(&op_id) else {
+                    return Ok(());
+                };
+                let key = key.clone();
+                match result {
+                    Ok(_) => {
+                        self.next_offset.insert(key.clone(), from + 1);
+                        let Some(Op::Send { msg, .. }) = self.ops.get(&op_id) else {
+                            return Ok(());
+                        };
+                        self.store(op_id, &key, from, *msg, output)?;
+                    }
+                    //someone else took that offset, or the cas may or may not have gone
+                    //through. either way go by where the counter is now, an offset we
+                    //took without hearing about it is left as a hole for polls to skip
+                    Err(_) => {
+                        let msg_id =
+                            self.lin_kv
+                                .read(counter_key(&key), &mut self.id, &mut *output)?;
+                        self.calls.insert(msg_id, (op_id, Call::ReadCounter));
+                    }
+                }
+            }
+
+            Call::ReadCounter => {
+                let Some(Op::Send { key, .. }) = self.ops.get(&op_id) else {
+                    return Ok(());
+                };
+                let key = key.clone();
+                let from = match result {
+                    Ok(value) => value.as_u64().context("offset counter is not a number")? as usize,
+                    Err(_) => 0,
+                };
+                self.allocate(op_id, &key, from, output)?;
+            }
+
+            Call::StoreMsg { offset } => {
+                let Some(Op::Send { key, msg, .. }) = self.ops.get(&op_id) else {
+                    return Ok(());
+                };
+                let (key, msg) = (key.clone(), *msg);
+                match result {
+                    Ok(_) => self.sent(op_id, offset, output)?,
+                    //a poll has tombstoned it, or an earlier try did get through
+                    Err(ErrorCode::PreconditionFailed) => {
+                        let msg_id =
+                            self.seq_kv
+                                .read(msg_key(&key, offset), &mut self.id, &mut *output)?;
+                        self.calls
+                            .insert(msg_id, (op_id, Call::CheckMsg { offset }));
+                    }
+                    //the offset is still ours, try again
+                    Err(_) => self.store(op_id, &key, offset, msg, output)?,
+                }
+            }
+
+            Call::CheckMsg { offset } => {
+                let Some(Op::Send { key, msg, .. }) = self.ops.get(&op_id) else {
+                    return Ok(());
+                };
+                let (key, msg) = (key.clone(), *msg);
+                match result {
+                    Ok(value) if value.as_u64() == Some(msg as u64) => {
+                        self.sent(op_id, offset, output)?
+                    }
+                    //tombstoned while we were slow, take a new offset
+                    Ok(_) => {
+                        let from = self.next_offset.get(&key).copied().unwrap_or(0);
+                        self.allocate(op_id, &key, from.max(offset + 1), output)?;
+                    }
+                    //seq-kv can serve a stale read that doesn't have it yet
+                    Err(_) => {
+                        let msg_id =
+                            self.seq_kv
+                                .read(msg_key(&key, offset), &mut self.id, &mut *output)?;
+                        self.calls
+                            .insert(msg_id, (op_id, Call::CheckMsg { offset }));
+                    }
+                }
+            }
+
+            Call::FetchMsg { key, offset } => {
+                let msg = match result {
+                    Ok(Value::Number(msg)) => msg.as_u64().map(|msg| Some(msg as usize)),
+                    Ok(Value::String(marker)) if marker == SKIPPED => Some(None),
+                    _ => None,
+                };
+                if let Some(msg) = msg {
+                    self.log.entry(key).or_default().insert(offset, msg);
+                }
+                if let Some(Op::Poll { waiting, .. }) = self.ops.get_mut(&op_id) {
+                    *waiting -= 1;
+                    if *waiting == 0 {
+                        self.finish(op_id, output)?;
+                    }
+                }
+            }
+
+            Call::Skip { key, offset } => {
+                //otherwise it got filled after all, the next poll fetches it
+                if result.is_ok() {
+                    self.log.entry(key).or_default().insert(offset, None);
+                }
+            }
+
+            Call::CommitKey => {
+                if let Some(Op::Commit { waiting, .. }) = self.ops.get_mut(&op_id) {
+                    *waiting -= 1;
+                    if *waiting == 0 {
+                        self.finish(op_id, output)?;
+                    }
+                }
+            }
+
+            Call::ListKey { key } => {
+                if let Some(Op::List {
+                    offsets, waiting, ..
+                }) = self.ops.get_mut(&op_id)
+                {
+                    if let Some(offset) = result.ok().and_then(|v| v.as_u64()) {
+                        offsets.insert(key, offset as usize);
+                    }
+                    *waiting -= 1;
+                    if *waiting == 0 {
+                        self.finish(op_id, output)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    //the send's message is in its slot, answer the client
+    fn sent(
+        &mut self,
+        op_id: usize,
+        offset: usize,
+        output: &mut std::io::StdoutLock,
+    ) -> anyhow::Result<()> {
+        let Some(Op::Send { request, key, msg }) = self.ops.remove(&op_id) else {
+            return Ok(());
+        };
+        self.log.entry(key).or_default().insert(offset, Some(msg));
+        self.reply(request, KafkaPayload::SendOk { offset }, output)
+    }
+
+    //all kv calls for an op are back, answer the client
+    fn finish(&mut self, op_id: usize, output: &mut std::io::StdoutLock) -> anyhow::Result<()> {
+        let Some(op) = self.ops.remove(&op_id) else {
+            return Ok(());
+        };
+        match op {
+            Op::Poll {
+                request, offsets, ..
+            } => {
+                let mut msgs = HashMap::new();
+                for (key, from) in offsets {
+                    let (messages, next) = self.cached_from(&key, from);
+                    self.check_hole(op_id, &key, next, output)?;
+                    if !messages.is_empty() {
+                        msgs.insert(key, messages);
+                    }
+                }
+                self.reply(request, KafkaPayload::PollOk { msgs }, output)
+            }
+            Op::Commit { request, .. } => {
+                self.reply(request, KafkaPayload::CommitOffsetsOk, output)
+            }
+            Op::List {
+                request, offsets, ..
+            } => self.reply(
+                request,
+                KafkaPayload::ListCommittedOffsetsOk { offsets },
+                output,
+            ),
+            Op::Send { .. } => Ok(()),
+        }
+    }
+}
+
+impl Node<(), Payload> for KafkaNode {
+    fn from_init(
+        _state: (),
+        init: Init,
+        _inject: std::sync::mpsc::Sender<Event<Payload, ()>>,
+    ) -> anyhow::Result<Self>
+    where
+        Self: Sized,
+    {
+        Ok(Self {
+            lin_kv: KvClient::new(init.node_id.clone(), LIN_KV),
+            seq_kv: KvClient::new(init.node_id, SEQ_KV),
+            id: 1,
+            log: HashMap::new(),
+            holes: HashMap::new(),
+            next_offset: HashMap::new(),
+            ops: HashMap::new(),
+            next_op: 0,
+            calls: HashMap::new(),
+        })
+    }
+
+    fn handle_input(
+        &mut self,
+        input: Event<Payload, ()>,
+        output: &mut std::io::StdoutLock,
+    ) -> anyhow::Result<()> {
+        match input {
+            Event::EOF => {}
+            Event::Injected(..) => {}
+            Event::Message(input) => {
+                let (payload, input) = input.into_parts();
+                match payload {
+                    Payload::Kafka(payload) => self.handle_client(input, payload, output)?,
+                    Payload::Kv(payload) => {
+                        if let Some(result) = payload.into_result() {
+                            self.handle_kv(input.body.in_reply_to, result, output)?;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    main_loop::<_, KafkaNode, _, _>(())
+}
@@ -66,7 +66,7 @@ impl Node<(), Payload, InjectedPayload> for RecordNode {
     {
         std::thread::spawn(move || loop {
             std::thread::sleep(Duration::from_millis(250));
-            if let Err(_) = tx.send(Event::Injected(InjectedPayload::Gossip)) {
+            if tx.send(Event::Injected(InjectedPayload::Gossip)).is_err() {
                 break;
             }
         });
@@ -118,7 +118,7 @@ impl Node<(), Payload, InjectedPayload> for RecordNode {
                         eprintln!("received gossip, with record {:?}", &history);
                         eprintln!("current record before merge {:?}", &self.record);
                         for (record_key, gossip_vec) in history {
-                            let own_vec = self.record.entry(record_key).or_insert(HashSet::new());
+                            let own_vec = self.record.entry(record_key).or_default();
                             own_vec.extend(gossip_vec);
                         }
                         eprintln!("record after gossip{:?}", &self.record);
@@ -127,7 +127,7 @@ impl Node<(), Payload, InjectedPayload> for RecordNode {
                     Payload::Send { key, msg } => {
                         eprintln!("RECEIVED SEND for key {key}, message:{msg}");
                         let mut send_offset = &key.parse::<usize>().unwrap() * 10000;
-                        let key_set = self.record.entry(key).or_insert(HashSet::new());
+                        let key_set = self.record.entry(key).or_default();
                         key_set.insert(msg);
                         send_offset += msg;
                         eprintln!("current record after adding from send {:?}", self.record);
@@ -202,7 +202,7 @@ impl Node<(), Payload, InjectedPayload> for RecordNode {
                         let mut ret_map: HashMap<String, usize> = HashMap::new();
                         for key in keys {
                             if let Some(val) = self.committed_offsets.get(&key) {
-                                ret_map.insert(key, val.clone());
+                                ret_map.insert(key, *val);
                             } else {
                                 eprintln!("KEY NOT FOUND IN LIST COMMITTED OFFSETS");
                                 continue;
@@ -0,0 +1,166 @@
+/*
+    lin-kv workload: a linearizable key-value store
+    every read/write/cas goes through the replicated log, served by
+    `replication::ClientNode` (followers forward to the leader, the node the
+    client asked answers once the op is applied there).
+    engine is raft by default, LIN_KV_ENGINE=paxos switches to multi-paxos
+*/
+
+use ds_challenge::{
+    paxos::Paxos,
+    raft::Raft,
+    replication::{ClientNode, Served, Service, StateMachine},
+    *,
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum KvPayload {
+    Read {
+        key: usize,
+    },
+    ReadOk {
+        value: usize,
+    },
+    Write {
+        key: usize,
+        value: usize,
+    },
+    WriteOk,
+    Cas {
+        key: usize,
+        from: usize,
+        to: usize,
+        #[serde(default)]
+        create_if_not_exists: bool,
+    },
+    CasOk,
+    Error {
+        code: usize,
+        text: String,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "f")]
+#[serde(rename_all = "snake_case")]
+enum KvOp {
+    Read {
+        key: usize,
+    },
+    Write {
+        key: usize,
+        value: usize,
+    },
+    Cas {
+        key: usize,
+        from: usize,
+        to: usize,
+        create_if_not_exists: bool,
+    },
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct KvStore {
+    data: HashMap<usize, usize>,
+}
+
+impl StateMachine for KvStore {
+    type Command = KvOp;
+    type Output = KvPayload;
+
+    fn apply(&mut self, op: KvOp) -> KvPayload {
+        let missing = |key| KvPayload::Error {
+            code: ErrorCode::KeyDoesNotExist.code(),
+            text: format!("key {key} does not exist"),
+        };
+        match op {
+            KvOp::Read { key } => match self.data.get(&key) {
+                Some(value) => KvPayload::ReadOk { value: *value },
+                None => missing(key),
+            },
+            KvOp::Write { key, value } => {
+                self.data.insert(key, value);
+                KvPayload::WriteOk
+            }
+            KvOp::Cas {
+                key,
+                from,
+                to,
+                create_if_not_exists,
+            } => match self.data.get_mut(&key) {
+                Some(current) if *current == from => {
+                    *current = to;
+                    KvPayload::CasOk
+                }
+                Some(current) => KvPayload::Error {
+                    code: ErrorCode::PreconditionFailed.code(),
+                    text: format!("expected {from}, found {current}"),
+                },
+                None if create_if_not_exists => {
+                    self.data.insert(key, to);
+                    KvPayload::CasOk
+                }
+                None => missing(key),
+            },
+        }
+    }
+
+    fn snapshot(&self) -> anyhow::Result<String> {
+        serde_json::to_string(self).context("serialize kv snapshot")
+    }
+
+    fn restore(&mut self, snapshot: &str) -> anyhow::Result<()> {
+        *self = serde_json::from_str(snapshot).context("deserialize kv snapshot")?;
+        Ok(())
+    }
+}
+
+impl Service for KvStore {
+    type Payload = KvPayload;
+
+    fn request(payload: KvPayload) -> Option<Result<KvOp, KvPayload>> {
+        Some(Ok(match payload {
+            KvPayload::Read { key } => KvOp::Read { key },
+            KvPayload::Write { key, value } => KvOp::Write { key, value },
+            KvPayload::Cas {
+                key,
+                from,
+                to,
+                create_if_not_exists,
+            } => KvOp::Cas {
+                key,
+                from,
+                to,
+                create_if_not_exists,
+            },
+            KvPayload::ReadOk { .. }
+            | KvPayload::WriteOk
+            | KvPayload::CasOk
+            | KvPayload::Error { .. } => return None,
+        }))
+    }
+
+    fn reply(output: KvPayload) -> KvPayload {
+        output
+    }
+
+    fn error(code: ErrorCode, text: String) -> KvPayload {
+        KvPayload::Error {
+            code: code.code(),
+            text,
+        }
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    match std::env::var("LIN_KV_ENGINE").as_deref() {
+        Ok("paxos") => main_loop::<_, ClientNode<KvStore, Paxos<Served<KvStore>>>, _, _>(()),
+        _ => main_loop::<_, ClientNode<KvStore, Raft<Served<KvStore>>>, _, _>(()),
+    }
+}
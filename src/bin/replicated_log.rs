@@ -1,4 +1,4 @@
-use ds_challenge::*;
+use ds_challenge::{records::PollLimits, *};
 
 use anyhow::Context;
 use serde::{Deserialize, Serialize};
@@ -17,6 +17,8 @@ enum Payload {
     },
     Poll {
         offsets: HashMap<String, usize>,
+        #[serde(flatten)]
+        limits: PollLimits,
     },
     PollOk {
         msgs: HashMap<String, Vec<(usize, usize)>>,
@@ -75,7 +77,7 @@ impl Node<(), Payload> for LogNode {
                 let mut response = input.derive_response(Some(&mut self.id));
                 match response.body.payload {
                     Payload::Send { key, msg } => {
-                        let key_set = self.log.entry(key).or_insert(Vec::new());
+                        let key_set = self.log.entry(key).or_default();
                         // key_set.insert((self.count, msg));
                         key_set.push((self.count, msg));
                         response.body.payload = Payload::SendOk { offset: self.count };
@@ -86,7 +88,7 @@ impl Node<(), Payload> for LogNode {
                         self.count += 1;
                     }
 
-                    Payload::Poll { offsets } => {
+                    Payload::Poll { offsets, limits } => {
                         // let mut ret_map: HashMap<String, HashSet<(usize, usize)>> = HashMap::new();
                         let mut ret_map: HashMap<String, Vec<(usize, usize)>> = HashMap::new();
                         for (k, v) in offsets {
@@ -94,13 +96,10 @@ impl Node<(), Payload> for LogNode {
                                 eprintln!("KEY: {k} NOT FOUND in {:?}", self.log);
                                 continue;
                             };
-                            let mut ret_set: Vec<(usize, usize)> = key_set
-                                .clone() //remove clone
-                                .into_iter()
-                                .filter(|(log_key, _)| *log_key >= v)
-                                .collect();
-                            // let ret_set: Vec<(usize, usize)> = ret_set.s;
-                            ret_set.sort();
+                            //offsets are handed out in order so key_set is already sorted
+                            let ret_set = limits.window(
+                                key_set.iter().copied().filter(|(log_key, _)| *log_key >= v),
+                            );
                             ret_map.insert(k, ret_set);
                         }
                         response.body.payload = Payload::PollOk { msgs: ret_map };
@@ -125,7 +124,7 @@ impl Node<(), Payload> for LogNode {
                         for key in keys {
                             // if !self.committed_offsets.contains_key(&key) {continue;}
                             if let Some(val) = self.committed_offsets.get(&key) {
-                                ret_map.insert(key, val.clone());
+                                ret_map.insert(key, *val);
                             } else {
                                 eprintln!("KEY NOT FOUND IN LIST COMMITTES OFFSETS");
                                 continue;
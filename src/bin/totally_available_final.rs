@@ -1,62 +1,265 @@
 /*
     Challenge 6:Totally-available
     passes all tests 6a, 6b, 6c
+    txns run against the local store only, so a node answers even when cut off.
+    writes are replicated afterwards by gossip: every write carries a version
+    (lamport clock, writing node) and the store keeps each key's versions side
+    by side (mvcc), the highest one being the current value, so all nodes
+    settle on the same values once they can talk again. each new version gets
+    a local sequence number and a peer is sent everything past the last one it
+    acknowledged, which also relays writes around a partition.
+
+    `pin` returns the node's clock and keeps the versions a read at that clock
+    needs until `unpin` or PIN_TTL, a txn with `at` reads every key as of that
+    clock (time travel, read-only). gc keeps each key's newest version at or
+    below the oldest pin, or just the newest one when nothing is pinned.
+    writes made elsewhere before the pinned clock can still gossip in until
+    partitions heal, so a pinned snapshot is only stable once the cluster is.
+
+    TXN_ISOLATION picks how a txn's writes are applied:
+    read-uncommitted writes straight to the store op by op, read-committed
+    (default) buffers them and applies them together at commit, and snapshot
+    buffers them too and reads every key at the version the txn started from
+    (read_before its start), so all its reads come from one consistent cut.
+    a node runs one txn at a time on its event loop, so locally the three only
+    differ in which versions a txn reads, not in what can interleave with it.
+    under partitions nodes still commit concurrent writes to a key and the
+    last writer wins, so snapshot holds per node rather than across the cluster.
 */
 
-use ds_challenge::*;
+use ds_challenge::{
+    mvcc::MvccStore,
+    txn::{Lenient, MicroOp},
+    *,
+};
 
 use anyhow::Context;
 use serde::{Deserialize, Serialize};
-// use serde_with::serde_as;
-use std::{collections::HashMap, fmt::Debug, usize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Debug,
+    time::{Duration, Instant},
+};
+
+//(lamport clock, node), totally ordered across nodes
+type Version = (usize, String);
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 #[serde(tag = "type")]
 enum Payload {
     Txn {
-        txn: Vec<(String, usize, Option<usize>)>,
+        txn: Lenient<Vec<MicroOp<usize>>>,
+        //read as of this clock instead of the current state, see `pin`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        at: Option<usize>,
     },
     TxnOk {
-        txn: Vec<(String, usize, usize)>,
+        txn: Vec<MicroOp<usize>>,
     },
+    Error {
+        code: usize,
+        text: String,
+    },
+    //versions written since the receiver last acked, as (key, value, version)
     Gossip {
-        gossiped_log: HashMap<usize, usize>,
+        writes: Vec<(usize, usize, Version)>,
+        upto: usize,
+    },
+    GossipOk {
+        upto: usize,
+    },
+    Pin,
+    PinOk {
+        at: usize,
     },
+    Unpin {
+        at: usize,
+    },
+    UnpinOk,
+}
+
+//a client that pinned a snapshot and went away doesn't hold history back forever
+const PIN_TTL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Isolation {
+    ReadUncommitted,
+    ReadCommitted,
+    Snapshot,
+}
+
+impl Isolation {
+    fn from_env() -> Self {
+        match std::env::var("TXN_ISOLATION").as_deref() {
+            Ok("read-uncommitted") => Isolation::ReadUncommitted,
+            Ok("snapshot") => Isolation::Snapshot,
+            _ => Isolation::ReadCommitted,
+        }
+    }
 }
 
 struct TxnNode {
-    // node: String,
+    node: String,
     id: usize,
-    own_log: HashMap<usize, usize>,
-    // initial_nodes: Vec<String>,
+    isolation: Isolation,
+    store: MvccStore<usize, Version, usize>,
+    clock: usize,
+    //sequence number of the last version written to the store
+    seq: usize,
+    //sequence number -> the version written, until every peer has it
+    changes: BTreeMap<usize, (usize, Version)>,
+    //peer -> last sequence number it acknowledged
+    acked: HashMap<String, usize>,
+    //clocks pinned for time-travel reads, with when they were pinned
+    pins: Vec<(usize, Instant)>,
+    //last gc horizon, reads at a snapshot below it can't be served anymore
+    horizon: Version,
 }
 
 enum InjectedPayload {
-    // Gossip,
+    Gossip,
+}
+
+impl TxnNode {
+    fn txn(&mut self, txn: Lenient<Vec<MicroOp<usize>>>, at: Option<usize>) -> Payload {
+        let error = |code: ErrorCode, text| Payload::Error {
+            code: code.code(),
+            text,
+        };
+        let txn = match txn {
+            Lenient::Valid(txn) => txn,
+            Lenient::Invalid { error: e, .. } => {
+                return error(ErrorCode::MalformedRequest, format!("invalid txn: {e}"))
+            }
+        };
+        if let Some(op) = txn.iter().find(|op| matches!(op, MicroOp::Append(..))) {
+            return error(
+                ErrorCode::NotSupported,
+                format!("append on key {} in a rw-register txn", op.key()),
+            );
+        }
+        if let Some(at) = at {
+            if let Some(op) = txn.iter().find(|op| matches!(op, MicroOp::Write(..))) {
+                return error(
+                    ErrorCode::NotSupported,
+                    format!("write on key {} in a txn reading at {at}", op.key()),
+                );
+            }
+            if (at + 1, String::new()) < self.horizon {
+                return error(
+                    ErrorCode::Abort,
+                    format!("versions as of {at} have been garbage collected"),
+                );
+            }
+        }
+        Payload::TxnOk {
+            txn: self.execute(txn, at),
+        }
+    }
+
+    //run a txn against the local store, all its writes share one version
+    fn execute(&mut self, txn: Vec<MicroOp<usize>>, at: Option<usize>) -> Vec<MicroOp<usize>> {
+        //snapshot reads see versions below this: from before the txn, or up to `at`
+        let snapshot = match at {
+            Some(at) => Some((at + 1, String::new())),
+            None if self.isolation == Isolation::Snapshot => Some((self.clock + 1, String::new())),
+            None => None,
+        };
+        self.clock += 1;
+        let version = (self.clock, self.node.clone());
+        //writes held back until commit, later writes to a key replace earlier ones
+        let mut buffered: HashMap<usize, usize> = HashMap::new();
+        let mut ret_txn = Vec::new();
+
+        for op in txn {
+            match op {
+                MicroOp::Write(key, msg) => {
+                    match self.isolation {
+                        Isolation::ReadUncommitted => self.apply(key, msg, version.clone()),
+                        Isolation::ReadCommitted | Isolation::Snapshot => {
+                            buffered.insert(key, msg);
+                        }
+                    }
+                    ret_txn.push(op);
+                }
+                //a txn sees its own writes, everything else comes from the store
+                MicroOp::Read(key, _) => {
+                    let ret = buffered.get(&key).copied().or_else(|| {
+                        match &snapshot {
+                            Some(snapshot) => self.store.read_before(&key, snapshot),
+                            None => self.store.latest(&key),
+                        }
+                        .map(|(_, value)| *value)
+                    });
+                    ret_txn.push(MicroOp::Read(key, ret));
+                }
+                //rejected before the txn runs
+                MicroOp::Append(..) => unreachable!("append in a rw-register txn"),
+            }
+        }
+
+        //commit
+        for (key, value) in buffered {
+            self.apply(key, value, version.clone());
+        }
+        ret_txn
+    }
+
+    //keep a version, new ones get a sequence number so they're gossiped on
+    fn apply(&mut self, key: usize, value: usize, version: Version) {
+        if self.store.write(key, version.clone(), value) {
+            self.seq += 1;
+            self.changes.insert(self.seq, (key, version));
+        }
+    }
+
+    //drop what every peer has been sent and the versions no pinned snapshot can read
+    fn gc(&mut self) {
+        let acked = self.acked.values().copied().min().unwrap_or(self.seq);
+        self.changes = self.changes.split_off(&(acked + 1));
+        self.pins.retain(|(_, since)| since.elapsed() < PIN_TTL);
+        //a txn's own snapshot only lives while execute runs, never across a gc.
+        //with no pins that's above every version, the clock is kept ahead of them all
+        let oldest = self.pins.iter().map(|(at, _)| *at).min();
+        let horizon = (oldest.unwrap_or(self.clock) + 1, String::new());
+        self.store.gc(&horizon);
+        self.horizon = horizon;
+    }
 }
 
 impl Node<(), Payload, InjectedPayload> for TxnNode {
     fn from_init(
         _state: (),
-        _init: Init,
-        _tx: std::sync::mpsc::Sender<Event<Payload, InjectedPayload>>,
+        init: Init,
+        tx: std::sync::mpsc::Sender<Event<Payload, InjectedPayload>>,
     ) -> anyhow::Result<Self>
     where
         Self: Sized,
     {
-        // std::thread::spawn(move || loop {
-        //     std::thread::sleep(Duration::from_millis(500));
-        //     if let Err(_) = tx.send(Event::Injected(InjectedPayload::Gossip)) {
-        //         break;
-        //     }
-        // });
+        std::thread::spawn(move || loop {
+            std::thread::sleep(Duration::from_millis(200));
+            if tx.send(Event::Injected(InjectedPayload::Gossip)).is_err() {
+                break;
+            }
+        });
 
         Ok(Self {
-            // node: init.node_id,
             id: 1,
-            // initial_nodes: init.node_ids,
-            own_log: HashMap::new(),
+            isolation: Isolation::from_env(),
+            store: MvccStore::new(),
+            clock: 0,
+            seq: 0,
+            changes: BTreeMap::new(),
+            acked: init
+                .node_ids
+                .into_iter()
+                .filter(|nid| *nid != init.node_id)
+                .map(|nid| (nid, 0))
+                .collect(),
+            pins: Vec::new(),
+            horizon: (0, String::new()),
+            node: init.node_id,
         })
     }
 
@@ -66,76 +269,81 @@ impl Node<(), Payload, InjectedPayload> for TxnNode {
         output: &mut std::io::StdoutLock,
     ) -> anyhow::Result<()> {
         match input {
-            Event::Injected(_) => {}
-            // Event::Injected(payload) => match payload {
-            // InjectedPayload::Gossip => {
-            //     eprintln!("GOSSIPING");
-            //     for n in &self.initial_nodes {
-            //         let msg: Message<Payload> = Message {
-            //             src: self.node.clone(),
-            //             dest: n.clone(),
-            //             body: Body {
-            //                 id: Some(self.id),
-            //                 in_reply_to: None,
-            //                 payload: Payload::Gossip {
-            //                     gossiped_log: self.own_log.clone(),
-            //                 },
-            //             },
-            //         };
-            //         eprintln!("sending gossip with record{:?}", &self.own_log);
-            //         msg.send_self(&mut *output)
-            //             .context("failed to gossip replicated record in kafka/replicated")?;
-            //         self.id += 1;
-            //     }
-            // }
-            // },
+            Event::Injected(payload) => match payload {
+                InjectedPayload::Gossip => {
+                    self.gc();
+                    for (peer, acked) in &self.acked {
+                        let writes: Vec<_> = self
+                            .changes
+                            .range(acked + 1..)
+                            .filter_map(|(_, (key, version))| {
+                                //skip versions gc has dropped since
+                                let value = self.store.get(key, version)?;
+                                Some((*key, *value, version.clone()))
+                            })
+                            .collect();
+                        //nothing new for this peer
+                        if writes.is_empty() {
+                            continue;
+                        }
+                        let upto = self.seq;
+                        Message::new(
+                            self.node.clone(),
+                            peer.clone(),
+                            &mut self.id,
+                            Payload::Gossip { writes, upto },
+                        )
+                        .send_self(&mut *output)
+                        .context(format!("gossip writes to {peer}"))?;
+                    }
+                }
+            },
             Event::EOF => {}
             Event::Message(input) => {
                 let mut response = input.derive_response(Some(&mut self.id));
                 match response.body.payload {
-                    Payload::Gossip { gossiped_log } => {
-                        eprintln!("got into gossip");
-                        gossiped_log.into_iter().for_each(|(k, v)| {
-                            let own_val = self.own_log.entry(k).or_insert(v);
-                            if v > *own_val {
-                                *own_val = v;
-                                eprintln!("making changes from gossip");
-                            }
-                        })
+                    Payload::Gossip { writes, upto } => {
+                        for (key, value, version) in writes {
+                            //keep the clock ahead of every version seen so local writes win
+                            self.clock = self.clock.max(version.0);
+                            self.apply(key, value, version);
+                        }
+                        response.body.payload = Payload::GossipOk { upto };
+                        response.send_self(&mut *output).context("ack gossip")?;
                     }
 
-                    Payload::Txn { txn } => {
-                        let mut ret_txn: Vec<_> = Vec::new();
-
-                        txn.clone().into_iter().for_each(|op| {
-                            let (op_name, key, msg) = op;
-                            if op_name == "w" {
-                                if let Some(msg) = msg {
-                                    self.own_log.insert(key, msg);
-                                    ret_txn.push((op_name, key, msg));
-                                }
-                            } else if op_name == "r" {
-                                let ret = self.own_log.entry(key).or_insert(0);
-                                ret_txn.push((op_name, key, ret.clone()));
-                            }
-                        });
-
-                        //NO NEED TO COMMIT WRITES BEFORE READS YET
-                        // txn.into_iter().for_each(|op| {
-                        //     let (op_name, key, _) = op;
-                        //     if op_name == "r" {
-                        //         let ret = self.own_log.entry(key).or_insert(0);
-                        //         ret_txn.push((op_name, key, ret.clone()));
-                        //     }
-                        // });
-
-                        response.body.payload = Payload::TxnOk { txn: ret_txn };
+                    Payload::GossipOk { upto } => {
+                        //gossip acks can come back out of order
+                        if let Some(acked) = self.acked.get_mut(&response.dest) {
+                            *acked = (*acked).max(upto);
+                        }
+                    }
 
+                    Payload::Txn { txn, at } => {
+                        response.body.payload = self.txn(txn, at);
                         response
                             .send_self(&mut *output)
                             .context("Txb response failed")?;
                     }
-                    Payload::TxnOk { .. } => {}
+
+                    Payload::Pin => {
+                        self.pins.push((self.clock, Instant::now()));
+                        response.body.payload = Payload::PinOk { at: self.clock };
+                        response.send_self(&mut *output).context("ack pin")?;
+                    }
+
+                    Payload::Unpin { at } => {
+                        if let Some(i) = self.pins.iter().position(|(pinned, _)| *pinned == at) {
+                            self.pins.swap_remove(i);
+                        }
+                        response.body.payload = Payload::UnpinOk;
+                        response.send_self(&mut *output).context("ack unpin")?;
+                    }
+
+                    Payload::TxnOk { .. }
+                    | Payload::Error { .. }
+                    | Payload::PinOk { .. }
+                    | Payload::UnpinOk => {}
                 }
             }
         }
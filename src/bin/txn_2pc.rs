@@ -0,0 +1,1089 @@
+/*
+    txn-rw-register, serializable across a sharded store with two-phase commit
+    keys are spread over the nodes by a hash ring. the node a client talks to
+    coordinates: it sends each owner its share of the ops (prepare), the owner
+    locks those keys (exclusive for writes, shared for reads), evaluates the
+    reads and votes. all yes: the coordinator records commit in lin-kv and
+    tells the owners, otherwise everyone aborts and the client gets
+    txn-conflict. locks are held until the decision (strict 2pl).
+    a txn's number is its start time, which orders txns by age for deadlock
+    prevention: with wait-die (default) an older txn waits for a lock and a
+    younger one votes no, TXN_DEADLOCK=wound-wait has an older txn abort the
+    younger ones in its way and a younger one wait. a txn that has voted yes
+    can't be wounded anymore, so an older one waits for its outcome instead.
+    the decision lives in lin-kv, not on the coordinator, so a participant left
+    in doubt (coordinator restarted or cut off) settles it there itself: it
+    tries to record abort, and if commit is already recorded it commits.
+    whichever write lands first is the outcome for everyone. owners keep their
+    keys in memory only, a restarted owner comes back empty.
+
+    TXN_CC=occ swaps locking for optimistic concurrency control: the
+    coordinator first fetches what the txn reads, with each key's version,
+    without locking anything. the prepare then carries those versions and an
+    owner votes no if any key changed since (or another txn is committing it),
+    so locks are only held for the commit itself, and nobody waits for one.
+    either way a no vote aborts
+    with txn-conflict. `txn_stats` reports how many txns this node coordinated
+    committed and aborted, to compare the two under the same workload, and
+    checks its lock waits for a deadlock the policy should have ruled out.
+*/
+
+use ds_challenge::{
+    kv::{KvClient, KvPayload, LIN_KV},
+    locks::{Acquire, LockManager, LockMode, Policy},
+    partition::{self, HashRing},
+    txn::{Lenient, MicroOp},
+    *,
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    io::Write,
+    time::{Duration, Instant},
+};
+
+//client and peer messages plus replies from lin-kv
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+enum Payload {
+    Kv(KvPayload),
+    Txn(TxnPayload),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum TxnPayload {
+    Txn {
+        txn: Lenient<Vec<MicroOp<usize>>>,
+    },
+    TxnOk {
+        txn: Vec<MicroOp<usize>>,
+    },
+    Error {
+        code: usize,
+        text: String,
+    },
+    //coordinator -> owner (occ): current values and versions of these keys
+    Fetch {
+        txn: usize,
+        keys: Vec<usize>,
+    },
+    //(key, value, version)
+    FetchOk {
+        txn: usize,
+        values: Vec<(usize, Option<usize>, usize)>,
+    },
+    //coordinator -> owner: lock these keys and evaluate the ops, by their index in the txn.
+    //with occ, only if the keys fetched are still at these versions
+    Prepare {
+        txn: usize,
+        ops: Vec<(usize, MicroOp<usize>)>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        versions: Vec<(usize, usize)>,
+    },
+    //owner -> coordinator: yes vote, with what the reads returned
+    PrepareOk {
+        txn: usize,
+        reads: Vec<(usize, Option<usize>)>,
+    },
+    //owner -> coordinator: no vote, some key was locked by another txn
+    PrepareFailed {
+        txn: usize,
+    },
+    Decide {
+        txn: usize,
+        commit: bool,
+    },
+    TxnStats,
+    TxnStatsOk {
+        control: String,
+        committed: usize,
+        aborted: usize,
+        abort_rate: f64,
+        //a cycle among txns waiting for this node's locks, the policy should rule it out
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        deadlock: Option<Vec<TxnId>>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Control {
+    //two-phase locking: locks taken at prepare, conflicts vote no
+    Locking,
+    //optimistic: unlocked reads first, validated against versions at prepare
+    Optimistic,
+}
+
+impl Control {
+    fn from_env() -> Self {
+        match std::env::var("TXN_CC").as_deref() {
+            Ok("occ") => Control::Optimistic,
+            _ => Control::Locking,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Control::Locking => "2pl",
+            Control::Optimistic => "occ",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum InjectedPayload {
+    Tick,
+}
+
+//coordinator gives up on votes by then and aborts, an owner on the locks it waits for
+const PREPARE_TIMEOUT: Duration = Duration::from_millis(1000);
+//a prepared txn not decided by then gets settled through lin-kv
+const IN_DOUBT: Duration = Duration::from_millis(2000);
+
+//a txn as owners know it: the coordinator's number for it, then the coordinator.
+//ordered by age, the number is the txn's start time
+type TxnId = (usize, String);
+
+//where a txn's outcome is recorded, "commit" or "abort"
+fn decision_key((txn, coordinator): &TxnId) -> String {
+    format!("2pc/{coordinator}/{txn}")
+}
+
+//never stored, so a cas from it only succeeds by creating the key
+const UNDECIDED: &str = "undecided";
+
+//owner side: a prepare still taking its locks, one key at a time in key order
+struct Acquiring {
+    ops: Vec<(usize, MicroOp<usize>)>,
+    left: VecDeque<(usize, LockMode)>,
+    since: Instant,
+}
+
+//owner side: a txn that voted yes and holds its locks until it's decided
+struct Prepared {
+    writes: Vec<(usize, usize)>,
+    since: Instant,
+    //a lin-kv request settling it is in flight
+    settling: bool,
+}
+
+//coordinator side
+struct Coordinating {
+    request: Message<()>,
+    ops: Vec<MicroOp<usize>>,
+    participants: Vec<String>,
+    //occ: fetches still to come, and key -> (value, version) from those in
+    fetching: usize,
+    fetched: HashMap<usize, (Option<usize>, usize)>,
+    //votes still to come
+    waiting: usize,
+    started: Instant,
+    //the commit is being recorded in lin-kv
+    committing: bool,
+}
+
+//what a lin-kv request was for, by its msg_id
+#[derive(Debug)]
+enum Call {
+    //coordinator recording commit
+    Commit(usize),
+    //owner recording abort for a txn it's in doubt about
+    Abort(TxnId),
+    //owner reading the recorded outcome after its abort lost the race
+    Lookup(TxnId),
+}
+
+struct TwoPcNode {
+    node: String,
+    id: usize,
+    ring: HashRing,
+    kv: KvClient,
+    control: Control,
+    //owner side
+    store: HashMap<usize, usize>,
+    //bumped on every committed write, for occ validation
+    versions: HashMap<usize, usize>,
+    locks: LockManager<usize, TxnId>,
+    acquiring: HashMap<TxnId, Acquiring>,
+    prepared: HashMap<TxnId, Prepared>,
+    //coordinator side
+    txns: HashMap<usize, Coordinating>,
+    next_txn: usize,
+    calls: HashMap<usize, Call>,
+    //outcomes of the txns we coordinated
+    committed: usize,
+    aborted: usize,
+}
+
+impl TwoPcNode {
+    fn new(init: Init, control: Control, policy: Policy) -> Self {
+        Self {
+            ring: HashRing::new(&init.node_ids, partition::DEFAULT_VNODES),
+            kv: KvClient::new(init.node_id.clone(), LIN_KV),
+            node: init.node_id,
+            id: 1,
+            control,
+            store: HashMap::new(),
+            versions: HashMap::new(),
+            locks: LockManager::new(policy),
+            acquiring: HashMap::new(),
+            prepared: HashMap::new(),
+            txns: HashMap::new(),
+            //txn numbers come from the clock, so a restarted coordinator doesn't reuse
+            //numbers whose outcome is already recorded
+            next_txn: now_micros(),
+            calls: HashMap::new(),
+            committed: 0,
+            aborted: 0,
+        }
+    }
+
+    fn owner(&self, key: usize) -> String {
+        self.ring
+            .owner(&key.to_string())
+            .expect("hash ring has no nodes")
+            .to_string()
+    }
+
+    fn reply(
+        &mut self,
+        request: Message<()>,
+        payload: TxnPayload,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        request
+            .with_payload(payload)
+            .derive_response(Some(&mut self.id))
+            .send_self(&mut *output)
+            .context("reply to txn")
+    }
+
+    fn send(
+        &mut self,
+        dest: String,
+        payload: TxnPayload,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        Message::new(self.node.clone(), dest, &mut self.id, payload)
+            .send_self(&mut *output)
+            .context("send 2pc message")
+    }
+
+    //group a txn's ops, with their index in it, by the owner of their key
+    fn shares(&self, ops: &[MicroOp<usize>]) -> HashMap<String, Vec<(usize, MicroOp<usize>)>> {
+        let mut shares: HashMap<String, Vec<(usize, MicroOp<usize>)>> = HashMap::new();
+        for (index, op) in ops.iter().enumerate() {
+            shares
+                .entry(self.owner(op.key()))
+                .or_default()
+                .push((index, op.clone()));
+        }
+        shares
+    }
+
+    //coordinator: start a txn, with occ by fetching what it reads
+    fn begin(
+        &mut self,
+        request: Message<()>,
+        ops: Vec<MicroOp<usize>>,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        //the start time, or just past the last txn if the clock hasn't moved
+        let txn = self.next_txn.max(now_micros());
+        self.next_txn = txn + 1;
+        let mut fetches: HashMap<String, Vec<usize>> = HashMap::new();
+        if self.control == Control::Optimistic {
+            for op in &ops {
+                if let MicroOp::Read(key, _) = op {
+                    fetches.entry(self.owner(*key)).or_default().push(*key);
+                }
+            }
+        }
+        let shares = self.shares(&ops);
+        let coordinating = Coordinating {
+            request,
+            ops,
+            participants: shares.keys().cloned().collect(),
+            fetching: fetches.len(),
+            fetched: HashMap::new(),
+            waiting: shares.len(),
+            started: Instant::now(),
+            committing: false,
+        };
+        self.txns.insert(txn, coordinating);
+        if fetches.is_empty() {
+            return self.prepare_all(txn, output);
+        }
+        let local = fetches.remove(&self.node);
+        for (owner, keys) in fetches {
+            self.send(owner, TxnPayload::Fetch { txn, keys }, output)?;
+        }
+        if let Some(keys) = local {
+            let values = self.fetch(keys);
+            self.fetched(txn, values, output)?;
+        }
+        Ok(())
+    }
+
+    //owner (occ): read without locking
+    fn fetch(&self, keys: Vec<usize>) -> Vec<(usize, Option<usize>, usize)> {
+        keys.into_iter()
+            .map(|key| {
+                let version = self.versions.get(&key).copied().unwrap_or(0);
+                (key, self.store.get(&key).copied(), version)
+            })
+            .collect()
+    }
+
+    //coordinator (occ): one owner's reads are in, prepare once they all are
+    fn fetched(
+        &mut self,
+        txn: usize,
+        values: Vec<(usize, Option<usize>, usize)>,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        let Some(coordinating) = self.txns.get_mut(&txn) else {
+            return Ok(());
+        };
+        for (key, value, version) in values {
+            coordinating.fetched.insert(key, (value, version));
+        }
+        coordinating.fetching -= 1;
+        if coordinating.fetching > 0 {
+            return Ok(());
+        }
+        self.prepare_all(txn, output)
+    }
+
+    //coordinator: hand every owner its share of the txn
+    fn prepare_all(&mut self, txn: usize, output: &mut impl Write) -> anyhow::Result<()> {
+        let coordinating = &self.txns[&txn];
+        let fetched = coordinating.fetched.clone();
+        let mut shares = self.shares(&coordinating.ops);
+        let versions_of = |ops: &[(usize, MicroOp<usize>)]| -> Vec<(usize, usize)> {
+            let keys: HashSet<usize> = ops.iter().map(|(_, op)| op.key()).collect();
+            keys.into_iter()
+                .filter_map(|key| Some((key, fetched.get(&key)?.1)))
+                .collect()
+        };
+        let local = shares.remove(&self.node);
+        for (owner, ops) in shares {
+            let versions = versions_of(&ops);
+            self.send(owner, TxnPayload::Prepare { txn, ops, versions }, output)?;
+        }
+        if let Some(ops) = local {
+            let versions = versions_of(&ops);
+            self.prepare((txn, self.node.clone()), ops, versions, output)?;
+        }
+        Ok(())
+    }
+
+    //owner: validate (occ) and start taking the txn's locks
+    fn prepare(
+        &mut self,
+        id: TxnId,
+        ops: Vec<(usize, MicroOp<usize>)>,
+        versions: Vec<(usize, usize)>,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        //occ: something the txn read has been written since
+        let stale = versions
+            .iter()
+            .any(|(key, version)| self.versions.get(key).copied().unwrap_or(0) != *version);
+        if stale {
+            return self.send_vote(id, None, output);
+        }
+        let mut modes: HashMap<usize, LockMode> = HashMap::new();
+        for (_, op) in &ops {
+            let mode = match op {
+                MicroOp::Read(..) => LockMode::Shared,
+                _ => LockMode::Exclusive,
+            };
+            let held = modes.entry(op.key()).or_insert(mode);
+            if mode == LockMode::Exclusive {
+                *held = mode;
+            }
+        }
+        let mut left: Vec<(usize, LockMode)> = modes.into_iter().collect();
+        left.sort_by_key(|(key, _)| *key);
+        let acquiring = Acquiring {
+            ops,
+            left: left.into(),
+            since: Instant::now(),
+        };
+        self.acquiring.insert(id.clone(), acquiring);
+        self.lock_next(id, output)
+    }
+
+    //owner: take a txn's locks until one has to be waited for, vote once it has them all
+    fn lock_next(&mut self, id: TxnId, output: &mut impl Write) -> anyhow::Result<()> {
+        loop {
+            let Some(acquiring) = self.acquiring.get_mut(&id) else {
+                return Ok(());
+            };
+            let Some((key, mode)) = acquiring.left.front().copied() else {
+                break;
+            };
+            match self.locks.acquire(&id, key, mode) {
+                Acquire::Granted => {
+                    acquiring.left.pop_front();
+                }
+                //occ never waits
+                Acquire::Waiting | Acquire::Wound(_) if self.control == Control::Optimistic => {
+                    return self.give_up(&id, output);
+                }
+                Acquire::Waiting => return Ok(()),
+                Acquire::Die => return self.give_up(&id, output),
+                Acquire::Wound(younger) => {
+                    //only txns that haven't voted yet can still be aborted here
+                    for victim in younger {
+                        if self.acquiring.contains_key(&victim) {
+                            self.give_up(&victim, output)?;
+                        }
+                    }
+                    return Ok(());
+                }
+            }
+        }
+
+        let acquiring = self.acquiring.remove(&id).expect("acquiring just seen");
+        //reads see the txn's own earlier writes
+        let mut writes: HashMap<usize, usize> = HashMap::new();
+        let mut reads = Vec::new();
+        for (index, op) in acquiring.ops {
+            match op {
+                MicroOp::Read(key, _) => {
+                    let value = writes.get(&key).or_else(|| self.store.get(&key));
+                    reads.push((index, value.copied()));
+                }
+                MicroOp::Write(key, value) => {
+                    writes.insert(key, value);
+                }
+                MicroOp::Append(..) => {}
+            }
+        }
+        let prepared = Prepared {
+            writes: writes.into_iter().collect(),
+            since: Instant::now(),
+            settling: false,
+        };
+        self.prepared.insert(id.clone(), prepared);
+        self.send_vote(id, Some(reads), output)
+    }
+
+    //owner: vote no on a txn still taking its locks and let go of the ones it has
+    fn give_up(&mut self, id: &TxnId, output: &mut impl Write) -> anyhow::Result<()> {
+        if self.acquiring.remove(id).is_none() {
+            return Ok(());
+        }
+        self.release(id, output)?;
+        self.send_vote(id.clone(), None, output)
+    }
+
+    fn send_vote(
+        &mut self,
+        (txn, coordinator): TxnId,
+        vote: Option<Vec<(usize, Option<usize>)>>,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        if coordinator == self.node {
+            let node = self.node.clone();
+            return self.vote(txn, node, vote, output);
+        }
+        let payload = match vote {
+            Some(reads) => TxnPayload::PrepareOk { txn, reads },
+            None => TxnPayload::PrepareFailed { txn },
+        };
+        self.send(coordinator, payload, output)
+    }
+
+    //owner: drop a txn's locks and carry on with the prepares that were waiting for them
+    fn release(&mut self, id: &TxnId, output: &mut impl Write) -> anyhow::Result<()> {
+        for (waiter, key) in self.locks.release_all(id) {
+            let Some(acquiring) = self.acquiring.get_mut(&waiter) else {
+                continue;
+            };
+            if acquiring.left.front().map(|(next, _)| *next) == Some(key) {
+                acquiring.left.pop_front();
+                self.lock_next(waiter, output)?;
+            }
+        }
+        Ok(())
+    }
+
+    //owner: apply or drop a txn and let go of its locks
+    fn finish(&mut self, id: &TxnId, commit: bool, output: &mut impl Write) -> anyhow::Result<()> {
+        //aborted while still waiting for locks
+        if self.acquiring.remove(id).is_some() {
+            return self.release(id, output);
+        }
+        let Some(prepared) = self.prepared.remove(id) else {
+            return Ok(());
+        };
+        if commit {
+            for (key, value) in prepared.writes {
+                self.store.insert(key, value);
+                *self.versions.entry(key).or_default() += 1;
+            }
+        }
+        self.release(id, output)
+    }
+
+    //coordinator: one owner's vote is in
+    fn vote(
+        &mut self,
+        txn: usize,
+        from: String,
+        vote: Option<Vec<(usize, Option<usize>)>>,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        //aborted already (a commit needs every vote, so it can't be that). a yes
+        //that comes this late still holds locks, tell it
+        let Some(coordinating) = self.txns.get_mut(&txn) else {
+            if vote.is_some() {
+                self.decide(txn, false, vec![from], output)?;
+            }
+            return Ok(());
+        };
+        if coordinating.committing || !coordinating.participants.contains(&from) {
+            return Ok(());
+        }
+        let Some(reads) = vote else {
+            let reason = match self.control {
+                Control::Locking => "a key is locked by an older txn",
+                Control::Optimistic => "a key it read has changed or is being committed",
+            };
+            return self.abort(txn, reason, output);
+        };
+        for (index, value) in reads {
+            if let Some(MicroOp::Read(_, read)) = coordinating.ops.get_mut(index) {
+                *read = value;
+            }
+        }
+        coordinating.waiting -= 1;
+        if coordinating.waiting > 0 {
+            return Ok(());
+        }
+        //everyone said yes: commit once it's recorded
+        coordinating.committing = true;
+        let key = decision_key(&(txn, self.node.clone()));
+        let msg_id = self
+            .kv
+            .cas(key, UNDECIDED, "commit", true, &mut self.id, &mut *output)?;
+        self.calls.insert(msg_id, Call::Commit(txn));
+        Ok(())
+    }
+
+    //coordinator: tell the owners, then the client. nothing recorded a commit,
+    //so an owner settling it on its own reaches the same outcome
+    fn abort(&mut self, txn: usize, reason: &str, output: &mut impl Write) -> anyhow::Result<()> {
+        let Some(coordinating) = self.txns.remove(&txn) else {
+            return Ok(());
+        };
+        self.aborted += 1;
+        self.decide(txn, false, coordinating.participants, output)?;
+        let error = TxnPayload::Error {
+            code: ErrorCode::TxnConflict.code(),
+            text: format!("txn aborted: {reason}"),
+        };
+        self.reply(coordinating.request, error, output)
+    }
+
+    fn decide(
+        &mut self,
+        txn: usize,
+        commit: bool,
+        owners: Vec<String>,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        for owner in owners {
+            if owner == self.node {
+                self.finish(&(txn, self.node.clone()), commit, output)?;
+            } else {
+                self.send(owner, TxnPayload::Decide { txn, commit }, output)?;
+            }
+        }
+        Ok(())
+    }
+
+    //a lin-kv reply, for a decision being recorded or looked up
+    fn kv_reply(
+        &mut self,
+        in_reply_to: Option<usize>,
+        payload: KvPayload,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        let Some(call) = in_reply_to.and_then(|id| self.calls.remove(&id)) else {
+            return Ok(());
+        };
+        let Some(result) = payload.into_result() else {
+            return Ok(());
+        };
+        match (call, result) {
+            (Call::Commit(txn), Ok(_)) => {
+                let Some(coordinating) = self.txns.remove(&txn) else {
+                    return Ok(());
+                };
+                self.committed += 1;
+                self.decide(txn, true, coordinating.participants, output)?;
+                let txn = coordinating.ops;
+                self.reply(coordinating.request, TxnPayload::TxnOk { txn }, output)?;
+            }
+            //an owner in doubt recorded abort first
+            (Call::Commit(txn), Err(ErrorCode::PreconditionFailed)) => {
+                self.abort(txn, "an owner gave up waiting for the outcome", output)?;
+            }
+            //outcome unknown, lin-kv may or may not have it. leave it to the owners
+            (Call::Commit(txn), Err(_)) => {
+                if let Some(coordinating) = self.txns.remove(&txn) {
+                    let error = TxnPayload::Error {
+                        code: ErrorCode::Crash.code(),
+                        text: "couldn't record the commit, outcome unknown".to_string(),
+                    };
+                    self.reply(coordinating.request, error, output)?;
+                }
+            }
+
+            (Call::Abort(id), Ok(_)) => self.finish(&id, false, output)?,
+            //something is recorded already, find out what
+            (Call::Abort(id), Err(ErrorCode::PreconditionFailed)) => {
+                let msg_id = self
+                    .kv
+                    .read(decision_key(&id), &mut self.id, &mut *output)?;
+                self.calls.insert(msg_id, Call::Lookup(id));
+            }
+            (Call::Lookup(id), Ok(Value::String(outcome))) => {
+                self.finish(&id, outcome == "commit", output)?;
+            }
+            //try again next tick
+            (Call::Abort(id) | Call::Lookup(id), _) => {
+                if let Some(prepared) = self.prepared.get_mut(&id) {
+                    prepared.settling = false;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn abort_rate(&self) -> f64 {
+        match self.committed + self.aborted {
+            0 => 0.0,
+            total => self.aborted as f64 / total as f64,
+        }
+    }
+
+    fn tick(&mut self, output: &mut impl Write) -> anyhow::Result<()> {
+        let expired: Vec<usize> = self
+            .txns
+            .iter()
+            .filter(|(_, c)| !c.committing && c.started.elapsed() > PREPARE_TIMEOUT)
+            .map(|(txn, _)| *txn)
+            .collect();
+        for txn in expired {
+            self.abort(txn, "timed out waiting for votes", output)?;
+        }
+
+        let stuck: Vec<TxnId> = self
+            .acquiring
+            .iter()
+            .filter(|(_, a)| a.since.elapsed() > PREPARE_TIMEOUT)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in stuck {
+            self.give_up(&id, output)?;
+        }
+        let in_doubt: Vec<TxnId> = self
+            .prepared
+            .iter()
+            .filter(|(_, p)| !p.settling && p.since.elapsed() > IN_DOUBT)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in in_doubt {
+            if let Some(prepared) = self.prepared.get_mut(&id) {
+                prepared.settling = true;
+            }
+            let key = decision_key(&id);
+            let msg_id = self
+                .kv
+                .cas(key, UNDECIDED, "abort", true, &mut self.id, &mut *output)?;
+            self.calls.insert(msg_id, Call::Abort(id));
+        }
+        Ok(())
+    }
+
+    fn handle(
+        &mut self,
+        input: Event<Payload, InjectedPayload>,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        let input = match input {
+            Event::Injected(InjectedPayload::Tick) => return self.tick(output),
+            //txn_stats reports the outcomes
+            Event::EOF => return Ok(()),
+            Event::Message(input) => input,
+        };
+        let (payload, request) = input.into_parts();
+        let payload = match payload {
+            Payload::Kv(payload) => {
+                return self.kv_reply(request.body.in_reply_to, payload, output);
+            }
+            Payload::Txn(payload) => payload,
+        };
+        match payload {
+            TxnPayload::Txn {
+                txn: Lenient::Invalid { error, .. },
+            } => {
+                let error = TxnPayload::Error {
+                    code: ErrorCode::MalformedRequest.code(),
+                    text: format!("invalid txn: {error}"),
+                };
+                self.reply(request, error, output)?;
+            }
+            TxnPayload::Txn {
+                txn: Lenient::Valid(txn),
+            } => {
+                if let Some(op) = txn.iter().find(|op| matches!(op, MicroOp::Append(..))) {
+                    let error = TxnPayload::Error {
+                        code: ErrorCode::NotSupported.code(),
+                        text: format!("append on key {} in a rw-register txn", op.key()),
+                    };
+                    return self.reply(request, error, output);
+                }
+                if txn.is_empty() {
+                    return self.reply(request, TxnPayload::TxnOk { txn }, output);
+                }
+                self.begin(request, txn, output)?;
+            }
+
+            TxnPayload::Fetch { txn, keys } => {
+                let values = self.fetch(keys);
+                self.reply(request, TxnPayload::FetchOk { txn, values }, output)?;
+            }
+            TxnPayload::FetchOk { txn, values } => self.fetched(txn, values, output)?,
+
+            TxnPayload::Prepare { txn, ops, versions } => {
+                self.prepare((txn, request.src), ops, versions, output)?;
+            }
+            TxnPayload::PrepareOk { txn, reads } => {
+                self.vote(txn, request.src, Some(reads), output)?;
+            }
+            TxnPayload::PrepareFailed { txn } => self.vote(txn, request.src, None, output)?,
+            TxnPayload::Decide { txn, commit } => {
+                self.finish(&(txn, request.src), commit, output)?;
+            }
+
+            TxnPayload::TxnStats => {
+                let stats = TxnPayload::TxnStatsOk {
+                    control: self.control.name().to_string(),
+                    committed: self.committed,
+                    aborted: self.aborted,
+                    abort_rate: self.abort_rate(),
+                    deadlock: self.locks.find_deadlock(),
+                };
+                self.reply(request, stats, output)?;
+            }
+
+            //replies for clients, nodes don't get them
+            TxnPayload::TxnOk { .. } | TxnPayload::Error { .. } => {}
+            TxnPayload::TxnStatsOk { .. } => {}
+        }
+        Ok(())
+    }
+}
+
+fn now_micros() -> usize {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |since| since.as_micros() as usize)
+}
+
+impl Node<(), Payload, InjectedPayload> for TwoPcNode {
+    fn from_init(
+        _state: (),
+        init: Init,
+        tx: std::sync::mpsc::Sender<Event<Payload, InjectedPayload>>,
+    ) -> anyhow::Result<Self>
+    where
+        Self: Sized,
+    {
+        std::thread::spawn(move || loop {
+            std::thread::sleep(Duration::from_millis(100));
+            if tx.send(Event::Injected(InjectedPayload::Tick)).is_err() {
+                break;
+            }
+        });
+
+        let policy = match std::env::var("TXN_DEADLOCK").as_deref() {
+            Ok("wound-wait") => Policy::WoundWait,
+            _ => Policy::WaitDie,
+        };
+        Ok(Self::new(init, Control::from_env(), policy))
+    }
+
+    fn handle_input(
+        &mut self,
+        input: Event<Payload, InjectedPayload>,
+        output: &mut std::io::StdoutLock,
+    ) -> anyhow::Result<()> {
+        self.handle(input, output)
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    main_loop::<_, TwoPcNode, _, _>(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    //one node of the cluster n0, n1, fed messages by hand, with what it sent since the last look
+    struct Harness {
+        node: TwoPcNode,
+        sent: Vec<Message<Value>>,
+        next_id: usize,
+    }
+
+    impl Harness {
+        fn new(name: &str) -> Self {
+            let init = Init {
+                node_id: name.to_string(),
+                node_ids: vec!["n0".to_string(), "n1".to_string()],
+            };
+            Self {
+                node: TwoPcNode::new(init, Control::Locking, Policy::WaitDie),
+                sent: Vec::new(),
+                next_id: 1000,
+            }
+        }
+
+        fn handle(&mut self, event: Event<Payload, InjectedPayload>) {
+            let mut output = Vec::new();
+            self.node.handle(event, &mut output).unwrap();
+            for line in output.split(|b| *b == b'\n').filter(|l| !l.is_empty()) {
+                self.sent.push(serde_json::from_slice(line).unwrap());
+            }
+        }
+
+        //a message as it would come off the wire
+        fn deliver(&mut self, src: &str, in_reply_to: Option<usize>, payload: Value) {
+            self.next_id += 1;
+            let mut body = json!({"msg_id": self.next_id, "in_reply_to": in_reply_to});
+            body.as_object_mut()
+                .unwrap()
+                .extend(payload.as_object().unwrap().clone());
+            let message = json!({"src": src, "dest": self.node.node, "body": body});
+            let message = serde_json::from_value(message).unwrap();
+            self.handle(Event::Message(message));
+        }
+
+        //what went to `dest` since the last look, with everything else
+        fn take(&mut self, dest: &str) -> Vec<Message<Value>> {
+            let (to, rest) = std::mem::take(&mut self.sent)
+                .into_iter()
+                .partition(|message| message.dest == dest);
+            self.sent = rest;
+            to
+        }
+
+        //the one request in flight to lin-kv, answered with `reply`
+        fn kv_reply(&mut self, reply: Value) -> Value {
+            let [request] = &self.take(LIN_KV)[..] else {
+                panic!("expected one lin-kv request");
+            };
+            self.deliver(LIN_KV, request.body.id, reply);
+            request.body.payload.clone()
+        }
+
+        //every timer has run out, then the tick runs
+        fn expire(&mut self) {
+            let long_ago = Instant::now().checked_sub(IN_DOUBT * 2).unwrap();
+            for prepared in self.node.prepared.values_mut() {
+                prepared.since = long_ago;
+            }
+            for coordinating in self.node.txns.values_mut() {
+                coordinating.started = long_ago;
+            }
+            self.handle(Event::Injected(InjectedPayload::Tick));
+        }
+
+        //nothing holds the key: even the youngest txn gets it without dying
+        fn released(&mut self, key: usize) -> bool {
+            let youngest = (usize::MAX, String::new());
+            let granted = self.node.locks.acquire(&youngest, key, LockMode::Exclusive);
+            self.node.locks.release_all(&youngest);
+            granted == Acquire::Granted
+        }
+
+        fn key_of(&self, owner: &str) -> usize {
+            (0..).find(|key| self.node.owner(*key) == owner).unwrap()
+        }
+    }
+
+    fn kind(message: &Message<Value>) -> &str {
+        message.body.payload["type"].as_str().unwrap()
+    }
+
+    //n1 owns a key and has voted yes on txn 7 from n0, which writes 5 to it
+    fn prepared_owner() -> (Harness, usize) {
+        let mut owner = Harness::new("n1");
+        let key = owner.key_of("n1");
+        let prepare = json!({"type": "prepare", "txn": 7, "ops": [[0, ["w", key, 5]]]});
+        owner.deliver("n0", None, prepare);
+        let votes = owner.take("n0");
+        assert_eq!(kind(&votes[0]), "prepare_ok");
+        //the coordinator is never heard from again
+        owner.expire();
+        (owner, key)
+    }
+
+    #[test]
+    fn in_doubt_abort_losing_to_a_recorded_commit_commits() {
+        let (mut owner, key) = prepared_owner();
+        let cas = owner.kv_reply(json!({"type": "error", "code": 22, "text": ""}));
+        assert_eq!(cas["key"], "2pc/n0/7");
+        assert_eq!(cas["to"], "abort");
+        let lookup = owner.kv_reply(json!({"type": "read_ok", "value": "commit"}));
+        assert_eq!(lookup["type"], "read");
+        assert_eq!(owner.node.store.get(&key), Some(&5));
+        assert!(owner.node.prepared.is_empty());
+        assert!(owner.released(key));
+    }
+
+    #[test]
+    fn in_doubt_abort_that_lands_first_aborts() {
+        let (mut owner, key) = prepared_owner();
+        owner.kv_reply(json!({"type": "cas_ok"}));
+        assert_eq!(owner.node.store.get(&key), None);
+        assert!(owner.node.prepared.is_empty());
+        assert!(owner.released(key));
+    }
+
+    #[test]
+    fn recorded_abort_is_looked_up_and_applied() {
+        let (mut owner, key) = prepared_owner();
+        owner.kv_reply(json!({"type": "error", "code": 22, "text": ""}));
+        owner.kv_reply(json!({"type": "read_ok", "value": "abort"}));
+        assert_eq!(owner.node.store.get(&key), None);
+        assert!(owner.node.prepared.is_empty());
+    }
+
+    #[test]
+    fn failed_settle_is_tried_again_on_a_later_tick() {
+        let (mut owner, key) = prepared_owner();
+        owner.kv_reply(json!({"type": "error", "code": 0, "text": ""}));
+        assert!(!owner.node.prepared[&(7, "n0".to_string())].settling);
+        owner.expire();
+        owner.kv_reply(json!({"type": "error", "code": 22, "text": ""}));
+        //a lookup that fails is retried the same way
+        owner.kv_reply(json!({"type": "error", "code": 11, "text": ""}));
+        owner.expire();
+        owner.kv_reply(json!({"type": "error", "code": 22, "text": ""}));
+        owner.kv_reply(json!({"type": "read_ok", "value": "commit"}));
+        assert_eq!(owner.node.store.get(&key), Some(&5));
+    }
+
+    //n0 coordinates a txn writing to a key only n1 owns, returns the txn number
+    fn coordinate(coordinator: &mut Harness) -> usize {
+        let key = coordinator.key_of("n1");
+        coordinator.deliver("c1", None, json!({"type": "txn", "txn": [["w", key, 1]]}));
+        let prepares = coordinator.take("n1");
+        assert_eq!(kind(&prepares[0]), "prepare");
+        prepares[0].body.payload["txn"].as_u64().unwrap() as usize
+    }
+
+    fn decided(coordinator: &mut Harness, txn: usize) -> Vec<bool> {
+        coordinator
+            .take("n1")
+            .iter()
+            .filter(|message| kind(message) == "decide")
+            .inspect(|message| assert_eq!(message.body.payload["txn"], txn))
+            .map(|message| message.body.payload["commit"].as_bool().unwrap())
+            .collect()
+    }
+
+    fn client_reply(coordinator: &mut Harness) -> Value {
+        let [reply] = &coordinator.take("c1")[..] else {
+            panic!("expected one reply to the client");
+        };
+        reply.body.payload.clone()
+    }
+
+    #[test]
+    fn recorded_commit_is_sent_to_owners_and_client() {
+        let mut coordinator = Harness::new("n0");
+        let txn = coordinate(&mut coordinator);
+        coordinator.deliver(
+            "n1",
+            None,
+            json!({"type": "prepare_ok", "txn": txn, "reads": []}),
+        );
+        let cas = coordinator.kv_reply(json!({"type": "cas_ok"}));
+        assert_eq!(cas["key"], format!("2pc/n0/{txn}"));
+        assert_eq!(cas["to"], "commit");
+        assert_eq!(decided(&mut coordinator, txn), [true]);
+        assert_eq!(client_reply(&mut coordinator)["type"], "txn_ok");
+        assert_eq!(coordinator.node.committed, 1);
+    }
+
+    #[test]
+    fn commit_losing_to_an_owners_abort_aborts() {
+        let mut coordinator = Harness::new("n0");
+        let txn = coordinate(&mut coordinator);
+        coordinator.deliver(
+            "n1",
+            None,
+            json!({"type": "prepare_ok", "txn": txn, "reads": []}),
+        );
+        coordinator.kv_reply(json!({"type": "error", "code": 22, "text": ""}));
+        assert_eq!(decided(&mut coordinator, txn), [false]);
+        let reply = client_reply(&mut coordinator);
+        assert_eq!(reply["code"], ErrorCode::TxnConflict.code());
+        assert_eq!(coordinator.node.aborted, 1);
+    }
+
+    #[test]
+    fn commit_with_unknown_outcome_is_left_to_the_owners() {
+        let mut coordinator = Harness::new("n0");
+        let txn = coordinate(&mut coordinator);
+        coordinator.deliver(
+            "n1",
+            None,
+            json!({"type": "prepare_ok", "txn": txn, "reads": []}),
+        );
+        coordinator.kv_reply(json!({"type": "error", "code": 0, "text": ""}));
+        assert!(decided(&mut coordinator, txn).is_empty());
+        let reply = client_reply(&mut coordinator);
+        assert_eq!(reply["code"], ErrorCode::Crash.code());
+        assert!(coordinator.node.txns.is_empty());
+    }
+
+    #[test]
+    fn late_yes_after_a_timeout_abort_is_told_to_abort() {
+        let mut coordinator = Harness::new("n0");
+        let txn = coordinate(&mut coordinator);
+        coordinator.expire();
+        assert_eq!(decided(&mut coordinator, txn), [false]);
+        assert_eq!(
+            client_reply(&mut coordinator)["code"],
+            ErrorCode::TxnConflict.code()
+        );
+        //the owner's yes arrives after all, it still holds locks
+        coordinator.deliver(
+            "n1",
+            None,
+            json!({"type": "prepare_ok", "txn": txn, "reads": []}),
+        );
+        assert_eq!(decided(&mut coordinator, txn), [false]);
+        assert!(coordinator.take(LIN_KV).is_empty());
+        //a late no needs nothing
+        coordinator.deliver("n1", None, json!({"type": "prepare_failed", "txn": txn}));
+        assert!(coordinator.sent.is_empty());
+    }
+}
@@ -0,0 +1,117 @@
+/*
+    txn-list-append workload: every key holds a list, a txn appends to lists
+    and reads whole ones. each txn is one command in the replicated log and is
+    applied in one go, so txns run one after another in log order on every
+    node (serializable, strict too since reads go through the log as well).
+    served by `replication::ClientNode`, like lin-kv: followers forward txns
+    to the leader and answer once they're applied locally.
+    engine is raft by default, TXN_ENGINE=paxos switches to multi-paxos
+*/
+
+use ds_challenge::{
+    paxos::Paxos,
+    raft::Raft,
+    replication::{ClientNode, Served, Service, StateMachine},
+    txn::{Lenient, MicroOp},
+    *,
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum TxnPayload {
+    Txn {
+        txn: Lenient<Vec<MicroOp<Vec<usize>>>>,
+    },
+    TxnOk {
+        txn: Vec<MicroOp<Vec<usize>>>,
+    },
+    Error {
+        code: usize,
+        text: String,
+    },
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ListStore {
+    lists: HashMap<usize, Vec<usize>>,
+}
+
+impl StateMachine for ListStore {
+    type Command = Vec<MicroOp<Vec<usize>>>;
+    type Output = Vec<MicroOp<Vec<usize>>>;
+
+    fn apply(&mut self, txn: Self::Command) -> Self::Output {
+        txn.into_iter()
+            .map(|op| match op {
+                MicroOp::Read(key, _) => MicroOp::Read(key, self.lists.get(&key).cloned()),
+                MicroOp::Append(key, value) => {
+                    self.lists.entry(key).or_default().push(value);
+                    op
+                }
+                //rejected before it reaches the log
+                MicroOp::Write(..) => op,
+            })
+            .collect()
+    }
+
+    fn snapshot(&self) -> anyhow::Result<String> {
+        serde_json::to_string(self).context("serialize list snapshot")
+    }
+
+    fn restore(&mut self, snapshot: &str) -> anyhow::Result<()> {
+        *self = serde_json::from_str(snapshot).context("deserialize list snapshot")?;
+        Ok(())
+    }
+}
+
+impl Service for ListStore {
+    type Payload = TxnPayload;
+
+    fn request(payload: TxnPayload) -> Option<Result<Self::Command, TxnPayload>> {
+        let txn = match payload {
+            TxnPayload::Txn {
+                txn: Lenient::Valid(txn),
+            } => txn,
+            TxnPayload::Txn {
+                txn: Lenient::Invalid { error, .. },
+            } => {
+                return Some(Err(Self::error(
+                    ErrorCode::MalformedRequest,
+                    format!("invalid txn: {error}"),
+                )))
+            }
+            TxnPayload::TxnOk { .. } | TxnPayload::Error { .. } => return None,
+        };
+
+        if let Some(op) = txn.iter().find(|op| matches!(op, MicroOp::Write(..))) {
+            return Some(Err(Self::error(
+                ErrorCode::NotSupported,
+                format!("write on key {} in a list-append txn", op.key()),
+            )));
+        }
+        Some(Ok(txn))
+    }
+
+    fn reply(txn: Self::Output) -> TxnPayload {
+        TxnPayload::TxnOk { txn }
+    }
+
+    fn error(code: ErrorCode, text: String) -> TxnPayload {
+        TxnPayload::Error {
+            code: code.code(),
+            text,
+        }
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    match std::env::var("TXN_ENGINE").as_deref() {
+        Ok("paxos") => main_loop::<_, ClientNode<ListStore, Paxos<Served<ListStore>>>, _, _>(()),
+        _ => main_loop::<_, ClientNode<ListStore, Raft<Served<ListStore>>>, _, _>(()),
+    }
+}
@@ -56,7 +56,7 @@ impl Node<(), Payload> for UniqueNode {
 
         match response.body.payload {
             //generate a unique id for a message
-            Payload::Generate {} => {
+            Payload::Generate => {
                 //{producer(node)-name/id}-{message_id} should always be unique
                 //assumes nodes do not reuse node-ids on restart
                 response.body.payload = Payload::GenerateOk {
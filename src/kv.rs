@@ -0,0 +1,133 @@
+/*
+    Client for maelstrom's built-in key-value services (lin-kv, seq-kv, lww-kv).
+    Requests go out as ordinary messages, the node routes the replies back
+    here by `in_reply_to` to find out which request they answer.
+*/
+
+use std::io::Write;
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{ErrorCode, Message};
+
+pub const LIN_KV: &str = "lin-kv";
+pub const SEQ_KV: &str = "seq-kv";
+pub const LWW_KV: &str = "lww-kv";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum KvPayload {
+    Read {
+        key: String,
+    },
+    ReadOk {
+        value: Value,
+    },
+    Write {
+        key: String,
+        value: Value,
+    },
+    WriteOk,
+    Cas {
+        key: String,
+        from: Value,
+        to: Value,
+        #[serde(default)]
+        create_if_not_exists: bool,
+    },
+    CasOk,
+    Error {
+        code: usize,
+        text: String,
+    },
+}
+
+//outcome of a kv request: the value for reads, Null for writes and cas
+pub type KvResult = Result<Value, ErrorCode>;
+
+impl KvPayload {
+    //None for request payloads, which a node never expects back from a service
+    pub fn into_result(self) -> Option<KvResult> {
+        match self {
+            KvPayload::ReadOk { value } => Some(Ok(value)),
+            KvPayload::WriteOk | KvPayload::CasOk => Some(Ok(Value::Null)),
+            KvPayload::Error { code, .. } => {
+                Some(Err(ErrorCode::from_code(code).unwrap_or(ErrorCode::Crash)))
+            }
+            KvPayload::Read { .. } | KvPayload::Write { .. } | KvPayload::Cas { .. } => None,
+        }
+    }
+}
+
+//sends requests to one kv service, each call returns the msg_id its reply will point at
+pub struct KvClient {
+    node: String,
+    service: String,
+}
+
+impl KvClient {
+    pub fn new(node: String, service: &str) -> Self {
+        Self {
+            node,
+            service: service.to_string(),
+        }
+    }
+
+    pub fn service(&self) -> &str {
+        &self.service
+    }
+
+    pub fn read(
+        &self,
+        key: String,
+        id: &mut usize,
+        output: &mut impl Write,
+    ) -> anyhow::Result<usize> {
+        self.send(KvPayload::Read { key }, id, output)
+    }
+
+    pub fn write(
+        &self,
+        key: String,
+        value: impl Into<Value>,
+        id: &mut usize,
+        output: &mut impl Write,
+    ) -> anyhow::Result<usize> {
+        let value = value.into();
+        self.send(KvPayload::Write { key, value }, id, output)
+    }
+
+    pub fn cas(
+        &self,
+        key: String,
+        from: impl Into<Value>,
+        to: impl Into<Value>,
+        create_if_not_exists: bool,
+        id: &mut usize,
+        output: &mut impl Write,
+    ) -> anyhow::Result<usize> {
+        let payload = KvPayload::Cas {
+            key,
+            from: from.into(),
+            to: to.into(),
+            create_if_not_exists,
+        };
+        self.send(payload, id, output)
+    }
+
+    fn send(
+        &self,
+        payload: KvPayload,
+        id: &mut usize,
+        output: &mut impl Write,
+    ) -> anyhow::Result<usize> {
+        let msg_id = *id;
+        Message::new(self.node.clone(), self.service.clone(), id, payload)
+            .send_self(&mut *output)
+            .context(format!("request to {}", self.service))?;
+        Ok(msg_id)
+    }
+}
@@ -0,0 +1,148 @@
+/*
+    Who leads a kafka key and which of its replicas are in sync with the leader.
+    It's kept in lin-kv under leadership-<key> and only changed there by cas,
+    every new leader bumps the epoch so two replicas can't both take over the
+    same one. A key nobody has changed it for isn't in lin-kv at all: its first
+    replica leads with every replica in sync.
+*/
+
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::membership::{MemberState, Membership};
+
+//a leadership change that got no answer, or lost a race, is tried again after this
+pub const LEADERSHIP_RETRY: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Leadership {
+    pub epoch: usize,
+    pub leader: String,
+    //replica order, the leader included
+    pub isr: Vec<String>,
+}
+
+impl Leadership {
+    //what a key starts out with until lin-kv says otherwise
+    pub fn initial(replicas: Vec<String>) -> Self {
+        Self {
+            epoch: 0,
+            leader: replicas[0].clone(),
+            isr: replicas,
+        }
+    }
+
+    //the next epoch, led by `leader`. the current leader leaves the isr, it's down
+    //or missing records
+    pub fn handed_to(&self, leader: String) -> Self {
+        Self {
+            epoch: self.epoch + 1,
+            leader,
+            isr: self
+                .isr
+                .iter()
+                .filter(|node| **node != self.leader)
+                .cloned()
+                .collect(),
+        }
+    }
+
+    //whether `node`, holding `current`, takes this one on. a newer epoch always does.
+    //within an epoch lin-kv has the last word and a follower believes its leader, but
+    //a leader only changes its own isr through lin-kv
+    pub fn replaces(&self, current: &Leadership, node: &str, from_kv: bool) -> bool {
+        let leading = current.leader == node;
+        let newer =
+            self.epoch > current.epoch || (self.epoch == current.epoch && (from_kv || !leading));
+        newer && self != current
+    }
+}
+
+pub fn leadership_key(key: &str) -> String {
+    format!("leadership-{key}")
+}
+
+//alive or only suspected, a suspect may still answer
+pub fn is_live(membership: &Membership, node: &str) -> bool {
+    matches!(
+        membership.state_of(node),
+        Some(MemberState::Alive | MemberState::Suspect)
+    )
+}
+
+//a leadership change in flight in lin-kv
+#[derive(Debug)]
+pub struct Change {
+    //lin-kv msg_id of the cas or re-read we're waiting on, later answers are ignored
+    pub call: Option<usize>,
+    pub started: Instant,
+}
+
+impl Change {
+    pub fn new(call: usize) -> Self {
+        Self {
+            call: Some(call),
+            started: Instant::now(),
+        }
+    }
+}
+
+//a key we can propose a leadership change for: none in flight, none failed just now
+pub fn can_propose(change: Option<&Change>) -> bool {
+    change.is_none_or(|change| change.started.elapsed() > LEADERSHIP_RETRY)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leadership(epoch: usize, leader: &str, isr: &[&str]) -> Leadership {
+        Leadership {
+            epoch,
+            leader: leader.to_string(),
+            isr: isr.iter().map(|node| node.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn handing_over_bumps_the_epoch_and_drops_the_old_leader() {
+        let current = leadership(3, "n0", &["n0", "n1", "n2"]);
+        assert_eq!(
+            current.handed_to("n2".to_string()),
+            leadership(4, "n2", &["n1", "n2"])
+        );
+    }
+
+    #[test]
+    fn newer_epoch_replaces_whatever_is_held() {
+        let current = leadership(3, "n0", &["n0", "n1"]);
+        let newer = leadership(4, "n1", &["n1"]);
+        assert!(newer.replaces(&current, "n0", false));
+        assert!(newer.replaces(&current, "n1", false));
+        assert!(!current.replaces(&newer, "n2", true));
+    }
+
+    #[test]
+    fn leader_takes_its_own_isr_changes_only_from_lin_kv() {
+        let current = leadership(3, "n0", &["n0", "n1", "n2"]);
+        let shrunk = leadership(3, "n0", &["n0", "n1"]);
+        assert!(!shrunk.replaces(&current, "n0", false));
+        assert!(shrunk.replaces(&current, "n0", true));
+        //a follower hears it from the leader
+        assert!(shrunk.replaces(&current, "n2", false));
+        assert!(!current.replaces(&current, "n2", true));
+    }
+
+    #[test]
+    fn changes_are_held_back_until_the_last_one_is_old() {
+        assert!(can_propose(None));
+        let change = Change::new(7);
+        assert!(!can_propose(Some(&change)));
+        let stale = Change {
+            call: None,
+            started: Instant::now() - LEADERSHIP_RETRY * 2,
+        };
+        assert!(can_propose(Some(&stale)));
+    }
+}
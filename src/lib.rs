@@ -6,6 +6,21 @@ use std::{
 use anyhow::Context;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
+pub mod kv;
+pub mod leadership;
+pub mod locks;
+pub mod log_partition;
+pub mod log_replication;
+pub mod membership;
+pub mod mvcc;
+pub mod partition;
+pub mod paxos;
+pub mod raft;
+pub mod records;
+pub mod replication;
+pub mod segment;
+pub mod txn;
+
 //basic skeleton of a network message
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Message<Payload> {
@@ -15,6 +30,21 @@ pub struct Message<Payload> {
 }
 
 impl<Payload> Message<Payload> {
+    //build a new (non-reply) message, taking its id from the node's counter
+    pub fn new(src: String, dest: String, id: &mut usize, payload: Payload) -> Self {
+        let mid = *id;
+        *id += 1;
+        Self {
+            src,
+            dest,
+            body: Body {
+                id: Some(mid),
+                in_reply_to: None,
+                payload,
+            },
+        }
+    }
+
     pub fn derive_response(self, id: Option<&mut usize>) -> Self {
         Self {
             src: self.dest,
@@ -31,6 +61,34 @@ impl<Payload> Message<Payload> {
         }
     }
 
+    //split off the payload so a wrapped protocol message can be matched and handed on
+    pub fn into_parts(self) -> (Payload, Message<()>) {
+        let Self { src, dest, body } = self;
+        let header = Message {
+            src,
+            dest,
+            body: Body {
+                id: body.id,
+                in_reply_to: body.in_reply_to,
+                payload: (),
+            },
+        };
+        (body.payload, header)
+    }
+
+    //swap the payload type, e.g. to hand an unwrapped protocol message to its module
+    pub fn with_payload<P>(self, payload: P) -> Message<P> {
+        Message {
+            src: self.src,
+            dest: self.dest,
+            body: Body {
+                id: self.body.id,
+                in_reply_to: self.body.in_reply_to,
+                payload,
+            },
+        }
+    }
+
     pub fn send_self(&self, output: &mut impl Write) -> anyhow::Result<()>
     where
         Payload: Serialize,
@@ -52,6 +110,54 @@ pub struct Body<Payload> {
     pub payload: Payload,
 }
 
+//maelstrom's standard error codes, sent as {"type": "error", "code": .., "text": ..}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    Timeout,
+    NotSupported,
+    TemporarilyUnavailable,
+    MalformedRequest,
+    Crash,
+    Abort,
+    KeyDoesNotExist,
+    KeyAlreadyExists,
+    PreconditionFailed,
+    TxnConflict,
+}
+
+impl ErrorCode {
+    pub fn code(self) -> usize {
+        match self {
+            ErrorCode::Timeout => 0,
+            ErrorCode::NotSupported => 10,
+            ErrorCode::TemporarilyUnavailable => 11,
+            ErrorCode::MalformedRequest => 12,
+            ErrorCode::Crash => 13,
+            ErrorCode::Abort => 14,
+            ErrorCode::KeyDoesNotExist => 20,
+            ErrorCode::KeyAlreadyExists => 21,
+            ErrorCode::PreconditionFailed => 22,
+            ErrorCode::TxnConflict => 30,
+        }
+    }
+
+    pub fn from_code(code: usize) -> Option<Self> {
+        Some(match code {
+            0 => ErrorCode::Timeout,
+            10 => ErrorCode::NotSupported,
+            11 => ErrorCode::TemporarilyUnavailable,
+            12 => ErrorCode::MalformedRequest,
+            13 => ErrorCode::Crash,
+            14 => ErrorCode::Abort,
+            20 => ErrorCode::KeyDoesNotExist,
+            21 => ErrorCode::KeyAlreadyExists,
+            22 => ErrorCode::PreconditionFailed,
+            30 => ErrorCode::TxnConflict,
+            _ => return None,
+        })
+    }
+}
+
 //handle init message that sends a list of nodes and this current nodes id
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Init {
@@ -150,7 +256,7 @@ where
             // eprintln!("got Message {}", &line);
             let input: Message<P> =
                 serde_json::from_str(&line).context("could not deserialize input line")?;
-            if let Err(_) = tx.send(Event::Message(input)) {
+            if tx.send(Event::Message(input)).is_err() {
                 //channel is closed or some other error
                 return Ok::<_, anyhow::Error>(());
             };
@@ -0,0 +1,315 @@
+/*
+    Lock manager for two-phase locking: shared/exclusive locks per key, with
+    requests that can't be granted queued in arrival order. txns are ordered
+    by age (smaller is older) and a conflict is settled by the policy so no
+    txn ever waits for a younger one (wait-die) or an older one (wound-wait),
+    which rules out deadlocks. the waits-for graph is there for diagnostics.
+*/
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    hash::Hash,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockMode {
+    Shared,
+    Exclusive,
+}
+
+impl LockMode {
+    fn compatible(self, other: LockMode) -> bool {
+        self == LockMode::Shared && other == LockMode::Shared
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    //an older requester waits, a younger one dies
+    WaitDie,
+    //an older requester wounds (aborts) the younger txns in its way, a younger one waits
+    WoundWait,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Acquire<T> {
+    Granted,
+    //queued, granted later by a release
+    Waiting,
+    //not queued: the requester must abort and release what it holds
+    Die,
+    //queued: these younger txns are in the way and must be aborted
+    Wound(Vec<T>),
+}
+
+#[derive(Debug)]
+struct KeyLock<T> {
+    holders: HashMap<T, LockMode>,
+    queue: VecDeque<(T, LockMode)>,
+}
+
+impl<T> Default for KeyLock<T> {
+    fn default() -> Self {
+        Self {
+            holders: HashMap::new(),
+            queue: VecDeque::new(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct LockManager<K, T> {
+    policy: Policy,
+    locks: HashMap<K, KeyLock<T>>,
+    //txn -> keys it holds or is queued on
+    keys: HashMap<T, HashSet<K>>,
+}
+
+impl<K: Clone + Eq + Hash, T: Clone + Ord + Hash> LockManager<K, T> {
+    pub fn new(policy: Policy) -> Self {
+        Self {
+            policy,
+            locks: HashMap::new(),
+            keys: HashMap::new(),
+        }
+    }
+
+    pub fn policy(&self) -> Policy {
+        self.policy
+    }
+
+    pub fn acquire(&mut self, txn: &T, key: K, mode: LockMode) -> Acquire<T> {
+        let lock = self.locks.entry(key.clone()).or_default();
+        let held = lock.holders.get(txn).copied();
+        if held == Some(LockMode::Exclusive) || held == Some(mode) {
+            return Acquire::Granted;
+        }
+        //everyone we'd wait for: other holders we clash with, and whoever queued first
+        let blockers: Vec<T> = lock
+            .holders
+            .iter()
+            .filter(|(holder, held)| *holder != txn && !mode.compatible(**held))
+            .map(|(holder, _)| holder.clone())
+            .chain(lock.queue.iter().map(|(queued, _)| queued.clone()))
+            .filter(|blocker| blocker != txn)
+            .collect();
+        if blockers.is_empty() {
+            lock.holders.insert(txn.clone(), mode);
+            self.keys.entry(txn.clone()).or_default().insert(key);
+            return Acquire::Granted;
+        }
+        let outcome = match self.policy {
+            Policy::WaitDie if blockers.iter().all(|blocker| txn < blocker) => Acquire::Waiting,
+            Policy::WaitDie => return Acquire::Die,
+            Policy::WoundWait => {
+                let younger: Vec<T> = blockers.into_iter().filter(|b| txn < b).collect();
+                if younger.is_empty() {
+                    Acquire::Waiting
+                } else {
+                    Acquire::Wound(younger)
+                }
+            }
+        };
+        lock.queue.push_back((txn.clone(), mode));
+        self.keys.entry(txn.clone()).or_default().insert(key);
+        outcome
+    }
+
+    //drop all of a txn's locks and queued requests, returns the requests granted as a result
+    pub fn release_all(&mut self, txn: &T) -> Vec<(T, K)> {
+        let mut granted = Vec::new();
+        for key in self.keys.remove(txn).unwrap_or_default() {
+            let Some(lock) = self.locks.get_mut(&key) else {
+                continue;
+            };
+            lock.holders.remove(txn);
+            lock.queue.retain(|(queued, _)| queued != txn);
+            //grant from the front of the queue while it fits with who holds the key
+            while let Some((next, mode)) = lock.queue.front() {
+                let fits = lock
+                    .holders
+                    .iter()
+                    .all(|(holder, held)| holder == next || mode.compatible(*held));
+                if !fits {
+                    break;
+                }
+                let (next, mode) = lock.queue.pop_front().expect("front just seen");
+                //an upgrade keeps the stronger mode
+                lock.holders.insert(next.clone(), mode);
+                granted.push((next, key.clone()));
+            }
+            if lock.holders.is_empty() && lock.queue.is_empty() {
+                self.locks.remove(&key);
+            }
+        }
+        granted
+    }
+
+    //queued txn -> the txns it's waiting for
+    pub fn waits_for(&self) -> HashMap<T, HashSet<T>> {
+        let mut graph: HashMap<T, HashSet<T>> = HashMap::new();
+        for lock in self.locks.values() {
+            for (position, (waiter, mode)) in lock.queue.iter().enumerate() {
+                let edges = graph.entry(waiter.clone()).or_default();
+                edges.extend(
+                    lock.holders
+                        .iter()
+                        .filter(|(holder, held)| *holder != waiter && !mode.compatible(**held))
+                        .map(|(holder, _)| holder.clone()),
+                );
+                edges.extend(
+                    lock.queue
+                        .iter()
+                        .take(position)
+                        .filter(|(ahead, _)| ahead != waiter)
+                        .map(|(ahead, _)| ahead.clone()),
+                );
+            }
+        }
+        graph
+    }
+
+    //a cycle in the waits-for graph, i.e. a deadlock. the policies should never let one form
+    pub fn find_deadlock(&self) -> Option<Vec<T>> {
+        let graph = self.waits_for();
+        let mut done: HashSet<T> = HashSet::new();
+        for start in graph.keys() {
+            let mut path = Vec::new();
+            if let Some(cycle) = Self::cycle_from(&graph, start, &mut path, &mut done) {
+                return Some(cycle);
+            }
+        }
+        None
+    }
+
+    //depth first search, `path` is the current chain of waiters
+    fn cycle_from(
+        graph: &HashMap<T, HashSet<T>>,
+        txn: &T,
+        path: &mut Vec<T>,
+        done: &mut HashSet<T>,
+    ) -> Option<Vec<T>> {
+        if let Some(position) = path.iter().position(|on_path| on_path == txn) {
+            return Some(path[position..].to_vec());
+        }
+        if done.contains(txn) {
+            return None;
+        }
+        path.push(txn.clone());
+        for next in graph.get(txn).into_iter().flatten() {
+            if let Some(cycle) = Self::cycle_from(graph, next, path, done) {
+                return Some(cycle);
+            }
+        }
+        path.pop();
+        done.insert(txn.clone());
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use LockMode::{Exclusive, Shared};
+
+    fn manager(policy: Policy) -> LockManager<&'static str, usize> {
+        LockManager::new(policy)
+    }
+
+    #[test]
+    fn shared_locks_share_and_exclusive_ones_conflict() {
+        let mut locks = manager(Policy::WaitDie);
+        assert_eq!(locks.acquire(&2, "a", Shared), Acquire::Granted);
+        assert_eq!(locks.acquire(&3, "a", Shared), Acquire::Granted);
+        assert_eq!(locks.acquire(&1, "a", Exclusive), Acquire::Waiting);
+        assert_eq!(locks.acquire(&4, "b", Exclusive), Acquire::Granted);
+        //asking again for what's already held
+        assert_eq!(locks.acquire(&4, "b", Shared), Acquire::Granted);
+        assert_eq!(locks.acquire(&4, "b", Exclusive), Acquire::Granted);
+    }
+
+    #[test]
+    fn wait_die_lets_older_wait_and_younger_die() {
+        let mut locks = manager(Policy::WaitDie);
+        assert_eq!(locks.acquire(&5, "a", Exclusive), Acquire::Granted);
+        assert_eq!(locks.acquire(&3, "a", Shared), Acquire::Waiting);
+        //younger than the holder
+        assert_eq!(locks.acquire(&7, "a", Shared), Acquire::Die);
+        //older than the holder but younger than the one queued ahead
+        assert_eq!(locks.acquire(&4, "a", Shared), Acquire::Die);
+        //a txn that died wasn't queued, it isn't waited for
+        assert_eq!(locks.waits_for()[&3], HashSet::from([5]));
+        assert_eq!(locks.waits_for().len(), 1);
+    }
+
+    #[test]
+    fn wound_wait_lets_older_wound_and_younger_wait() {
+        let mut locks = manager(Policy::WoundWait);
+        assert_eq!(locks.acquire(&5, "a", Exclusive), Acquire::Granted);
+        assert_eq!(locks.acquire(&7, "a", Shared), Acquire::Waiting);
+        //both are in the way of an older txn, which queues behind them
+        assert_eq!(
+            locks.acquire(&1, "a", Exclusive),
+            Acquire::Wound(vec![5, 7])
+        );
+        assert_eq!(locks.waits_for()[&1], HashSet::from([5, 7]));
+        //older than the holder, younger than the queue: only the younger ones are wounded
+        let mut locks = manager(Policy::WoundWait);
+        locks.acquire(&5, "b", Exclusive);
+        locks.acquire(&2, "b", Exclusive);
+        assert_eq!(locks.acquire(&3, "b", Shared), Acquire::Wound(vec![5]));
+    }
+
+    #[test]
+    fn upgrades_wait_for_other_readers() {
+        let mut locks = manager(Policy::WaitDie);
+        //alone on the key, an upgrade goes straight through
+        assert_eq!(locks.acquire(&1, "a", Shared), Acquire::Granted);
+        assert_eq!(locks.acquire(&1, "a", Exclusive), Acquire::Granted);
+        assert_eq!(locks.acquire(&2, "a", Shared), Acquire::Die);
+
+        assert_eq!(locks.acquire(&1, "b", Shared), Acquire::Granted);
+        assert_eq!(locks.acquire(&2, "b", Shared), Acquire::Granted);
+        assert_eq!(locks.acquire(&1, "b", Exclusive), Acquire::Waiting);
+        assert_eq!(locks.release_all(&2), vec![(1, "b")]);
+        //the upgrade holds, another reader is shut out
+        assert_eq!(locks.acquire(&0, "b", Shared), Acquire::Waiting);
+        assert_eq!(locks.release_all(&1), vec![(0, "b")]);
+    }
+
+    #[test]
+    fn release_grants_the_queue_in_order() {
+        let mut locks = manager(Policy::WaitDie);
+        locks.acquire(&9, "a", Exclusive);
+        assert_eq!(locks.acquire(&5, "a", Shared), Acquire::Waiting);
+        assert_eq!(locks.acquire(&3, "a", Shared), Acquire::Waiting);
+        assert_eq!(locks.acquire(&1, "a", Exclusive), Acquire::Waiting);
+        assert_eq!(locks.acquire(&0, "a", Shared), Acquire::Waiting);
+        //both readers at the front go together, the writer behind them stops the queue
+        assert_eq!(locks.release_all(&9), vec![(5, "a"), (3, "a")]);
+        assert_eq!(locks.release_all(&5), vec![]);
+        assert_eq!(locks.release_all(&3), vec![(1, "a")]);
+        //a queued request is dropped with the rest of a txn
+        assert_eq!(locks.release_all(&0), vec![]);
+        assert_eq!(locks.release_all(&1), vec![]);
+        assert!(locks.locks.is_empty() && locks.keys.is_empty());
+    }
+
+    #[test]
+    fn finds_a_waits_for_cycle() {
+        //wound-wait only stays deadlock free if the wounded are aborted, here they aren't
+        let mut locks = manager(Policy::WoundWait);
+        locks.acquire(&2, "a", Exclusive);
+        locks.acquire(&1, "b", Exclusive);
+        assert_eq!(locks.acquire(&1, "a", Exclusive), Acquire::Wound(vec![2]));
+        assert_eq!(locks.find_deadlock(), None);
+        assert_eq!(locks.acquire(&2, "b", Exclusive), Acquire::Waiting);
+
+        let mut cycle = locks.find_deadlock().expect("1 and 2 wait for each other");
+        cycle.sort_unstable();
+        assert_eq!(cycle, vec![1, 2]);
+        assert_eq!(locks.release_all(&2), vec![(1, "a")]);
+        assert_eq!(locks.find_deadlock(), None);
+    }
+}
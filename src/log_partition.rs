@@ -0,0 +1,568 @@
+/*
+    One key of the kafka-style log as a replica keeps it: the records, which
+    transaction each undecided or aborted record belongs to, consumer group
+    offsets and the last leadership heard of. A poll sees records below the
+    high-water mark up to the first undecided one and skips aborted ones.
+    Committed batches that wrote to other keys too are remembered with where
+    they end on each, so a poll over several keys can tell one it would only
+    show part of.
+    Retention (off by default) drops the oldest records but never past what's
+    visible: KAFKA_RETENTION_RECORDS keeps the newest n, KAFKA_RETENTION_MS
+    drops older ones and KAFKA_TRUNCATE_COMMITTED=1 drops what every group
+    committed past.
+    Persistence (off by default): KAFKA_DATA_DIR keeps every key in segment
+    files under <dir>/<node id>/, with the rest of its state next to them.
+    KAFKA_FSYNC is always, never or an interval in ms (default 100),
+    KAFKA_SEGMENT_BYTES sets when a segment rolls over (default 1MiB)
+*/
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    leadership::{Change, Leadership},
+    log_replication::Replica,
+    segment::{FsyncPolicy, SegmentConfig, SegmentLog},
+    Message,
+};
+
+//sequence numbers remembered per producer and key, enough to cover its sends in flight
+pub const DEDUP_WINDOW: usize = 64;
+
+//a transaction as leaders know it: its coordinator and the coordinator's number for it
+pub type TxnId = (String, usize);
+
+//a committed batch that wrote to other keys too, as one of its keys holds it. kept
+//under where it starts on this key
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BatchSpan {
+    //its last record on this key
+    pub last: usize,
+    //its last record on each of its keys, this one included
+    pub ends: HashMap<String, usize>,
+}
+
+//what a leader does once the hwm passes an offset
+#[derive(Debug)]
+pub enum Waiter {
+    Send(Message<()>),
+    Prepare(TxnId),
+}
+
+#[derive(Debug, Default)]
+pub struct Retention {
+    pub max_records: Option<usize>,
+    pub max_age: Option<Duration>,
+    pub below_committed: bool,
+}
+
+impl Retention {
+    pub fn from_env() -> Self {
+        let var = |name| std::env::var(name).ok();
+        Self {
+            max_records: var("KAFKA_RETENTION_RECORDS").and_then(|n| n.parse().ok()),
+            max_age: var("KAFKA_RETENTION_MS")
+                .and_then(|ms| ms.parse().ok())
+                .map(Duration::from_millis),
+            below_committed: var("KAFKA_TRUNCATE_COMMITTED").is_some_and(|on| on == "1"),
+        }
+    }
+}
+
+//where each node keeps its logs, None when nothing is persisted
+pub fn storage_from_env(node: &str) -> Option<(PathBuf, SegmentConfig)> {
+    let dir = PathBuf::from(std::env::var("KAFKA_DATA_DIR").ok()?).join(node);
+    let mut config = SegmentConfig::default();
+    if let Some(fsync) = std::env::var("KAFKA_FSYNC")
+        .ok()
+        .and_then(|fsync| FsyncPolicy::parse(&fsync))
+    {
+        config.fsync = fsync;
+    }
+    if let Some(bytes) = std::env::var("KAFKA_SEGMENT_BYTES")
+        .ok()
+        .and_then(|bytes| bytes.parse().ok())
+    {
+        config.max_segment_bytes = bytes;
+    }
+    Some((dir, config))
+}
+
+//keys can hold anything, so their directories are named by the hex of the key
+pub fn key_dir(key: &str) -> String {
+    key.bytes().map(|b| format!("{b:02x}")).collect()
+}
+
+pub fn dir_key(dir: &str) -> Option<String> {
+    let bytes = (0..dir.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(dir.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    String::from_utf8(bytes).ok()
+}
+
+//a record as it's kept and replicated, with its producer so every replica can tell
+//a retried send and deduplication survives a restart
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Record {
+    pub msg: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub producer: Option<(String, usize)>,
+}
+
+//kept next to a key's segments
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct PartitionState {
+    start: usize,
+    committed: HashMap<String, usize>,
+    #[serde(default)]
+    pending: BTreeMap<usize, TxnId>,
+    #[serde(default)]
+    aborted: BTreeSet<usize>,
+    #[serde(default)]
+    batches: BTreeMap<usize, BatchSpan>,
+    #[serde(default)]
+    leadership: Option<Leadership>,
+    //the records it came from may be gone to retention
+    #[serde(default)]
+    producers: HashMap<String, BTreeMap<usize, usize>>,
+}
+
+//what a producer's sequence number says about a send
+#[derive(Debug, PartialEq, Eq)]
+pub enum Sequenced {
+    New,
+    //a retry of the send that got this offset
+    Retry(usize),
+    //older than every sequence number remembered, can't tell if it's a retry
+    Forgotten,
+}
+
+//everything a replica keeps for one key
+#[derive(Debug, Default)]
+pub struct Partition {
+    pub log: BTreeMap<usize, Record>,
+    //first offset still in the log, everything before it was dropped by retention
+    pub start: usize,
+    pub next_offset: usize,
+    //when each record reached this replica, for age based retention
+    pub appended: VecDeque<(usize, Instant)>,
+    //records below this are on every in-sync replica
+    pub hwm: usize,
+    //the last we heard of who leads
+    pub leadership: Leadership,
+    //leader only: lin-kv agrees with our leadership, nothing is acknowledged until it does
+    pub confirmed: bool,
+    //follower only: caught up with the leader's hwm since this process started.
+    //until then we may be missing acknowledged records and can't take over
+    pub synced: bool,
+    //leadership change in flight in lin-kv
+    pub change: Option<Change>,
+    //group -> committed offset
+    pub committed: HashMap<String, usize>,
+    //leader only: how far each follower has got
+    pub followers: HashMap<String, Replica>,
+    //leader's log end as of the last tick, a follower that has reached it is caught up
+    pub mark: usize,
+    //leader only: what to answer once the hwm passes an offset
+    pub unacked: Vec<(usize, Waiter)>,
+    //records of transactions not decided yet, polls stop at the first one
+    pub pending: BTreeMap<usize, TxnId>,
+    //records of aborted transactions, polls skip them
+    pub aborted: BTreeSet<usize>,
+    //committed batches that wrote to other keys too
+    pub batches: BTreeMap<usize, BatchSpan>,
+    //leader only: committed transactions resolved here, the msg_id of the first
+    //replicate that carried that out, and the in-sync followers yet to ack one since
+    pub resolving: Vec<(TxnId, usize, BTreeSet<String>)>,
+    //producer -> recent sequence numbers and the offsets they got
+    pub producers: HashMap<String, BTreeMap<usize, usize>>,
+    pub store: Option<SegmentLog<Record>>,
+}
+
+impl Partition {
+    pub fn new(leadership: Leadership) -> Self {
+        Self {
+            leadership,
+            ..Default::default()
+        }
+    }
+
+    //reload what a previous run left on disk. the whole log goes back into memory and
+    //polls are served from there, so the store's sparse index never serves a real seek:
+    //this read starts at the front and is the only one
+    pub fn recover(&mut self, store: SegmentLog<Record>) -> anyhow::Result<()> {
+        let state: PartitionState = store.load_state()?.unwrap_or_default();
+        self.start = state.start.max(store.start_offset());
+        self.next_offset = self.start;
+        self.producers = state.producers;
+        for (offset, record) in store.read_from(self.start, usize::MAX)? {
+            self.load(offset, record);
+        }
+        self.next_offset = self.next_offset.max(store.next_offset());
+        //a crash may have lost records the state was saved with
+        for seqs in self.producers.values_mut() {
+            seqs.retain(|_, offset| *offset < self.next_offset);
+        }
+        self.hwm = self.start;
+        self.committed = state.committed;
+        self.pending = state.pending;
+        self.aborted = state.aborted;
+        self.batches = state.batches;
+        if let Some(leadership) = state.leadership {
+            self.leadership = leadership;
+        }
+        self.store = Some(store);
+        Ok(())
+    }
+
+    pub fn push(&mut self, msg: usize, producer: Option<(String, usize)>) -> anyhow::Result<usize> {
+        let offset = self.next_offset;
+        let record = Record { msg, producer };
+        if let Some(store) = &mut self.store {
+            store.append(&record).context("persist kafka record")?;
+        }
+        self.load(offset, record);
+        Ok(offset)
+    }
+
+    fn load(&mut self, offset: usize, record: Record) {
+        if let Some((producer, seq)) = &record.producer {
+            let seqs = self.producers.entry(producer.clone()).or_default();
+            seqs.insert(*seq, offset);
+            if seqs.len() > DEDUP_WINDOW {
+                seqs.pop_first();
+            }
+        }
+        self.log.insert(offset, record);
+        self.appended.push_back((offset, Instant::now()));
+        self.next_offset = offset + 1;
+    }
+
+    pub fn sequenced(&self, producer: &str, seq: usize) -> Sequenced {
+        let Some(seqs) = self.producers.get(producer) else {
+            return Sequenced::New;
+        };
+        if let Some(offset) = seqs.get(&seq) {
+            return Sequenced::Retry(*offset);
+        }
+        let forgotten = seqs.len() >= DEDUP_WINDOW
+            && seqs
+                .first_key_value()
+                .is_some_and(|(oldest, _)| seq < *oldest);
+        if forgotten {
+            Sequenced::Forgotten
+        } else {
+            Sequenced::New
+        }
+    }
+
+    //drop records from `from` on
+    pub fn truncate_tail(&mut self, from: usize) -> anyhow::Result<()> {
+        if from >= self.next_offset {
+            return Ok(());
+        }
+        self.log.split_off(&from);
+        self.pending.split_off(&from);
+        self.aborted.split_off(&from);
+        self.batches.split_off(&from);
+        self.appended.retain(|(offset, _)| *offset < from);
+        for seqs in self.producers.values_mut() {
+            seqs.retain(|_, offset| *offset < from);
+        }
+        self.next_offset = from;
+        if let Some(store) = &mut self.store {
+            store
+                .truncate_from(from)
+                .context("drop unreplicated kafka records")?;
+        }
+        self.save_state()
+    }
+
+    //the follower fell behind the leader's log start, drop everything and carry on from there
+    pub fn reset(&mut self, start: usize) -> anyhow::Result<()> {
+        self.log.clear();
+        self.appended.clear();
+        self.start = start;
+        self.next_offset = start;
+        if let Some(store) = &mut self.store {
+            store.reset(start).context("reset kafka log")?;
+        }
+        self.save_state()
+    }
+
+    pub fn save_state(&self) -> anyhow::Result<()> {
+        let Some(store) = &self.store else {
+            return Ok(());
+        };
+        let state = PartitionState {
+            start: self.start,
+            committed: self.committed.clone(),
+            pending: self.pending.clone(),
+            aborted: self.aborted.clone(),
+            batches: self.batches.clone(),
+            leadership: Some(self.leadership.clone()),
+            producers: self.producers.clone(),
+        };
+        store
+            .save_state(&state)
+            .context("persist kafka partition state")
+    }
+
+    //lowest offset any consumer group still needs
+    pub fn min_committed(&self) -> Option<usize> {
+        self.committed.values().copied().min()
+    }
+
+    //records below this can be polled: replicated, and no undecided transaction before them
+    pub fn visible_end(&self) -> usize {
+        match self.pending.first_key_value() {
+            Some((first, _)) => self.hwm.min(*first),
+            None => self.hwm,
+        }
+    }
+
+    //the undecided transaction polls stop at, if it may be committed elsewhere already:
+    //all its records are below the hwm, so its coordinator may have heard they're prepared
+    pub fn held_by(&self) -> Option<&TxnId> {
+        let (_, id) = self.pending.first_key_value()?;
+        let (last, _) = self
+            .pending
+            .iter()
+            .rev()
+            .find(|(_, pending)| *pending == id)?;
+        (*last < self.hwm).then_some(id)
+    }
+
+    //committed batches with records among `from..=to` that wrote to other keys too
+    pub fn batches_in(&self, from: usize, to: usize) -> Vec<(usize, BatchSpan)> {
+        self.batches
+            .range(..=to)
+            .filter(|(_, span)| span.last >= from)
+            .map(|(first, span)| (*first, span.clone()))
+            .collect()
+    }
+
+    //what a poll from `from` gets, before any limits
+    pub fn visible_from(&self, from: usize) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.log
+            .range(from..)
+            .take_while(|(offset, _)| **offset < self.visible_end())
+            .filter(|(offset, _)| !self.aborted.contains(offset))
+            .map(|(offset, record)| (*offset, record.msg))
+    }
+
+    //offsets of a transaction's records that are still undecided
+    pub fn pending_of(&self, id: &TxnId) -> Vec<usize> {
+        self.pending
+            .iter()
+            .filter(|(_, pending)| *pending == id)
+            .map(|(offset, _)| *offset)
+            .collect()
+    }
+
+    //a transaction's records become visible, or are skipped from now on. `ends` is where
+    //a committed one ended on each of its keys. false if we hold none of its records
+    pub fn resolve(
+        &mut self,
+        id: &TxnId,
+        commit: bool,
+        ends: &HashMap<String, usize>,
+    ) -> anyhow::Result<bool> {
+        let offsets = self.pending_of(id);
+        let (Some(first), Some(last)) = (offsets.first(), offsets.last()) else {
+            return Ok(false);
+        };
+        if commit && ends.len() > 1 {
+            let span = BatchSpan {
+                last: *last,
+                ends: ends.clone(),
+            };
+            self.batches.insert(*first, span);
+        }
+        for offset in offsets {
+            self.pending.remove(&offset);
+            if !commit {
+                self.aborted.insert(offset);
+            }
+        }
+        self.save_state()?;
+        Ok(true)
+    }
+
+    //drop records below `to`. never past what's visible, the rest may still be needed
+    pub fn truncate(&mut self, to: usize) -> anyhow::Result<()> {
+        let to = to.min(self.visible_end());
+        if to <= self.start {
+            return Ok(());
+        }
+        self.log = self.log.split_off(&to);
+        self.aborted = self.aborted.split_off(&to);
+        self.batches.retain(|_, span| span.last >= to);
+        self.start = to;
+        while self
+            .appended
+            .front()
+            .is_some_and(|(offset, _)| *offset < to)
+        {
+            self.appended.pop_front();
+        }
+        if let Some(store) = &mut self.store {
+            store
+                .truncate_before(to)
+                .context("drop old kafka segments")?;
+        }
+        self.save_state()
+    }
+
+    pub fn retain(&mut self, retention: &Retention) -> anyhow::Result<()> {
+        if let Some(max) = retention.max_records {
+            self.truncate(self.next_offset.saturating_sub(max))?;
+        }
+        if let Some(max_age) = retention.max_age {
+            let expired = self
+                .appended
+                .iter()
+                .take_while(|(_, at)| at.elapsed() > max_age)
+                .last();
+            if let Some((offset, _)) = expired {
+                self.truncate(offset + 1)?;
+            }
+        }
+        if retention.below_committed {
+            if let Some(committed) = self.min_committed() {
+                self.truncate(committed)?;
+            }
+        }
+        if let Some(store) = &mut self.store {
+            store.tick().context("fsync kafka log")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn partition(msgs: &[usize]) -> Partition {
+        let mut partition = Partition::default();
+        for msg in msgs {
+            partition.push(*msg, None).unwrap();
+        }
+        partition
+    }
+
+    #[test]
+    fn polls_stop_at_the_hwm_and_the_first_undecided_record() {
+        let mut partition = partition(&[10, 11, 12, 13, 14]);
+        partition.hwm = 4;
+        let txn = ("n1".to_string(), 7);
+        partition.pending.insert(2, txn.clone());
+        let visible: Vec<_> = partition.visible_from(0).collect();
+        assert_eq!(visible, [(0, 10), (1, 11)]);
+        partition.resolve(&txn, true, &HashMap::new()).unwrap();
+        let visible: Vec<_> = partition.visible_from(1).collect();
+        assert_eq!(visible, [(1, 11), (2, 12), (3, 13)]);
+    }
+
+    #[test]
+    fn aborted_records_are_skipped() {
+        let mut partition = partition(&[10, 11, 12]);
+        partition.hwm = 3;
+        let txn = ("n1".to_string(), 7);
+        partition.pending.insert(1, txn.clone());
+        assert!(partition.resolve(&txn, false, &HashMap::new()).unwrap());
+        assert!(!partition.resolve(&txn, false, &HashMap::new()).unwrap());
+        let visible: Vec<_> = partition.visible_from(0).collect();
+        assert_eq!(visible, [(0, 10), (2, 12)]);
+    }
+
+    #[test]
+    fn undecided_txn_holds_polls_once_it_may_be_committed() {
+        let mut partition = partition(&[10, 11, 12, 13]);
+        let txn = ("n1".to_string(), 7);
+        partition.pending.insert(1, txn.clone());
+        partition.pending.insert(2, txn.clone());
+        partition.hwm = 2;
+        //not all replicated yet, nobody can have committed it
+        assert_eq!(partition.held_by(), None);
+        partition.hwm = 4;
+        assert_eq!(partition.held_by(), Some(&txn));
+    }
+
+    #[test]
+    fn committed_batches_spanning_keys_are_remembered() {
+        let mut partition = partition(&[10, 11, 12, 13]);
+        partition.hwm = 4;
+        let (single, spanning) = (("n1".to_string(), 7), ("n1".to_string(), 8));
+        partition.pending.insert(0, single.clone());
+        partition.pending.insert(1, spanning.clone());
+        partition.pending.insert(2, spanning.clone());
+        let ends = HashMap::from([("a".to_string(), 2), ("b".to_string(), 5)]);
+        partition
+            .resolve(&single, true, &HashMap::from([("a".to_string(), 0)]))
+            .unwrap();
+        partition.resolve(&spanning, true, &ends).unwrap();
+        let span = BatchSpan { last: 2, ends };
+        assert_eq!(partition.batches_in(0, 0), []);
+        assert_eq!(partition.batches_in(2, 3), [(1, span.clone())]);
+        partition.truncate(2).unwrap();
+        assert_eq!(partition.batches_in(2, 3), [(1, span)]);
+        partition.truncate(3).unwrap();
+        assert!(partition.batches.is_empty());
+    }
+
+    #[test]
+    fn retention_never_drops_what_polls_cannot_see_yet() {
+        let mut partition = partition(&[10, 11, 12, 13]);
+        partition.hwm = 2;
+        let retention = Retention {
+            max_records: Some(1),
+            ..Default::default()
+        };
+        partition.retain(&retention).unwrap();
+        assert_eq!(partition.start, 2);
+        assert_eq!(partition.log.keys().copied().collect::<Vec<_>>(), [2, 3]);
+    }
+
+    #[test]
+    fn producer_sequences_outlive_retention_across_a_restart() {
+        let dir = std::env::temp_dir().join(format!("log-partition-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let open = || SegmentLog::open(&dir, SegmentConfig::default()).unwrap();
+        let mut partition = Partition::default();
+        partition.recover(open()).unwrap();
+        partition.push(10, Some(("p".to_string(), 3))).unwrap();
+        partition.push(11, None).unwrap();
+        partition.hwm = 2;
+        partition.truncate(2).unwrap();
+
+        let mut restarted = Partition::default();
+        restarted.recover(open()).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(restarted.log.is_empty());
+        assert_eq!(restarted.sequenced("p", 3), Sequenced::Retry(0));
+    }
+
+    #[test]
+    fn producer_sequences_tell_retries_from_new_sends() {
+        let mut partition = Partition::default();
+        for seq in 0..=DEDUP_WINDOW {
+            partition.push(seq, Some(("p".to_string(), seq))).unwrap();
+        }
+        assert_eq!(partition.sequenced("p", 5), Sequenced::Retry(5));
+        assert_eq!(partition.sequenced("p", DEDUP_WINDOW + 1), Sequenced::New);
+        assert_eq!(partition.sequenced("p", 0), Sequenced::Forgotten);
+        assert_eq!(partition.sequenced("q", 0), Sequenced::New);
+        //records dropped past the hwm are no longer retries
+        partition.truncate_tail(5).unwrap();
+        assert_eq!(partition.sequenced("p", 5), Sequenced::New);
+    }
+}
@@ -0,0 +1,387 @@
+/*
+    How a kafka key's leader keeps its followers in line. The leader pushes new
+    records to every follower and hears back how far each one's log goes. A
+    record is acknowledged once every replica in the in-sync set has it, which
+    moves the high-water mark past it. A follower that hasn't caught up for a
+    while drops out of the set so it can't stall the key. A follower takes the
+    records that extend its log along with the leader's hwm, group offsets and
+    transaction state, so whichever in-sync follower takes over knows them.
+*/
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    time::{Duration, Instant},
+};
+
+use crate::log_partition::{BatchSpan, Partition, Record, TxnId, Waiter};
+
+//a follower that hasn't caught up with the leader for this long leaves the in-sync set
+pub const ISR_LAG: Duration = Duration::from_millis(1000);
+//most records pushed to a follower in one message
+pub const MAX_BATCH: usize = 128;
+
+//a follower as its leader sees it
+#[derive(Debug)]
+pub struct Replica {
+    pub end: usize,
+    //None until it first catches up, unless it starts out in the isr
+    pub caught_up: Option<Instant>,
+}
+
+impl Replica {
+    pub fn in_sync(&self) -> bool {
+        self.caught_up.is_some_and(|at| at.elapsed() < ISR_LAG)
+    }
+}
+
+//a leader's transaction state, sent whole with its records
+#[derive(Debug, Default)]
+pub struct Txns {
+    pub pending: BTreeMap<usize, TxnId>,
+    pub aborted: BTreeSet<usize>,
+    pub batches: BTreeMap<usize, BatchSpan>,
+}
+
+impl Partition {
+    //we lead now: every other replica follows, those in the isr count as caught up for now
+    pub fn lead(&mut self, replicas: &[String]) {
+        self.confirmed = false;
+        self.synced = true;
+        self.resolving.clear();
+        self.followers = replicas
+            .iter()
+            .filter(|node| **node != self.leadership.leader)
+            .map(|follower| {
+                let replica = Replica {
+                    end: 0,
+                    caught_up: self.leadership.isr.contains(follower).then(Instant::now),
+                };
+                (follower.clone(), replica)
+            })
+            .collect();
+    }
+
+    //someone else leads now: nothing we were waiting to ack will be acked by us, those
+    //waiting are handed back. records past our hwm may be ones the new leader never got
+    pub fn follow(&mut self) -> anyhow::Result<Vec<(usize, Waiter)>> {
+        self.confirmed = false;
+        self.followers.clear();
+        //the new leader reports them, or asks the coordinator if it never heard
+        self.resolving.clear();
+        self.truncate_tail(self.hwm)?;
+        Ok(std::mem::take(&mut self.unacked))
+    }
+
+    //leader side: the key's replica set changed. new replicas start from scratch and
+    //count as caught up once they are, replicas that are gone aren't sent anything
+    pub fn rebalance(&mut self, replicas: &[String]) {
+        self.followers
+            .retain(|follower, _| replicas.contains(follower));
+        for replica in replicas {
+            if *replica != self.leadership.leader {
+                self.followers.entry(replica.clone()).or_insert(Replica {
+                    end: 0,
+                    caught_up: None,
+                });
+            }
+        }
+    }
+
+    //what a follower whose log ends at `from` is sent next
+    pub fn records_from(&self, from: usize) -> Vec<(usize, Record)> {
+        self.log
+            .range(from..)
+            .take(MAX_BATCH)
+            .map(|(offset, record)| (*offset, record.clone()))
+            .collect()
+    }
+
+    //once a tick: followers at our log end are caught up, the others get what they miss
+    pub fn lagging(&mut self) -> Vec<(String, Vec<(usize, Record)>)> {
+        self.mark = self.next_offset;
+        let mut lagging = Vec::new();
+        for (follower, replica) in &mut self.followers {
+            if replica.end >= self.next_offset {
+                replica.caught_up = Some(Instant::now());
+            } else {
+                lagging.push((follower.clone(), replica.end));
+            }
+        }
+        lagging
+            .into_iter()
+            .map(|(follower, from)| (follower, self.records_from(from)))
+            .collect()
+    }
+
+    //the isr as it should be: us and the followers that kept up, in replica order.
+    //we stay in even once the ring has moved the key elsewhere, we still lead it
+    pub fn in_sync_replicas(&self, replicas: &[String], node: &str) -> Vec<String> {
+        let mut isr: Vec<String> = replicas
+            .iter()
+            .filter(|replica| {
+                *replica == node
+                    || self
+                        .followers
+                        .get(*replica)
+                        .is_some_and(|replica| replica.in_sync())
+            })
+            .cloned()
+            .collect();
+        if !isr.iter().any(|replica| replica == node) {
+            isr.insert(0, node.to_string());
+        }
+        isr
+    }
+
+    //follower side: take what the leader sent, returns where our log ends now
+    pub fn take(
+        &mut self,
+        start: usize,
+        records: Vec<(usize, Record)>,
+        hwm: usize,
+        committed: HashMap<String, usize>,
+        txns: Txns,
+    ) -> anyhow::Result<usize> {
+        if self.next_offset < start {
+            //the leader no longer has what we're missing
+            self.reset(start)?;
+        }
+        //with their producers, so we tell retries apart too once we lead
+        for (offset, record) in records {
+            if offset == self.next_offset {
+                self.push(record.msg, record.producer)?;
+            }
+        }
+        self.hwm = self.hwm.max(hwm.min(self.next_offset));
+        self.hwm = self.hwm.max(self.start);
+        self.synced |= self.next_offset >= hwm;
+        let changed = txns.pending != self.pending
+            || txns.aborted != self.aborted
+            || txns.batches != self.batches;
+        if committed != self.committed || changed {
+            for (group, offset) in committed {
+                let ours = self.committed.entry(group).or_default();
+                *ours = (*ours).max(offset);
+            }
+            self.pending = txns.pending;
+            self.aborted = txns.aborted;
+            self.batches = txns.batches;
+            self.save_state()?;
+        }
+        Ok(self.next_offset)
+    }
+
+    //leader side: a follower's log ends at `end` as of our replicate `msg_id`.
+    //returns the transactions every in-sync follower has resolved now
+    pub fn acked(&mut self, follower: &str, end: usize, msg_id: usize) -> Vec<TxnId> {
+        let caught_up = self.mark.max(self.hwm);
+        if let Some(replica) = self.followers.get_mut(follower) {
+            //not max'd, a restarted follower comes back with an empty log
+            replica.end = end;
+            if end >= caught_up {
+                replica.caught_up = Some(Instant::now());
+            }
+        }
+        for (_, from, waiting) in &mut self.resolving {
+            if msg_id >= *from {
+                waiting.remove(follower);
+            }
+        }
+        self.drain_resolved()
+    }
+
+    //followers that dropped out of the isr aren't waited for
+    pub fn drain_resolved(&mut self) -> Vec<TxnId> {
+        let isr = &self.leadership.isr;
+        for (_, _, waiting) in &mut self.resolving {
+            waiting.retain(|follower| isr.contains(follower));
+        }
+        let (done, waiting) = std::mem::take(&mut self.resolving)
+            .into_iter()
+            .partition(|(_, _, waiting)| waiting.is_empty());
+        self.resolving = waiting;
+        done.into_iter().map(|(id, _, _)| id).collect()
+    }
+
+    //move the hwm up to the shortest log in the isr lin-kv agreed to and hand back
+    //what's now safe to ack. a follower that fell behind holds it until it's voted out
+    pub fn advance_hwm(&mut self) -> Vec<(usize, Waiter)> {
+        if !self.confirmed {
+            return Vec::new();
+        }
+        let hwm = self
+            .leadership
+            .isr
+            .iter()
+            .filter(|node| **node != self.leadership.leader)
+            .map(|node| self.followers.get(node).map_or(0, |replica| replica.end))
+            .fold(self.next_offset, usize::min);
+        self.hwm = self.hwm.max(hwm);
+        let (acked, unacked) = std::mem::take(&mut self.unacked)
+            .into_iter()
+            .partition(|(offset, _)| *offset < self.hwm);
+        self.unacked = unacked;
+        acked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{leadership::Leadership, log_partition::Sequenced};
+
+    use super::*;
+
+    fn nodes(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    //who's behind and the offsets they're sent
+    fn lagging(partition: &mut Partition) -> Vec<(String, Vec<usize>)> {
+        partition
+            .lagging()
+            .into_iter()
+            .map(|(follower, records)| (follower, records.iter().map(|(o, _)| *o).collect()))
+            .collect()
+    }
+
+    fn take(
+        follower: &mut Partition,
+        start: usize,
+        records: Vec<(usize, Record)>,
+        hwm: usize,
+    ) -> usize {
+        follower
+            .take(start, records, hwm, HashMap::new(), Txns::default())
+            .unwrap()
+    }
+
+    fn records(records: &[(usize, usize)]) -> Vec<(usize, Record)> {
+        records
+            .iter()
+            .map(|(offset, msg)| {
+                (
+                    *offset,
+                    Record {
+                        msg: *msg,
+                        producer: None,
+                    },
+                )
+            })
+            .collect()
+    }
+
+    //n0 leads three replicas, lin-kv agrees, and it has appended `records`
+    fn leader(records: usize) -> Partition {
+        let replicas = nodes(&["n0", "n1", "n2"]);
+        let mut partition = Partition::new(Leadership::initial(replicas.clone()));
+        partition.lead(&replicas);
+        partition.confirmed = true;
+        for msg in 0..records {
+            let offset = partition.push(msg, None).unwrap();
+            partition
+                .unacked
+                .push((offset, Waiter::Prepare(("n0".to_string(), msg))));
+        }
+        partition
+    }
+
+    #[test]
+    fn hwm_follows_the_shortest_log_in_the_isr() {
+        let mut partition = leader(3);
+        partition.acked("n1", 3, 0);
+        assert!(partition.advance_hwm().is_empty());
+        partition.acked("n2", 2, 0);
+        let acked: Vec<usize> = partition.advance_hwm().iter().map(|(o, _)| *o).collect();
+        assert_eq!(acked, [0, 1]);
+        assert_eq!(partition.hwm, 2);
+        //n2 leaves the isr, n1 alone holds everything
+        partition.leadership.isr = nodes(&["n0", "n1"]);
+        assert_eq!(partition.advance_hwm().len(), 1);
+        assert_eq!(partition.hwm, 3);
+    }
+
+    #[test]
+    fn nothing_is_acked_before_lin_kv_agrees() {
+        let mut partition = leader(1);
+        partition.confirmed = false;
+        partition.acked("n1", 1, 0);
+        partition.acked("n2", 1, 0);
+        assert!(partition.advance_hwm().is_empty());
+        assert_eq!(partition.hwm, 0);
+    }
+
+    #[test]
+    fn followers_that_fall_behind_leave_the_isr() {
+        let mut partition = leader(2);
+        let replicas = nodes(&["n0", "n1", "n2"]);
+        partition.acked("n1", 2, 0);
+        assert_eq!(lagging(&mut partition), [("n2".to_string(), vec![0, 1])]);
+        partition.followers.get_mut("n2").unwrap().caught_up = Some(Instant::now() - ISR_LAG * 2);
+        assert_eq!(
+            partition.in_sync_replicas(&replicas, "n0"),
+            nodes(&["n0", "n1"])
+        );
+    }
+
+    #[test]
+    fn new_replica_joins_the_isr_once_it_catches_up() {
+        let mut partition = leader(2);
+        partition.acked("n1", 2, 0);
+        partition.acked("n2", 2, 0);
+        //n2 is gone, n3 took its place on the ring
+        let replicas = nodes(&["n0", "n1", "n3"]);
+        partition.rebalance(&replicas);
+        assert_eq!(lagging(&mut partition), [("n3".to_string(), vec![0, 1])]);
+        assert_eq!(
+            partition.in_sync_replicas(&replicas, "n0"),
+            nodes(&["n0", "n1"])
+        );
+        //n2 is still in the isr lin-kv has, so nothing is acked without it
+        partition.push(2, None).unwrap();
+        partition.acked("n1", 3, 0);
+        partition.acked("n3", 3, 0);
+        assert_eq!(partition.advance_hwm().len(), 0);
+        assert_eq!(
+            partition.in_sync_replicas(&replicas, "n0"),
+            nodes(&["n0", "n1", "n3"])
+        );
+        partition.leadership.isr = nodes(&["n0", "n1", "n3"]);
+        partition.advance_hwm();
+        assert_eq!(partition.hwm, 3);
+    }
+
+    #[test]
+    fn follower_takes_only_records_that_extend_its_log() {
+        let mut follower = Partition::new(Leadership::initial(nodes(&["n0", "n1"])));
+        assert_eq!(take(&mut follower, 0, records(&[(1, 11)]), 0), 0);
+        assert_eq!(take(&mut follower, 0, records(&[(0, 10), (1, 11)]), 1), 2);
+        assert_eq!(follower.hwm, 1);
+        assert!(follower.synced);
+        //the leader dropped everything before 5 while we were away
+        assert_eq!(take(&mut follower, 5, records(&[(5, 15)]), 6), 6);
+        assert_eq!(follower.start, 5);
+        assert_eq!(follower.hwm, 6);
+    }
+
+    #[test]
+    fn follower_tells_retried_sends_apart_once_it_leads() {
+        let mut partition = leader(0);
+        partition.push(10, Some(("p".to_string(), 7))).unwrap();
+        partition.push(11, None).unwrap();
+        let mut follower = Partition::new(partition.leadership.clone());
+        take(&mut follower, 0, partition.records_from(0), 2);
+        assert_eq!(follower.sequenced("p", 7), Sequenced::Retry(0));
+        assert_eq!(follower.sequenced("p", 8), Sequenced::New);
+    }
+
+    #[test]
+    fn new_leader_drops_records_past_its_hwm() {
+        let mut partition = leader(3);
+        partition.hwm = 1;
+        partition.leadership = partition.leadership.handed_to("n1".to_string());
+        assert_eq!(partition.follow().unwrap().len(), 3);
+        assert_eq!(partition.next_offset, 1);
+        assert!(partition.unacked.is_empty());
+        assert!(partition.followers.is_empty());
+    }
+}
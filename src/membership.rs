@@ -0,0 +1,779 @@
+/*
+    SWIM-style membership: failure detection by direct and indirect probes,
+    suspicion before declaring a node dead, and infection-style dissemination
+    of membership updates piggybacked on the probe traffic. a node that
+    left or was declared dead can come back by joining again, the seed
+    admits it under a higher incarnation than the one it went away with.
+*/
+
+use std::{
+    collections::HashMap,
+    io::Write,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use rand::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::Message;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MemberState {
+    Alive,
+    Suspect,
+    Dead,
+    Left,
+}
+
+//a single membership fact, gossiped between nodes
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Update {
+    pub node: String,
+    pub state: MemberState,
+    pub incarnation: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum MembershipPayload {
+    Ping {
+        seq: usize,
+        updates: Vec<Update>,
+    },
+    PingAck {
+        seq: usize,
+        updates: Vec<Update>,
+    },
+    //ask a peer to probe target on our behalf
+    PingReq {
+        seq: usize,
+        target: String,
+        updates: Vec<Update>,
+    },
+    Join,
+    JoinOk {
+        members: Vec<Update>,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct MembershipConfig {
+    //how often a new member is probed
+    pub protocol_period: Duration,
+    //how long to wait for a direct ack before asking others to probe
+    pub ping_timeout: Duration,
+    //how long a member stays suspect before it's declared dead
+    pub suspect_timeout: Duration,
+    //number of members asked to probe indirectly
+    pub indirect_probes: usize,
+    //updates piggybacked on a single message
+    pub max_piggyback: usize,
+    //multiplier for how many times an update is retransmitted (lambda * log n)
+    pub retransmit_mult: usize,
+}
+
+impl Default for MembershipConfig {
+    fn default() -> Self {
+        Self {
+            protocol_period: Duration::from_millis(500),
+            ping_timeout: Duration::from_millis(150),
+            suspect_timeout: Duration::from_millis(1500),
+            indirect_probes: 3,
+            max_piggyback: 8,
+            retransmit_mult: 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Member {
+    state: MemberState,
+    incarnation: usize,
+    //when the member last changed state, used for the suspicion timeout
+    since: Instant,
+}
+
+#[derive(Debug, Clone)]
+struct Probe {
+    target: String,
+    started: Instant,
+    //ping-req already sent for this probe
+    indirect: bool,
+    //set when probing on behalf of another node: (requester, requester's seq)
+    requester: Option<(String, usize)>,
+}
+
+pub struct Membership {
+    node: String,
+    config: MembershipConfig,
+    incarnation: usize,
+    members: HashMap<String, Member>,
+    probes: HashMap<usize, Probe>,
+    next_seq: usize,
+    //shuffled round-robin order of probe targets
+    probe_order: Vec<String>,
+    last_probe: Option<Instant>,
+    //pending updates and their remaining transmissions
+    gossip: Vec<(Update, usize)>,
+    //bumped on every change to the member view
+    version: usize,
+}
+
+impl Membership {
+    pub fn new(node: String, node_ids: Vec<String>, config: MembershipConfig) -> Self {
+        let now = Instant::now();
+        let members = node_ids
+            .into_iter()
+            .filter(|n| n != &node)
+            .map(|n| {
+                (
+                    n,
+                    Member {
+                        state: MemberState::Alive,
+                        incarnation: 0,
+                        since: now,
+                    },
+                )
+            })
+            .collect();
+        Self {
+            node,
+            config,
+            incarnation: 0,
+            members,
+            probes: HashMap::new(),
+            next_seq: 0,
+            probe_order: Vec::new(),
+            last_probe: None,
+            gossip: Vec::new(),
+            version: 0,
+        }
+    }
+
+    //all live members (alive or suspect) including this node, sorted for a stable view
+    pub fn members(&self) -> Vec<String> {
+        let mut members: Vec<String> = self.peers();
+        members.push(self.node.clone());
+        members.sort();
+        members
+    }
+
+    //live members other than this node
+    pub fn peers(&self) -> Vec<String> {
+        self.members
+            .iter()
+            .filter(|(_, m)| matches!(m.state, MemberState::Alive | MemberState::Suspect))
+            .map(|(n, _)| n.clone())
+            .collect()
+    }
+
+    pub fn state_of(&self, node: &str) -> Option<MemberState> {
+        if node == self.node {
+            return Some(MemberState::Alive);
+        }
+        self.members.get(node).map(|m| m.state)
+    }
+
+    //changes whenever the view changes, so callers can cheaply detect when to rebuild
+    pub fn version(&self) -> usize {
+        self.version
+    }
+
+    //ask a seed node to admit us into the cluster
+    pub fn join(
+        &mut self,
+        seed: &str,
+        id: &mut usize,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        Message::new(
+            self.node.clone(),
+            seed.to_string(),
+            id,
+            MembershipPayload::Join,
+        )
+        .send_self(&mut *output)
+        .context("send membership join")
+    }
+
+    //announce a graceful leave, pushed directly to a few peers and then disseminated
+    pub fn leave(&mut self, id: &mut usize, output: &mut impl Write) -> anyhow::Result<()> {
+        self.incarnation += 1;
+        let update = Update {
+            node: self.node.clone(),
+            state: MemberState::Left,
+            incarnation: self.incarnation,
+        };
+        self.enqueue(update);
+        let mut peers = self.peers();
+        peers.shuffle(&mut rand::thread_rng());
+        for peer in peers.into_iter().take(self.config.indirect_probes.max(1)) {
+            self.send_ping(peer, id, output)?;
+        }
+        Ok(())
+    }
+
+    //drive probe timeouts and start new probes, call periodically from an injected event
+    pub fn tick(&mut self, id: &mut usize, output: &mut impl Write) -> anyhow::Result<()> {
+        let now = Instant::now();
+
+        //probes that timed out: first escalate to ping-req, then suspect the target
+        let mut expired = Vec::new();
+        for (seq, probe) in self.probes.iter_mut() {
+            let waited = now.duration_since(probe.started);
+            if probe.requester.is_some() {
+                if waited >= self.config.protocol_period {
+                    expired.push((*seq, probe.target.clone(), false));
+                }
+            } else if !probe.indirect && waited >= self.config.ping_timeout {
+                probe.indirect = true;
+                expired.push((*seq, probe.target.clone(), true));
+            } else if waited >= self.config.protocol_period {
+                expired.push((*seq, probe.target.clone(), false));
+            }
+        }
+        for (seq, target, escalate) in expired {
+            if escalate {
+                let mut helpers: Vec<String> =
+                    self.peers().into_iter().filter(|p| p != &target).collect();
+                helpers.shuffle(&mut rand::thread_rng());
+                for helper in helpers.into_iter().take(self.config.indirect_probes) {
+                    let updates = self.piggyback();
+                    Message::new(
+                        self.node.clone(),
+                        helper,
+                        id,
+                        MembershipPayload::PingReq {
+                            seq,
+                            target: target.clone(),
+                            updates,
+                        },
+                    )
+                    .send_self(&mut *output)
+                    .context("send membership ping-req")?;
+                }
+            } else if let Some(probe) = self.probes.remove(&seq) {
+                if probe.requester.is_none() {
+                    self.suspect(&target);
+                }
+            }
+        }
+
+        //suspects that were never refuted are declared dead
+        let dead: Vec<(String, usize)> = self
+            .members
+            .iter()
+            .filter(|(_, m)| {
+                m.state == MemberState::Suspect
+                    && now.duration_since(m.since) >= self.config.suspect_timeout
+            })
+            .map(|(n, m)| (n.clone(), m.incarnation))
+            .collect();
+        for (node, incarnation) in dead {
+            self.apply(Update {
+                node,
+                state: MemberState::Dead,
+                incarnation,
+            });
+        }
+
+        let due = self
+            .last_probe
+            .is_none_or(|t| now.duration_since(t) >= self.config.protocol_period);
+        if due {
+            self.last_probe = Some(now);
+            if let Some(target) = self.next_target() {
+                self.send_ping(target, id, output)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn handle(
+        &mut self,
+        input: Message<MembershipPayload>,
+        id: &mut usize,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        let src = input.src.clone();
+        match input.body.payload {
+            MembershipPayload::Ping { seq, updates } => {
+                self.merge(updates);
+                self.nudge(&src);
+                let updates = self.piggyback();
+                Message::new(
+                    self.node.clone(),
+                    src,
+                    id,
+                    MembershipPayload::PingAck { seq, updates },
+                )
+                .send_self(&mut *output)
+                .context("send membership ack")?;
+            }
+
+            MembershipPayload::PingAck { seq, updates } => {
+                self.merge(updates);
+                self.nudge(&src);
+                let Some(probe) = self.probes.remove(&seq) else {
+                    return Ok(());
+                };
+                //an ack via a helper is as good as a direct one
+                if let Some((requester, req_seq)) = probe.requester {
+                    let updates = self.piggyback();
+                    Message::new(
+                        self.node.clone(),
+                        requester,
+                        id,
+                        MembershipPayload::PingAck {
+                            seq: req_seq,
+                            updates,
+                        },
+                    )
+                    .send_self(&mut *output)
+                    .context("forward indirect membership ack")?;
+                }
+            }
+
+            MembershipPayload::PingReq {
+                seq,
+                target,
+                updates,
+            } => {
+                self.merge(updates);
+                let own_seq = self.next_seq();
+                self.probes.insert(
+                    own_seq,
+                    Probe {
+                        target: target.clone(),
+                        started: Instant::now(),
+                        indirect: true,
+                        requester: Some((src, seq)),
+                    },
+                );
+                let updates = self.piggyback();
+                Message::new(
+                    self.node.clone(),
+                    target,
+                    id,
+                    MembershipPayload::Ping {
+                        seq: own_seq,
+                        updates,
+                    },
+                )
+                .send_self(&mut *output)
+                .context("send indirect membership ping")?;
+            }
+
+            MembershipPayload::Join => {
+                let incarnation = self.members.get(&src).map_or(0, |m| m.incarnation + 1);
+                self.apply(Update {
+                    node: src.clone(),
+                    state: MemberState::Alive,
+                    incarnation,
+                });
+                let mut members: Vec<Update> = self
+                    .members
+                    .iter()
+                    .map(|(n, m)| Update {
+                        node: n.clone(),
+                        state: m.state,
+                        incarnation: m.incarnation,
+                    })
+                    .collect();
+                members.push(self.own_update());
+                let mut response = input.derive_response(Some(id));
+                response.body.payload = MembershipPayload::JoinOk { members };
+                response
+                    .send_self(&mut *output)
+                    .context("respond to membership join")?;
+            }
+
+            MembershipPayload::JoinOk { members } => self.merge(members),
+        }
+        Ok(())
+    }
+
+    fn send_ping(
+        &mut self,
+        target: String,
+        id: &mut usize,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        let seq = self.next_seq();
+        self.probes.insert(
+            seq,
+            Probe {
+                target: target.clone(),
+                started: Instant::now(),
+                indirect: false,
+                requester: None,
+            },
+        );
+        let updates = self.piggyback();
+        Message::new(
+            self.node.clone(),
+            target,
+            id,
+            MembershipPayload::Ping { seq, updates },
+        )
+        .send_self(&mut *output)
+        .context("send membership ping")
+    }
+
+    fn next_seq(&mut self) -> usize {
+        self.next_seq += 1;
+        self.next_seq
+    }
+
+    //round-robin over a shuffled list so every member is probed within a bounded time.
+    //dead members stay in the rotation so both sides of a healed partition find each other
+    fn next_target(&mut self) -> Option<String> {
+        loop {
+            let Some(target) = self.probe_order.pop() else {
+                let mut order: Vec<String> = self
+                    .members
+                    .iter()
+                    .filter(|(_, m)| m.state != MemberState::Left)
+                    .map(|(n, _)| n.clone())
+                    .collect();
+                if order.is_empty() {
+                    return None;
+                }
+                order.shuffle(&mut rand::thread_rng());
+                self.probe_order = order;
+                continue;
+            };
+            if self
+                .state_of(&target)
+                .is_some_and(|state| state != MemberState::Left)
+            {
+                return Some(target);
+            }
+        }
+    }
+
+    //a node we consider dead is talking to us: make sure it hears so and can refute
+    fn nudge(&mut self, src: &str) {
+        let Some(member) = self.members.get(src) else {
+            return;
+        };
+        if member.state == MemberState::Dead {
+            let update = Update {
+                node: src.to_string(),
+                state: MemberState::Dead,
+                incarnation: member.incarnation,
+            };
+            if !self.gossip.iter().any(|(u, _)| u == &update) {
+                self.enqueue(update);
+            }
+        }
+    }
+
+    fn own_update(&self) -> Update {
+        Update {
+            node: self.node.clone(),
+            state: MemberState::Alive,
+            incarnation: self.incarnation,
+        }
+    }
+
+    fn suspect(&mut self, node: &str) {
+        let Some(member) = self.members.get(node) else {
+            return;
+        };
+        if member.state != MemberState::Alive {
+            return;
+        }
+        let incarnation = member.incarnation;
+        self.apply(Update {
+            node: node.to_string(),
+            state: MemberState::Suspect,
+            incarnation,
+        });
+    }
+
+    fn merge(&mut self, updates: Vec<Update>) {
+        for update in updates {
+            self.apply(update);
+        }
+    }
+
+    //SWIM precedence: higher incarnation wins, suspect beats alive at equal incarnation,
+    //dead and left are final for that incarnation and only a rejoin (alive, higher) undoes them
+    fn apply(&mut self, update: Update) {
+        if update.node == self.node {
+            match update.state {
+                //refute suspicion about ourselves with a fresh incarnation
+                MemberState::Suspect | MemberState::Dead
+                    if update.incarnation >= self.incarnation =>
+                {
+                    self.incarnation = update.incarnation + 1;
+                    self.enqueue(self.own_update());
+                }
+                //a seed admitted us under a newer incarnation, e.g. after a restart
+                MemberState::Alive if update.incarnation > self.incarnation => {
+                    self.incarnation = update.incarnation;
+                }
+                _ => {}
+            }
+            return;
+        }
+
+        let now = Instant::now();
+        let changed = match self.members.get_mut(&update.node) {
+            None => {
+                self.members.insert(
+                    update.node.clone(),
+                    Member {
+                        state: update.state,
+                        incarnation: update.incarnation,
+                        since: now,
+                    },
+                );
+                true
+            }
+            Some(member) => {
+                let overrides = match (member.state, update.state) {
+                    (MemberState::Dead | MemberState::Left, MemberState::Alive) => {
+                        update.incarnation > member.incarnation
+                    }
+                    (MemberState::Dead | MemberState::Left, _) => false,
+                    (_, MemberState::Dead | MemberState::Left) => true,
+                    (MemberState::Alive, MemberState::Suspect) => {
+                        update.incarnation >= member.incarnation
+                    }
+                    _ => update.incarnation > member.incarnation,
+                };
+                if overrides {
+                    member.state = update.state;
+                    member.incarnation = update.incarnation;
+                    member.since = now;
+                }
+                overrides
+            }
+        };
+        if changed {
+            self.version += 1;
+            self.enqueue(update);
+        }
+    }
+
+    fn enqueue(&mut self, update: Update) {
+        //a newer fact about a node replaces anything still queued for it
+        self.gossip.retain(|(u, _)| u.node != update.node);
+        let n = self.members.len() + 1;
+        let transmissions =
+            self.config.retransmit_mult * (usize::BITS - n.leading_zeros()) as usize;
+        self.gossip.push((update, transmissions.max(1)));
+    }
+
+    //take the least-transmitted updates to piggyback on an outgoing message
+    fn piggyback(&mut self) -> Vec<Update> {
+        self.gossip
+            .sort_by_key(|(_, left)| std::cmp::Reverse(*left));
+        let updates: Vec<Update> = self
+            .gossip
+            .iter_mut()
+            .take(self.config.max_piggyback)
+            .map(|(u, left)| {
+                *left -= 1;
+                u.clone()
+            })
+            .collect();
+        self.gossip.retain(|(_, left)| *left > 0);
+        updates
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashSet, VecDeque};
+
+    use super::*;
+
+    //every node of an in-process cluster, stepped a tick at a time. timeouts are zero
+    //so a probe escalates on the tick after it's sent and gives up on the one after
+    struct Sim {
+        nodes: Vec<Membership>,
+        queue: VecDeque<Message<MembershipPayload>>,
+        blocked: HashSet<(String, String)>,
+        delivered: Vec<Message<MembershipPayload>>,
+        id: usize,
+    }
+
+    impl Sim {
+        //nodes are named n0..n{n-1}
+        fn new(n: usize, suspect_timeout: Duration) -> Self {
+            let names: Vec<String> = (0..n).map(|i| format!("n{i}")).collect();
+            let config = MembershipConfig {
+                protocol_period: Duration::ZERO,
+                ping_timeout: Duration::ZERO,
+                suspect_timeout,
+                ..MembershipConfig::default()
+            };
+            let nodes = names
+                .iter()
+                .map(|name| Membership::new(name.clone(), names.clone(), config.clone()))
+                .collect();
+            Self {
+                nodes,
+                queue: VecDeque::new(),
+                blocked: HashSet::new(),
+                delivered: Vec::new(),
+                id: 0,
+            }
+        }
+
+        fn collect(&mut self, output: Vec<u8>) {
+            for line in output.split(|b| *b == b'\n').filter(|l| !l.is_empty()) {
+                let msg = serde_json::from_slice(line).expect("membership message");
+                self.queue.push_back(msg);
+            }
+        }
+
+        fn deliver(&mut self) {
+            while let Some(msg) = self.queue.pop_front() {
+                if self.blocked.contains(&(msg.src.clone(), msg.dest.clone())) {
+                    continue;
+                }
+                self.delivered.push(msg.clone());
+                let i = msg.dest[1..].parse::<usize>().unwrap();
+                let mut output = Vec::new();
+                self.nodes[i]
+                    .handle(msg, &mut self.id, &mut output)
+                    .unwrap();
+                self.collect(output);
+            }
+        }
+
+        //one tick on every node, then deliver until nothing is in flight
+        fn round(&mut self) {
+            for i in 0..self.nodes.len() {
+                let mut output = Vec::new();
+                self.nodes[i].tick(&mut self.id, &mut output).unwrap();
+                self.collect(output);
+            }
+            self.deliver();
+        }
+
+        fn run(&mut self, rounds: usize) {
+            for _ in 0..rounds {
+                self.round();
+            }
+        }
+
+        //drop everything between a and b, both directions
+        fn cut(&mut self, a: usize, b: usize) {
+            self.blocked.insert((format!("n{a}"), format!("n{b}")));
+            self.blocked.insert((format!("n{b}"), format!("n{a}")));
+        }
+
+        fn isolate(&mut self, i: usize) {
+            for other in 0..self.nodes.len() {
+                if other != i {
+                    self.cut(i, other);
+                }
+            }
+        }
+
+        fn state(&self, i: usize, of: usize) -> Option<MemberState> {
+            self.nodes[i].state_of(&format!("n{of}"))
+        }
+
+        //let the node at `i` do something outside a tick, then deliver what it sent
+        fn with(&mut self, i: usize, f: impl FnOnce(&mut Membership, &mut usize, &mut Vec<u8>)) {
+            let mut output = Vec::new();
+            f(&mut self.nodes[i], &mut self.id, &mut output);
+            self.collect(output);
+            self.deliver();
+        }
+    }
+
+    //enough rounds for every node to probe every other one a few times
+    const ROUNDS: usize = 20;
+
+    #[test]
+    fn unreachable_member_is_suspected_then_declared_dead() {
+        let mut sim = Sim::new(3, Duration::from_secs(3600));
+        sim.run(ROUNDS);
+        assert_eq!(sim.nodes[0].members(), ["n0", "n1", "n2"]);
+
+        sim.isolate(2);
+        sim.run(ROUNDS);
+        for i in 0..2 {
+            assert_eq!(sim.state(i, 2), Some(MemberState::Suspect));
+        }
+        //a suspect is still a member, it may yet refute
+        assert_eq!(sim.nodes[0].members(), ["n0", "n1", "n2"]);
+
+        for node in &mut sim.nodes {
+            node.config.suspect_timeout = Duration::ZERO;
+        }
+        let version = sim.nodes[0].version();
+        sim.round();
+        for i in 0..2 {
+            assert_eq!(sim.state(i, 2), Some(MemberState::Dead));
+        }
+        assert_eq!(sim.nodes[0].members(), ["n0", "n1"]);
+        assert!(sim.nodes[0].version() > version);
+    }
+
+    #[test]
+    fn member_reachable_through_others_stays_alive() {
+        let mut sim = Sim::new(3, Duration::ZERO);
+        sim.cut(0, 2);
+        sim.run(ROUNDS);
+        assert_eq!(sim.state(0, 2), Some(MemberState::Alive));
+        assert_eq!(sim.state(2, 0), Some(MemberState::Alive));
+        //n1 did the probing for them, and passed the acks back
+        let relayed = |src: &str, dest: &str| {
+            sim.delivered.iter().any(|msg| {
+                msg.src == src
+                    && msg.dest == dest
+                    && matches!(msg.body.payload, MembershipPayload::PingReq { .. })
+            })
+        };
+        assert!(relayed("n0", "n1"));
+        assert!(relayed("n2", "n1"));
+    }
+
+    #[test]
+    fn member_that_left_can_rejoin() {
+        let mut sim = Sim::new(3, Duration::ZERO);
+        sim.run(ROUNDS);
+        sim.with(2, |node, id, output| node.leave(id, output).unwrap());
+        sim.run(ROUNDS);
+        for i in 0..2 {
+            assert_eq!(sim.state(i, 2), Some(MemberState::Left));
+            assert_eq!(sim.nodes[i].members(), ["n0", "n1"]);
+        }
+
+        sim.with(2, |node, id, output| node.join("n0", id, output).unwrap());
+        sim.run(ROUNDS);
+        for i in 0..2 {
+            assert_eq!(sim.state(i, 2), Some(MemberState::Alive));
+            assert_eq!(sim.nodes[i].members(), ["n0", "n1", "n2"]);
+        }
+        //admitted under an incarnation newer than the one it left with
+        assert!(sim.nodes[0].members["n2"].incarnation > 1);
+    }
+
+    #[test]
+    fn dead_member_refutes_once_the_partition_heals() {
+        let mut sim = Sim::new(3, Duration::ZERO);
+        sim.isolate(2);
+        sim.run(ROUNDS);
+        assert_eq!(sim.state(0, 2), Some(MemberState::Dead));
+        assert_eq!(sim.state(2, 0), Some(MemberState::Dead));
+
+        //dead members are still probed, so each side hears the other is back
+        sim.blocked.clear();
+        sim.run(ROUNDS);
+        for i in 0..3 {
+            assert_eq!(sim.nodes[i].members(), ["n0", "n1", "n2"]);
+        }
+    }
+}
@@ -0,0 +1,132 @@
+/*
+    Multi-version store: every write to a key is kept under its version
+    instead of overwriting the last one, so a reader can ask for a key as of
+    some version and concurrent writers each keep their own entry. The newest
+    version is the current value. gc drops history nobody can ask for anymore,
+    keeping for every key the newest version at or below the horizon.
+*/
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::Hash,
+};
+
+#[derive(Debug, Clone)]
+pub struct MvccStore<K, T, V> {
+    versions: HashMap<K, BTreeMap<T, V>>,
+}
+
+impl<K, T, V> Default for MvccStore<K, T, V> {
+    fn default() -> Self {
+        Self {
+            versions: HashMap::new(),
+        }
+    }
+}
+
+impl<K: Eq + Hash, T: Ord + Clone, V: PartialEq> MvccStore<K, T, V> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    //false if the store already had exactly this version and value
+    pub fn write(&mut self, key: K, version: T, value: V) -> bool {
+        let versions = self.versions.entry(key).or_default();
+        if versions.get(&version) == Some(&value) {
+            return false;
+        }
+        versions.insert(version, value);
+        true
+    }
+
+    pub fn get(&self, key: &K, version: &T) -> Option<&V> {
+        self.versions.get(key)?.get(version)
+    }
+
+    pub fn latest(&self, key: &K) -> Option<(&T, &V)> {
+        self.versions.get(key)?.last_key_value()
+    }
+
+    //the newest version strictly below `bound`, i.e. the key as a snapshot
+    //taken just before `bound` saw it
+    pub fn read_before(&self, key: &K, bound: &T) -> Option<(&T, &V)> {
+        self.versions.get(key)?.range(..bound).next_back()
+    }
+
+    //forget versions older than the newest one at or below `horizon`, reads
+    //from before the horizon can't be served afterwards
+    pub fn gc(&mut self, horizon: &T) {
+        for versions in self.versions.values_mut() {
+            let Some((base, _)) = versions.range(..=horizon).next_back() else {
+                continue;
+            };
+            let base = base.clone();
+            *versions = versions.split_off(&base);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store() -> MvccStore<&'static str, usize, usize> {
+        let mut store = MvccStore::new();
+        for (version, value) in [(1, 10), (3, 30), (5, 50)] {
+            store.write("x", version, value);
+        }
+        store
+    }
+
+    #[test]
+    fn write_keeps_every_version() {
+        let mut store = store();
+        assert_eq!(store.get(&"x", &3), Some(&30));
+        assert_eq!(store.get(&"x", &2), None);
+        assert_eq!(store.latest(&"x"), Some((&5, &50)));
+        assert_eq!(store.latest(&"y"), None);
+        //the same version and value again is not a change, a new value is
+        assert!(!store.write("x", 3, 30));
+        assert!(store.write("x", 3, 31));
+        assert_eq!(store.get(&"x", &3), Some(&31));
+    }
+
+    #[test]
+    fn read_before_sees_the_key_as_of_a_snapshot() {
+        let store = store();
+        assert_eq!(store.read_before(&"x", &1), None);
+        assert_eq!(store.read_before(&"x", &2), Some((&1, &10)));
+        //strictly below the bound
+        assert_eq!(store.read_before(&"x", &3), Some((&1, &10)));
+        assert_eq!(store.read_before(&"x", &4), Some((&3, &30)));
+        assert_eq!(store.read_before(&"x", &100), Some((&5, &50)));
+        assert_eq!(store.read_before(&"y", &100), None);
+    }
+
+    #[test]
+    fn gc_keeps_what_reads_at_or_above_the_horizon_need() {
+        let mut store = store();
+        store.write("y", 2, 20);
+        store.gc(&4);
+        //3 is the newest at or below the horizon, so a read at 4 still sees it
+        assert_eq!(store.get(&"x", &1), None);
+        assert_eq!(store.read_before(&"x", &4), Some((&3, &30)));
+        assert_eq!(store.read_before(&"x", &6), Some((&5, &50)));
+        assert_eq!(store.get(&"y", &2), Some(&20));
+
+        //above every version, only the newest of each key is left
+        store.gc(&100);
+        assert_eq!(store.get(&"x", &3), None);
+        assert_eq!(store.latest(&"x"), Some((&5, &50)));
+        assert_eq!(store.latest(&"y"), Some((&2, &20)));
+    }
+
+    #[test]
+    fn gc_below_every_version_keeps_them_all() {
+        let mut store = store();
+        store.gc(&0);
+        for (version, value) in [(1, 10), (3, 30), (5, 50)] {
+            assert_eq!(store.get(&"x", &version), Some(&value));
+        }
+    }
+}
@@ -0,0 +1,74 @@
+/*
+    Consistent hashing: keys and nodes are hashed onto a ring, a key belongs
+    to the first node clockwise from it. Each node gets several virtual points
+    so keys spread evenly and a membership change only moves a slice of them.
+*/
+
+use std::collections::BTreeMap;
+
+//points per node on the ring
+pub const DEFAULT_VNODES: usize = 64;
+
+#[derive(Debug, Clone)]
+pub struct HashRing {
+    ring: BTreeMap<u64, String>,
+    vnodes: usize,
+}
+
+impl HashRing {
+    pub fn new(nodes: &[String], vnodes: usize) -> Self {
+        let mut ring = Self {
+            ring: BTreeMap::new(),
+            vnodes,
+        };
+        ring.rebuild(nodes);
+        ring
+    }
+
+    //replace the node set, e.g. with a fresh view from the membership layer
+    pub fn rebuild(&mut self, nodes: &[String]) {
+        self.ring = nodes
+            .iter()
+            .flat_map(|node| {
+                (0..self.vnodes)
+                    .map(move |v| (hash(format!("{node}#{v}").as_bytes()), node.clone()))
+            })
+            .collect();
+    }
+
+    pub fn owner(&self, key: &str) -> Option<&str> {
+        self.owners(key, 1).into_iter().next()
+    }
+
+    //the first n distinct nodes clockwise from the key, owner first
+    pub fn owners(&self, key: &str, n: usize) -> Vec<&str> {
+        let point = hash(key.as_bytes());
+        let mut owners: Vec<&str> = Vec::new();
+        for node in self
+            .ring
+            .range(point..)
+            .chain(self.ring.range(..point))
+            .map(|(_, node)| node.as_str())
+        {
+            if owners.len() == n {
+                break;
+            }
+            if !owners.contains(&node) {
+                owners.push(node);
+            }
+        }
+        owners
+    }
+}
+
+//fnv-1a, stable across processes unlike std's randomly seeded hashers,
+//with a murmur3 finalizer so near-identical names still land far apart
+fn hash(bytes: &[u8]) -> u64 {
+    let mut h = bytes.iter().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x100000001b3)
+    });
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51afd7ed558ccd);
+    h ^= h >> 33;
+    h
+}
@@ -0,0 +1,848 @@
+/*
+    Multi-Paxos: a stable leader runs phase 1 (prepare/promise) once for all
+    open slots, then only phase 2 (accept/accepted) per command. The leader
+    holds a lease refreshed by heartbeats, acceptors won't promise a rival
+    ballot while it's valid, which keeps leadership stable under flaky links.
+    Same Replicator interface as raft, so a node can switch engines.
+    Applied slots are folded into a state machine snapshot once enough pile
+    up, a follower (or candidate) that needs compacted slots gets the snapshot.
+*/
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io::Write,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use rand::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    replication::{Applied, Replicator, StateMachine},
+    Init, Message,
+};
+
+//proposal number, ordered by round then node so ballots are unique per node
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Ballot {
+    pub round: usize,
+    pub node: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Accepted<C> {
+    pub slot: usize,
+    pub ballot: Ballot,
+    //None is a no-op filling a gap left by an earlier leader
+    pub command: Option<C>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum PaxosPayload<C> {
+    Prepare {
+        ballot: Ballot,
+        //first slot the new leader doesn't know to be chosen
+        from_slot: usize,
+    },
+    Promise {
+        ballot: Ballot,
+        ok: bool,
+        promised: Ballot,
+        accepted: Vec<Accepted<C>>,
+        //slots past from_slot this acceptor already knows are chosen
+        chosen: Vec<(usize, Option<C>)>,
+        //set when from_slot is already compacted: (last slot it covers, state)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        snapshot: Option<(usize, String)>,
+    },
+    Accept {
+        ballot: Ballot,
+        slot: usize,
+        command: Option<C>,
+    },
+    AcceptOk {
+        ballot: Ballot,
+        slot: usize,
+        ok: bool,
+        promised: Ballot,
+    },
+    Decide {
+        slot: usize,
+        command: Option<C>,
+    },
+    Heartbeat {
+        ballot: Ballot,
+    },
+    //state machine as of `slot`, for a follower missing compacted slots
+    Snapshot {
+        slot: usize,
+        data: String,
+    },
+    HeartbeatOk {
+        ballot: Ballot,
+        ok: bool,
+        promised: Ballot,
+        chosen_upto: usize,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+#[derive(Debug, Clone)]
+pub struct PaxosConfig {
+    //a follower that hears nothing from a leader for this long (randomized) runs phase 1
+    pub election_timeout: (Duration, Duration),
+    pub heartbeat_interval: Duration,
+    //how long an acceptor keeps backing the current leader after its last heartbeat
+    pub lease: Duration,
+    //max decisions re-sent to a lagging follower per heartbeat
+    pub max_batch: usize,
+    //applied slots kept before they're compacted into a snapshot
+    pub snapshot_threshold: usize,
+    //how long before a snapshot a follower hasn't confirmed is sent again
+    pub snapshot_retry: Duration,
+}
+
+impl Default for PaxosConfig {
+    fn default() -> Self {
+        Self {
+            election_timeout: (Duration::from_millis(400), Duration::from_millis(800)),
+            heartbeat_interval: Duration::from_millis(100),
+            lease: Duration::from_millis(300),
+            max_batch: 64,
+            snapshot_threshold: 1000,
+            snapshot_retry: Duration::from_millis(500),
+        }
+    }
+}
+
+//a slot the leader is waiting on a majority for
+struct Proposal<C> {
+    command: Option<C>,
+    acks: HashSet<String>,
+}
+
+pub struct Paxos<S: StateMachine> {
+    node: String,
+    peers: Vec<String>,
+    config: PaxosConfig,
+    state: S,
+
+    //acceptor
+    promised: Ballot,
+    accepted: BTreeMap<usize, Accepted<S::Command>>,
+    lease_until: Option<Instant>,
+
+    //learner
+    chosen: BTreeMap<usize, Option<S::Command>>,
+    chosen_upto: usize,
+    last_applied: usize,
+    //slots up to snapshot_slot live only in the snapshot
+    snapshot_slot: usize,
+    snapshot: Option<String>,
+
+    //proposer
+    role: Role,
+    ballot: Ballot,
+    leader: Option<String>,
+    promises: HashMap<String, Vec<Accepted<S::Command>>>,
+    next_slot: usize,
+    in_flight: BTreeMap<usize, Proposal<S::Command>>,
+    follower_chosen: HashMap<String, usize>,
+    snapshot_sent: HashMap<String, Instant>,
+    last_heartbeat: Option<Instant>,
+    election_deadline: Instant,
+}
+
+impl<S: StateMachine> Paxos<S> {
+    pub fn new(init: &Init, state: S, config: PaxosConfig) -> Self {
+        let peers = init
+            .node_ids
+            .iter()
+            .filter(|n| *n != &init.node_id)
+            .cloned()
+            .collect();
+        let election_deadline = Instant::now() + random_timeout(&config);
+        Self {
+            node: init.node_id.clone(),
+            peers,
+            config,
+            state,
+            promised: Ballot::default(),
+            accepted: BTreeMap::new(),
+            lease_until: None,
+            chosen: BTreeMap::new(),
+            chosen_upto: 0,
+            last_applied: 0,
+            snapshot_slot: 0,
+            snapshot: None,
+            role: Role::Follower,
+            ballot: Ballot::default(),
+            leader: None,
+            promises: HashMap::new(),
+            next_slot: 1,
+            in_flight: BTreeMap::new(),
+            follower_chosen: HashMap::new(),
+            snapshot_sent: HashMap::new(),
+            last_heartbeat: None,
+            election_deadline,
+        }
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    pub fn ballot(&self) -> &Ballot {
+        &self.ballot
+    }
+
+    pub fn last_applied(&self) -> usize {
+        self.last_applied
+    }
+
+    fn quorum(&self) -> usize {
+        let cluster = self.peers.len() + 1;
+        cluster / 2 + 1
+    }
+
+    fn lease_held_by_other(&self, ballot: &Ballot) -> bool {
+        let held = self.lease_until.is_some_and(|t| Instant::now() < t);
+        held && self.leader.as_deref() != Some(ballot.node.as_str())
+    }
+
+    //a higher ballot means someone else is leading, stop proposing
+    fn observe_ballot(&mut self, ballot: &Ballot) {
+        if ballot > &self.ballot && self.role != Role::Follower {
+            self.role = Role::Follower;
+            //not us anymore, or requests forwarded to the leader would come straight back
+            self.leader = Some(ballot.node.clone());
+            self.promises.clear();
+            self.in_flight.clear();
+        }
+        if ballot.round > self.ballot.round {
+            self.election_deadline = Instant::now() + random_timeout(&self.config);
+        }
+    }
+
+    fn start_election(&mut self, id: &mut usize, output: &mut impl Write) -> anyhow::Result<()> {
+        self.role = Role::Candidate;
+        self.ballot = Ballot {
+            round: self.ballot.round.max(self.promised.round) + 1,
+            node: self.node.clone(),
+        };
+        self.election_deadline = Instant::now() + random_timeout(&self.config);
+        self.promises.clear();
+        let from_slot = self.chosen_upto + 1;
+
+        //promise to ourselves
+        self.promised = self.ballot.clone();
+        let own = self
+            .accepted
+            .range(from_slot..)
+            .map(|(_, a)| a.clone())
+            .collect();
+        self.promises.insert(self.node.clone(), own);
+        if self.promises.len() >= self.quorum() {
+            return self.become_leader(id, output);
+        }
+        for peer in &self.peers {
+            Message::new(
+                self.node.clone(),
+                peer.clone(),
+                id,
+                PaxosPayload::<S::Command>::Prepare {
+                    ballot: self.ballot.clone(),
+                    from_slot,
+                },
+            )
+            .send_self(&mut *output)
+            .context(format!("send prepare to {peer}"))?;
+        }
+        Ok(())
+    }
+
+    //re-propose the highest-ballot value seen for each open slot, no-ops in the gaps
+    fn become_leader(&mut self, id: &mut usize, output: &mut impl Write) -> anyhow::Result<()> {
+        self.role = Role::Leader;
+        self.leader = Some(self.node.clone());
+        let mut recovered: BTreeMap<usize, Accepted<S::Command>> = BTreeMap::new();
+        for accepted in self.promises.drain().flat_map(|(_, a)| a) {
+            match recovered.get(&accepted.slot) {
+                Some(known) if known.ballot >= accepted.ballot => {}
+                _ => {
+                    recovered.insert(accepted.slot, accepted);
+                }
+            }
+        }
+        let last = recovered.keys().next_back().copied().unwrap_or(0);
+        //chosen may be compacted down to nothing, chosen_upto still counts
+        let last_chosen = self.chosen.keys().next_back().copied().unwrap_or(0);
+        let last_chosen = last_chosen.max(self.chosen_upto);
+        self.next_slot = last.max(last_chosen) + 1;
+        self.in_flight.clear();
+        for slot in self.chosen_upto + 1..self.next_slot {
+            if self.chosen.contains_key(&slot) {
+                continue;
+            }
+            let command = recovered.remove(&slot).and_then(|a| a.command);
+            self.send_accept(slot, command, id, output)?;
+        }
+        self.heartbeat(id, output)
+    }
+
+    fn send_accept(
+        &mut self,
+        slot: usize,
+        command: Option<S::Command>,
+        id: &mut usize,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        //accept locally first, we're one of the acceptors
+        self.accepted.insert(
+            slot,
+            Accepted {
+                slot,
+                ballot: self.ballot.clone(),
+                command: command.clone(),
+            },
+        );
+        let mut acks = HashSet::new();
+        acks.insert(self.node.clone());
+        self.in_flight.insert(
+            slot,
+            Proposal {
+                command: command.clone(),
+                acks,
+            },
+        );
+        for peer in &self.peers {
+            Message::new(
+                self.node.clone(),
+                peer.clone(),
+                id,
+                PaxosPayload::Accept {
+                    ballot: self.ballot.clone(),
+                    slot,
+                    command: command.clone(),
+                },
+            )
+            .send_self(&mut *output)
+            .context(format!("send accept to {peer}"))?;
+        }
+        self.check_chosen(slot, id, output)
+    }
+
+    fn check_chosen(
+        &mut self,
+        slot: usize,
+        id: &mut usize,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        let Some(proposal) = self.in_flight.get(&slot) else {
+            return Ok(());
+        };
+        if proposal.acks.len() < self.quorum() {
+            return Ok(());
+        }
+        let proposal = self.in_flight.remove(&slot).expect("proposal just found");
+        self.decide(slot, proposal.command.clone());
+        for peer in &self.peers {
+            Message::new(
+                self.node.clone(),
+                peer.clone(),
+                id,
+                PaxosPayload::Decide {
+                    slot,
+                    command: proposal.command.clone(),
+                },
+            )
+            .send_self(&mut *output)
+            .context(format!("send decide to {peer}"))?;
+        }
+        Ok(())
+    }
+
+    fn decide(&mut self, slot: usize, command: Option<S::Command>) {
+        //already known, and maybe compacted away
+        if slot <= self.chosen_upto {
+            return;
+        }
+        self.chosen.entry(slot).or_insert(command);
+        while self.chosen.contains_key(&(self.chosen_upto + 1)) {
+            self.chosen_upto += 1;
+        }
+        //chosen slots no longer need their accepted state
+        self.accepted = self.accepted.split_off(&(self.chosen_upto + 1));
+    }
+
+    //heartbeat doubles as lease renewal and as retransmission of anything outstanding
+    fn heartbeat(&mut self, id: &mut usize, output: &mut impl Write) -> anyhow::Result<()> {
+        self.last_heartbeat = Some(Instant::now());
+        for peer in &self.peers {
+            Message::new(
+                self.node.clone(),
+                peer.clone(),
+                id,
+                PaxosPayload::<S::Command>::Heartbeat {
+                    ballot: self.ballot.clone(),
+                },
+            )
+            .send_self(&mut *output)
+            .context(format!("send heartbeat to {peer}"))?;
+
+            for (slot, proposal) in &self.in_flight {
+                if proposal.acks.contains(peer) {
+                    continue;
+                }
+                Message::new(
+                    self.node.clone(),
+                    peer.clone(),
+                    id,
+                    PaxosPayload::Accept {
+                        ballot: self.ballot.clone(),
+                        slot: *slot,
+                        command: proposal.command.clone(),
+                    },
+                )
+                .send_self(&mut *output)
+                .context(format!("resend accept to {peer}"))?;
+            }
+
+            let known = self.follower_chosen.get(peer).copied().unwrap_or(0);
+            //what it's missing is only in the snapshot now
+            if known < self.snapshot_slot {
+                let recent = self
+                    .snapshot_sent
+                    .get(peer)
+                    .is_some_and(|t| t.elapsed() < self.config.snapshot_retry);
+                if let (false, Some(data)) = (recent, &self.snapshot) {
+                    self.snapshot_sent.insert(peer.clone(), Instant::now());
+                    Message::new(
+                        self.node.clone(),
+                        peer.clone(),
+                        id,
+                        PaxosPayload::<S::Command>::Snapshot {
+                            slot: self.snapshot_slot,
+                            data: data.clone(),
+                        },
+                    )
+                    .send_self(&mut *output)
+                    .context(format!("send snapshot to {peer}"))?;
+                }
+                continue;
+            }
+            for (slot, command) in self.chosen.range(known + 1..).take(self.config.max_batch) {
+                Message::new(
+                    self.node.clone(),
+                    peer.clone(),
+                    id,
+                    PaxosPayload::Decide {
+                        slot: *slot,
+                        command: command.clone(),
+                    },
+                )
+                .send_self(&mut *output)
+                .context(format!("resend decide to {peer}"))?;
+            }
+        }
+        Ok(())
+    }
+
+    //fold applied slots into a snapshot once enough have piled up
+    fn maybe_compact(&mut self) -> anyhow::Result<()> {
+        if self.last_applied - self.snapshot_slot < self.config.snapshot_threshold {
+            return Ok(());
+        }
+        self.snapshot = Some(self.state.snapshot().context("snapshot state machine")?);
+        self.snapshot_slot = self.last_applied;
+        self.chosen = self.chosen.split_off(&(self.last_applied + 1));
+        Ok(())
+    }
+
+    //take over a snapshot that's ahead of everything we've learned
+    fn install_snapshot(&mut self, slot: usize, data: String) -> anyhow::Result<()> {
+        if slot <= self.chosen_upto {
+            return Ok(());
+        }
+        self.state
+            .restore(&data)
+            .context("restore state machine from snapshot")?;
+        self.snapshot = Some(data);
+        self.snapshot_slot = slot;
+        self.last_applied = slot;
+        self.chosen_upto = slot;
+        self.chosen = self.chosen.split_off(&(slot + 1));
+        while self.chosen.contains_key(&(self.chosen_upto + 1)) {
+            self.chosen_upto += 1;
+        }
+        self.accepted = self.accepted.split_off(&(self.chosen_upto + 1));
+        Ok(())
+    }
+
+    fn apply_chosen(&mut self) -> anyhow::Result<Vec<Applied<S::Output>>> {
+        let mut applied = Vec::new();
+        while self.last_applied < self.chosen_upto {
+            self.last_applied += 1;
+            let command = self.chosen[&self.last_applied].clone();
+            applied.push(Applied {
+                index: self.last_applied,
+                output: command.map(|c| self.state.apply(c)),
+            });
+        }
+        self.maybe_compact()?;
+        Ok(applied)
+    }
+}
+
+impl<S: StateMachine> Replicator<S> for Paxos<S> {
+    type Payload = PaxosPayload<S::Command>;
+
+    fn from_init(init: &Init, state: S) -> Self {
+        Self::new(init, state, PaxosConfig::default())
+    }
+
+    fn is_leader(&self) -> bool {
+        self.role == Role::Leader
+    }
+
+    fn leader(&self) -> Option<&str> {
+        match self.role {
+            Role::Leader => Some(&self.node),
+            _ => self.leader.as_deref(),
+        }
+    }
+
+    fn state(&self) -> &S {
+        &self.state
+    }
+
+    fn propose(
+        &mut self,
+        command: S::Command,
+        id: &mut usize,
+        output: &mut impl Write,
+    ) -> anyhow::Result<Option<usize>> {
+        if self.role != Role::Leader {
+            return Ok(None);
+        }
+        let slot = self.next_slot;
+        self.next_slot += 1;
+        self.send_accept(slot, Some(command), id, output)?;
+        Ok(Some(slot))
+    }
+
+    fn tick(
+        &mut self,
+        id: &mut usize,
+        output: &mut impl Write,
+    ) -> anyhow::Result<Vec<Applied<S::Output>>> {
+        let now = Instant::now();
+        match self.role {
+            Role::Leader => {
+                let due = self
+                    .last_heartbeat
+                    .is_none_or(|t| now.duration_since(t) >= self.config.heartbeat_interval);
+                if due {
+                    self.heartbeat(id, output)?;
+                }
+            }
+            Role::Follower | Role::Candidate => {
+                if now >= self.election_deadline {
+                    self.start_election(id, output)?;
+                }
+            }
+        }
+        self.apply_chosen()
+    }
+
+    fn handle(
+        &mut self,
+        input: Message<Self::Payload>,
+        id: &mut usize,
+        output: &mut impl Write,
+    ) -> anyhow::Result<Vec<Applied<S::Output>>> {
+        let src = input.src;
+        match input.body.payload {
+            PaxosPayload::Prepare { ballot, from_slot } => {
+                self.observe_ballot(&ballot);
+                let ok = ballot >= self.promised && !self.lease_held_by_other(&ballot);
+                let (accepted, chosen, snapshot) = if ok {
+                    self.promised = ballot.clone();
+                    //the candidate must not fill compacted slots with no-ops
+                    let snapshot = self
+                        .snapshot
+                        .clone()
+                        .filter(|_| from_slot <= self.snapshot_slot)
+                        .map(|data| (self.snapshot_slot, data));
+                    (
+                        self.accepted
+                            .range(from_slot..)
+                            .map(|(_, a)| a.clone())
+                            .collect(),
+                        //accepted state is dropped once chosen, so report those outright
+                        self.chosen
+                            .range(from_slot..)
+                            .map(|(slot, c)| (*slot, c.clone()))
+                            .collect(),
+                        snapshot,
+                    )
+                } else {
+                    (Vec::new(), Vec::new(), None)
+                };
+                Message::new(
+                    self.node.clone(),
+                    src,
+                    id,
+                    PaxosPayload::Promise {
+                        ballot,
+                        ok,
+                        promised: self.promised.clone(),
+                        accepted,
+                        chosen,
+                        snapshot,
+                    },
+                )
+                .send_self(&mut *output)
+                .context("respond to prepare")?;
+            }
+
+            PaxosPayload::Promise {
+                ballot,
+                ok,
+                promised,
+                accepted,
+                chosen,
+                snapshot,
+            } => {
+                self.observe_ballot(&promised);
+                if let Some((slot, data)) = snapshot {
+                    self.install_snapshot(slot, data)?;
+                }
+                for (slot, command) in chosen {
+                    self.decide(slot, command);
+                }
+                if ok && self.role == Role::Candidate && ballot == self.ballot {
+                    self.promises.insert(src, accepted);
+                    if self.promises.len() >= self.quorum() {
+                        self.become_leader(id, output)?;
+                    }
+                }
+            }
+
+            PaxosPayload::Accept {
+                ballot,
+                slot,
+                command,
+            } => {
+                self.observe_ballot(&ballot);
+                let ok = ballot >= self.promised;
+                if ok {
+                    self.promised = ballot.clone();
+                    self.leader = Some(ballot.node.clone());
+                    self.lease_until = Some(Instant::now() + self.config.lease);
+                    self.election_deadline = Instant::now() + random_timeout(&self.config);
+                    if slot > self.chosen_upto {
+                        self.accepted.insert(
+                            slot,
+                            Accepted {
+                                slot,
+                                ballot: ballot.clone(),
+                                command,
+                            },
+                        );
+                    }
+                }
+                Message::new(
+                    self.node.clone(),
+                    src,
+                    id,
+                    PaxosPayload::<S::Command>::AcceptOk {
+                        ballot,
+                        slot,
+                        ok,
+                        promised: self.promised.clone(),
+                    },
+                )
+                .send_self(&mut *output)
+                .context("respond to accept")?;
+            }
+
+            PaxosPayload::AcceptOk {
+                ballot,
+                slot,
+                ok,
+                promised,
+            } => {
+                self.observe_ballot(&promised);
+                if ok && self.role == Role::Leader && ballot == self.ballot {
+                    if let Some(proposal) = self.in_flight.get_mut(&slot) {
+                        proposal.acks.insert(src);
+                    }
+                    self.check_chosen(slot, id, output)?;
+                }
+            }
+
+            PaxosPayload::Decide { slot, command } => self.decide(slot, command),
+
+            PaxosPayload::Snapshot { slot, data } => self.install_snapshot(slot, data)?,
+
+            PaxosPayload::Heartbeat { ballot } => {
+                self.observe_ballot(&ballot);
+                let ok = ballot >= self.promised;
+                if ok {
+                    self.promised = ballot.clone();
+                    self.leader = Some(ballot.node.clone());
+                    self.lease_until = Some(Instant::now() + self.config.lease);
+                    self.election_deadline = Instant::now() + random_timeout(&self.config);
+                }
+                Message::new(
+                    self.node.clone(),
+                    src,
+                    id,
+                    PaxosPayload::<S::Command>::HeartbeatOk {
+                        ballot,
+                        ok,
+                        promised: self.promised.clone(),
+                        chosen_upto: self.chosen_upto,
+                    },
+                )
+                .send_self(&mut *output)
+                .context("respond to heartbeat")?;
+            }
+
+            PaxosPayload::HeartbeatOk {
+                ballot,
+                ok,
+                promised,
+                chosen_upto,
+            } => {
+                self.observe_ballot(&promised);
+                if ok && self.role == Role::Leader && ballot == self.ballot {
+                    self.follower_chosen.insert(src, chosen_upto);
+                }
+            }
+        }
+        self.apply_chosen()
+    }
+}
+
+fn random_timeout(config: &PaxosConfig) -> Duration {
+    let (low, high) = config.election_timeout;
+    rand::thread_rng().gen_range(low..=high)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::replication::sim::{Appends, Sim};
+
+    use super::*;
+
+    //elections only happen when a test forces one, every tick is a heartbeat and
+    //there's no lease, so a forced election always goes through
+    fn sim(n: usize, config: PaxosConfig) -> Sim<Paxos<Appends>> {
+        let config = PaxosConfig {
+            election_timeout: (Duration::from_secs(3600), Duration::from_secs(3600)),
+            heartbeat_interval: Duration::ZERO,
+            lease: Duration::ZERO,
+            ..config
+        };
+        Sim::new(n, |init| {
+            Paxos::new(init, Appends::default(), config.clone())
+        })
+    }
+
+    fn elect(sim: &mut Sim<Paxos<Appends>>, i: usize) {
+        sim.nodes[i].election_deadline = Instant::now();
+        sim.run(3);
+        assert!(sim.nodes[i].is_leader(), "n{i} should have won");
+    }
+
+    #[test]
+    fn deposed_leader_points_at_the_new_one() {
+        let mut sim = sim(3, PaxosConfig::default());
+        elect(&mut sim, 0);
+        sim.partition(&[0]);
+        elect(&mut sim, 1);
+        assert!(sim.nodes[0].is_leader());
+
+        sim.blocked.clear();
+        sim.run(2);
+        assert_eq!(sim.nodes[0].role(), Role::Follower);
+        assert_eq!(sim.nodes[0].leader(), Some("n1"));
+    }
+
+    #[test]
+    fn chosen_slots_are_compacted_and_shipped_as_a_snapshot() {
+        let mut sim = sim(
+            3,
+            PaxosConfig {
+                snapshot_threshold: 5,
+                snapshot_retry: Duration::ZERO,
+                ..PaxosConfig::default()
+            },
+        );
+        elect(&mut sim, 0);
+        sim.partition(&[2]);
+        for command in 0..20 {
+            sim.propose(0, command);
+        }
+        sim.run(3);
+        assert!(sim.nodes[0].snapshot_slot > 0);
+        assert!(sim.nodes[0].chosen.len() < 5);
+
+        sim.blocked.clear();
+        sim.run(3);
+        let expected: Vec<u64> = (0..20).collect();
+        assert_eq!(sim.values(2), expected);
+
+        //a leader whose own chosen slots are all compacted still proposes past them
+        elect(&mut sim, 2);
+        sim.propose(2, 20);
+        sim.run(3);
+        let expected: Vec<u64> = (0..=20).collect();
+        for i in 0..3 {
+            assert_eq!(sim.values(i), expected);
+        }
+    }
+
+    #[test]
+    fn lagging_candidate_learns_compacted_slots_from_promises() {
+        let mut sim = sim(
+            3,
+            PaxosConfig {
+                snapshot_threshold: 5,
+                snapshot_retry: Duration::ZERO,
+                ..PaxosConfig::default()
+            },
+        );
+        elect(&mut sim, 0);
+        sim.partition(&[2]);
+        for command in 0..20 {
+            sim.propose(0, command);
+        }
+        sim.run(3);
+
+        //n2 never heard of any of it and runs for leader straight away
+        sim.blocked.clear();
+        sim.blocked.insert(("n0".to_string(), "n2".to_string()));
+        sim.nodes[2].election_deadline = Instant::now();
+        sim.run(3);
+        assert!(sim.nodes[2].is_leader());
+        sim.blocked.clear();
+        sim.propose(2, 20);
+        sim.run(3);
+        let expected: Vec<u64> = (0..=20).collect();
+        for i in 0..3 {
+            assert_eq!(sim.values(i), expected);
+        }
+    }
+}
@@ -0,0 +1,965 @@
+/*
+    Raft consensus: leader election, log replication and commit tracking,
+    applying committed commands to a replicated state machine.
+    Driven by the node: feed it raft messages with `handle` and call `tick`
+    periodically from an injected event, just like the gossip timers.
+    The log is compacted into state machine snapshots, lagging followers are
+    caught up with install_snapshot sent in bounded chunks, one chunk in
+    flight per follower: the next goes out when the last is acked, and a
+    chunk nobody acked is resent after snapshot_retry.
+*/
+
+use std::{
+    collections::HashMap,
+    io::Write,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use rand::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    replication::{Applied, Replicator},
+    Init, Message,
+};
+
+pub use crate::replication::StateMachine;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry<C> {
+    pub term: usize,
+    //None is the no-op a new leader appends to commit entries from earlier terms
+    pub command: Option<C>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum RaftPayload<C> {
+    RequestVote {
+        term: usize,
+        last_log_index: usize,
+        last_log_term: usize,
+    },
+    RequestVoteOk {
+        term: usize,
+        vote_granted: bool,
+    },
+    AppendEntries {
+        term: usize,
+        prev_log_index: usize,
+        prev_log_term: usize,
+        entries: Vec<Entry<C>>,
+        leader_commit: usize,
+    },
+    AppendEntriesOk {
+        term: usize,
+        success: bool,
+        //on success the follower's last matching index, on failure a hint for next_index
+        match_index: usize,
+    },
+    InstallSnapshot {
+        term: usize,
+        last_included_index: usize,
+        last_included_term: usize,
+        //byte offset of this chunk in the snapshot
+        offset: usize,
+        data: String,
+        done: bool,
+    },
+    InstallSnapshotOk {
+        term: usize,
+        last_included_index: usize,
+        //next byte the follower expects, or the snapshot length once installed
+        offset: usize,
+        done: bool,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+#[derive(Debug, Clone)]
+pub struct RaftConfig {
+    //election timeout is picked uniformly from this range
+    pub election_timeout: (Duration, Duration),
+    pub heartbeat_interval: Duration,
+    //max entries in a single append_entries
+    pub max_batch: usize,
+    //applied entries kept in the log before it's compacted into a snapshot
+    pub snapshot_threshold: usize,
+    //max snapshot bytes in a single install_snapshot
+    pub snapshot_chunk: usize,
+    //how long an unacked snapshot chunk waits before it's resent
+    pub snapshot_retry: Duration,
+}
+
+impl Default for RaftConfig {
+    fn default() -> Self {
+        Self {
+            election_timeout: (Duration::from_millis(400), Duration::from_millis(800)),
+            heartbeat_interval: Duration::from_millis(100),
+            max_batch: 64,
+            snapshot_threshold: 1000,
+            snapshot_chunk: 16 * 1024,
+            snapshot_retry: Duration::from_millis(500),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Transfer {
+    //snapshot being sent, by its last included index
+    index: usize,
+    //start of the chunk in flight
+    offset: usize,
+    sent: Instant,
+}
+
+pub struct Raft<S: StateMachine> {
+    node: String,
+    peers: Vec<String>,
+    config: RaftConfig,
+    state: S,
+
+    role: Role,
+    term: usize,
+    voted_for: Option<String>,
+    leader: Option<String>,
+    votes: Vec<String>,
+
+    //log[i] holds index snapshot_index + i + 1
+    log: Vec<Entry<S::Command>>,
+    commit_index: usize,
+    last_applied: usize,
+
+    //everything up to snapshot_index lives only in the snapshot
+    snapshot_index: usize,
+    snapshot_term: usize,
+    snapshot: Option<String>,
+    //follower: snapshot being received in chunks, keyed by its last included index
+    incoming: Option<(usize, String)>,
+
+    //leader only
+    next_index: HashMap<String, usize>,
+    match_index: HashMap<String, usize>,
+    //snapshot chunk in flight per follower
+    transfers: HashMap<String, Transfer>,
+
+    election_deadline: Instant,
+    last_heartbeat: Option<Instant>,
+}
+
+impl<S: StateMachine> Raft<S> {
+    pub fn new(init: &Init, state: S, config: RaftConfig) -> Self {
+        let peers = init
+            .node_ids
+            .iter()
+            .filter(|n| *n != &init.node_id)
+            .cloned()
+            .collect();
+        let election_deadline = Instant::now() + random_timeout(&config);
+        Self {
+            node: init.node_id.clone(),
+            peers,
+            config,
+            state,
+            role: Role::Follower,
+            term: 0,
+            voted_for: None,
+            leader: None,
+            votes: Vec::new(),
+            log: Vec::new(),
+            commit_index: 0,
+            last_applied: 0,
+            snapshot_index: 0,
+            snapshot_term: 0,
+            snapshot: None,
+            incoming: None,
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            transfers: HashMap::new(),
+            election_deadline,
+            last_heartbeat: None,
+        }
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    pub fn term(&self) -> usize {
+        self.term
+    }
+
+    pub fn commit_index(&self) -> usize {
+        self.commit_index
+    }
+
+    //entries up to here are reflected in state(), including ones restored from a snapshot
+    pub fn last_applied(&self) -> usize {
+        self.last_applied
+    }
+
+    //fold applied entries into a snapshot once the log grows past the threshold
+    fn maybe_compact(&mut self) -> anyhow::Result<()> {
+        if self.last_applied - self.snapshot_index < self.config.snapshot_threshold {
+            return Ok(());
+        }
+        let snapshot = self.state.snapshot().context("snapshot state machine")?;
+        let term = self.term_at(self.last_applied);
+        self.log.drain(..self.last_applied - self.snapshot_index);
+        self.snapshot_index = self.last_applied;
+        self.snapshot_term = term;
+        self.snapshot = Some(snapshot);
+        Ok(())
+    }
+
+    fn quorum(&self) -> usize {
+        let cluster = self.peers.len() + 1;
+        cluster / 2 + 1
+    }
+
+    fn last_index(&self) -> usize {
+        self.snapshot_index + self.log.len()
+    }
+
+    fn last_term(&self) -> usize {
+        self.term_at(self.last_index())
+    }
+
+    //terms below the snapshot are gone, they're only asked for when already committed
+    fn term_at(&self, index: usize) -> usize {
+        if index <= self.snapshot_index {
+            return self.snapshot_term;
+        }
+        self.log
+            .get(index - self.snapshot_index - 1)
+            .map_or(0, |e| e.term)
+    }
+
+    fn reset_election_timer(&mut self) {
+        self.election_deadline = Instant::now() + random_timeout(&self.config);
+    }
+
+    //any newer term seen demotes us to follower
+    fn observe_term(&mut self, term: usize) {
+        if term > self.term {
+            self.term = term;
+            self.role = Role::Follower;
+            self.voted_for = None;
+            self.leader = None;
+        }
+    }
+
+    fn start_election(&mut self, id: &mut usize, output: &mut impl Write) -> anyhow::Result<()> {
+        self.term += 1;
+        self.role = Role::Candidate;
+        self.voted_for = Some(self.node.clone());
+        self.leader = None;
+        self.votes = vec![self.node.clone()];
+        self.reset_election_timer();
+        if self.votes.len() >= self.quorum() {
+            return self.become_leader(id, output);
+        }
+        for peer in &self.peers {
+            Message::new(
+                self.node.clone(),
+                peer.clone(),
+                id,
+                RaftPayload::<S::Command>::RequestVote {
+                    term: self.term,
+                    last_log_index: self.last_index(),
+                    last_log_term: self.last_term(),
+                },
+            )
+            .send_self(&mut *output)
+            .context(format!("request vote from {peer}"))?;
+        }
+        Ok(())
+    }
+
+    fn become_leader(&mut self, id: &mut usize, output: &mut impl Write) -> anyhow::Result<()> {
+        self.role = Role::Leader;
+        self.leader = Some(self.node.clone());
+        let next = self.last_index() + 1;
+        self.next_index = self.peers.iter().map(|p| (p.clone(), next)).collect();
+        self.match_index = self.peers.iter().map(|p| (p.clone(), 0)).collect();
+        self.transfers.clear();
+        //entries from earlier terms only commit once something from this term does
+        self.log.push(Entry {
+            term: self.term,
+            command: None,
+        });
+        if self.peers.is_empty() {
+            self.advance_commit();
+        }
+        self.replicate(id, output)
+    }
+
+    //send every follower whatever it is missing, doubles as the heartbeat
+    fn replicate(&mut self, id: &mut usize, output: &mut impl Write) -> anyhow::Result<()> {
+        self.last_heartbeat = Some(Instant::now());
+        for peer in self.peers.clone() {
+            let next = self.next_index.get(&peer).copied().unwrap_or(1);
+            //the entries this follower needs have been compacted away. the acks drive
+            //the transfer, here it's only started or resent when a chunk went unacked
+            if next <= self.snapshot_index {
+                let offset = match self.transfers.get(&peer) {
+                    Some(t) if t.index == self.snapshot_index => {
+                        if t.sent.elapsed() < self.config.snapshot_retry {
+                            continue;
+                        }
+                        t.offset
+                    }
+                    _ => 0,
+                };
+                self.send_snapshot(&peer, offset, id, output)?;
+                continue;
+            }
+            let prev_log_index = next - 1;
+            let entries: Vec<_> = self
+                .log
+                .iter()
+                .skip(prev_log_index - self.snapshot_index)
+                .take(self.config.max_batch)
+                .cloned()
+                .collect();
+            //pipeline: assume these land, a rejection walks next_index back
+            self.next_index
+                .insert(peer.clone(), prev_log_index + entries.len() + 1);
+            Message::new(
+                self.node.clone(),
+                peer.clone(),
+                id,
+                RaftPayload::AppendEntries {
+                    term: self.term,
+                    prev_log_index,
+                    prev_log_term: self.term_at(prev_log_index),
+                    entries,
+                    leader_commit: self.commit_index,
+                },
+            )
+            .send_self(&mut *output)
+            .context(format!("append entries to {peer}"))?;
+        }
+        Ok(())
+    }
+
+    fn send_snapshot(
+        &mut self,
+        peer: &str,
+        offset: usize,
+        id: &mut usize,
+        output: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        let Some(snapshot) = &self.snapshot else {
+            return Ok(());
+        };
+        let offset = offset.min(snapshot.len());
+        //cut on a char boundary so every chunk is valid on its own
+        let mut end = (offset + self.config.snapshot_chunk).min(snapshot.len());
+        while !snapshot.is_char_boundary(end) {
+            end -= 1;
+        }
+        let transfer = Transfer {
+            index: self.snapshot_index,
+            offset,
+            sent: Instant::now(),
+        };
+        self.transfers.insert(peer.to_string(), transfer);
+        Message::new(
+            self.node.clone(),
+            peer.to_string(),
+            id,
+            RaftPayload::<S::Command>::InstallSnapshot {
+                term: self.term,
+                last_included_index: self.snapshot_index,
+                last_included_term: self.snapshot_term,
+                offset,
+                data: snapshot[offset..end].to_string(),
+                done: end == snapshot.len(),
+            },
+        )
+        .send_self(&mut *output)
+        .context(format!("install snapshot on {peer}"))
+    }
+
+    //returns the next expected offset and whether the snapshot is now installed
+    fn receive_snapshot(
+        &mut self,
+        last_included_index: usize,
+        last_included_term: usize,
+        offset: usize,
+        data: String,
+        done: bool,
+    ) -> anyhow::Result<(usize, bool)> {
+        //already have everything it covers
+        if last_included_index <= self.last_applied {
+            return Ok((offset + data.len(), true));
+        }
+        let buffer = match &mut self.incoming {
+            Some((index, buffer)) if *index == last_included_index => buffer,
+            _ => &mut self.incoming.insert((last_included_index, String::new())).1,
+        };
+        if offset != buffer.len() {
+            //out of order chunk, ask the leader to resume from what we have
+            return Ok((buffer.len(), false));
+        }
+        buffer.push_str(&data);
+        if !done {
+            return Ok((buffer.len(), false));
+        }
+
+        let (_, snapshot) = self.incoming.take().expect("snapshot buffer just written");
+        self.state
+            .restore(&snapshot)
+            .context("restore state machine from snapshot")?;
+        //keep any suffix that agrees with the snapshot, otherwise the whole log is stale
+        if self.last_index() >= last_included_index
+            && self.term_at(last_included_index) == last_included_term
+        {
+            self.log.drain(..last_included_index - self.snapshot_index);
+        } else {
+            self.log.clear();
+        }
+        let len = snapshot.len();
+        self.snapshot_index = last_included_index;
+        self.snapshot_term = last_included_term;
+        self.snapshot = Some(snapshot);
+        self.commit_index = self.commit_index.max(last_included_index);
+        self.last_applied = last_included_index;
+        Ok((len, true))
+    }
+
+    fn append_from_leader(
+        &mut self,
+        prev_log_index: usize,
+        prev_log_term: usize,
+        entries: Vec<Entry<S::Command>>,
+        leader_commit: usize,
+    ) -> (bool, usize) {
+        if prev_log_index > self.last_index() {
+            return (false, self.last_index());
+        }
+        if prev_log_index >= self.snapshot_index && self.term_at(prev_log_index) != prev_log_term {
+            return (
+                false,
+                prev_log_index.saturating_sub(1).max(self.snapshot_index),
+            );
+        }
+        let verified = prev_log_index + entries.len();
+        for (offset, entry) in entries.into_iter().enumerate() {
+            let index = prev_log_index + 1 + offset;
+            //already folded into our snapshot
+            if index <= self.snapshot_index {
+                continue;
+            }
+            if index <= self.last_index() {
+                if self.term_at(index) == entry.term {
+                    continue;
+                }
+                //conflicting suffix is never committed, drop it
+                self.log.truncate(index - self.snapshot_index - 1);
+            }
+            self.log.push(entry);
+        }
+        //anything past what the leader just sent may still be a stale suffix
+        if leader_commit > self.commit_index {
+            self.commit_index = leader_commit.min(verified);
+        }
+        (true, verified)
+    }
+
+    //highest index stored on a majority and written in this term
+    fn advance_commit(&mut self) {
+        let mut matched: Vec<usize> = self.match_index.values().copied().collect();
+        matched.push(self.last_index());
+        matched.sort_unstable_by(|a, b| b.cmp(a));
+        let candidate = matched[self.quorum() - 1];
+        if candidate > self.commit_index && self.term_at(candidate) == self.term {
+            self.commit_index = candidate;
+        }
+    }
+
+    fn apply_committed(&mut self) -> Vec<Applied<S::Output>> {
+        let mut applied = Vec::new();
+        while self.last_applied < self.commit_index {
+            self.last_applied += 1;
+            let entry = self.log[self.last_applied - self.snapshot_index - 1].clone();
+            applied.push(Applied {
+                index: self.last_applied,
+                output: entry.command.map(|c| self.state.apply(c)),
+            });
+        }
+        applied
+    }
+}
+
+impl<S: StateMachine> Replicator<S> for Raft<S> {
+    type Payload = RaftPayload<S::Command>;
+
+    fn from_init(init: &Init, state: S) -> Self {
+        Self::new(init, state, RaftConfig::default())
+    }
+
+    fn is_leader(&self) -> bool {
+        self.role == Role::Leader
+    }
+
+    fn leader(&self) -> Option<&str> {
+        match self.role {
+            Role::Leader => Some(&self.node),
+            _ => self.leader.as_deref(),
+        }
+    }
+
+    fn state(&self) -> &S {
+        &self.state
+    }
+
+    fn propose(
+        &mut self,
+        command: S::Command,
+        id: &mut usize,
+        output: &mut impl Write,
+    ) -> anyhow::Result<Option<usize>> {
+        if self.role != Role::Leader {
+            return Ok(None);
+        }
+        self.log.push(Entry {
+            term: self.term,
+            command: Some(command),
+        });
+        let index = self.last_index();
+        if self.peers.is_empty() {
+            self.advance_commit();
+        } else {
+            self.replicate(id, output)?;
+        }
+        Ok(Some(index))
+    }
+
+    //election and heartbeat timers, then apply whatever has committed
+    fn tick(
+        &mut self,
+        id: &mut usize,
+        output: &mut impl Write,
+    ) -> anyhow::Result<Vec<Applied<S::Output>>> {
+        let now = Instant::now();
+        match self.role {
+            Role::Leader => {
+                let due = self
+                    .last_heartbeat
+                    .is_none_or(|t| now.duration_since(t) >= self.config.heartbeat_interval);
+                if due {
+                    self.replicate(id, output)?;
+                }
+            }
+            Role::Follower | Role::Candidate => {
+                if now >= self.election_deadline {
+                    self.start_election(id, output)?;
+                }
+            }
+        }
+        let applied = self.apply_committed();
+        self.maybe_compact()?;
+        Ok(applied)
+    }
+
+    fn handle(
+        &mut self,
+        input: Message<Self::Payload>,
+        id: &mut usize,
+        output: &mut impl Write,
+    ) -> anyhow::Result<Vec<Applied<S::Output>>> {
+        let src = input.src;
+        match input.body.payload {
+            RaftPayload::RequestVote {
+                term,
+                last_log_index,
+                last_log_term,
+            } => {
+                self.observe_term(term);
+                let up_to_date =
+                    (last_log_term, last_log_index) >= (self.last_term(), self.last_index());
+                let vote_granted = term == self.term
+                    && up_to_date
+                    && self.voted_for.as_ref().is_none_or(|v| v == &src);
+                if vote_granted {
+                    self.voted_for = Some(src.clone());
+                    self.reset_election_timer();
+                }
+                Message::new(
+                    self.node.clone(),
+                    src,
+                    id,
+                    RaftPayload::<S::Command>::RequestVoteOk {
+                        term: self.term,
+                        vote_granted,
+                    },
+                )
+                .send_self(&mut *output)
+                .context("respond to request_vote")?;
+            }
+
+            RaftPayload::RequestVoteOk { term, vote_granted } => {
+                self.observe_term(term);
+                if self.role == Role::Candidate && term == self.term && vote_granted {
+                    if !self.votes.contains(&src) {
+                        self.votes.push(src);
+                    }
+                    if self.votes.len() >= self.quorum() {
+                        self.become_leader(id, output)?;
+                    }
+                }
+            }
+
+            RaftPayload::AppendEntries {
+                term,
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+            } => {
+                self.observe_term(term);
+                let (success, match_index) = if term < self.term {
+                    (false, self.last_index())
+                } else {
+                    self.role = Role::Follower;
+                    self.leader = Some(src.clone());
+                    self.reset_election_timer();
+                    self.append_from_leader(prev_log_index, prev_log_term, entries, leader_commit)
+                };
+                Message::new(
+                    self.node.clone(),
+                    src,
+                    id,
+                    RaftPayload::<S::Command>::AppendEntriesOk {
+                        term: self.term,
+                        success,
+                        match_index,
+                    },
+                )
+                .send_self(&mut *output)
+                .context("respond to append_entries")?;
+            }
+
+            RaftPayload::AppendEntriesOk {
+                term,
+                success,
+                match_index,
+            } => {
+                self.observe_term(term);
+                if self.role == Role::Leader && term == self.term {
+                    if success {
+                        let known = self.match_index.entry(src.clone()).or_insert(0);
+                        *known = (*known).max(match_index);
+                        let next = self.next_index.entry(src).or_insert(1);
+                        *next = (*next).max(match_index + 1);
+                        self.advance_commit();
+                    } else {
+                        let next = self.next_index.entry(src).or_insert(1);
+                        *next = (*next - 1).min(match_index + 1).max(1);
+                    }
+                }
+            }
+
+            RaftPayload::InstallSnapshot {
+                term,
+                last_included_index,
+                last_included_term,
+                offset,
+                data,
+                done,
+            } => {
+                self.observe_term(term);
+                let (offset, done) = if term < self.term {
+                    (offset, false)
+                } else {
+                    self.role = Role::Follower;
+                    self.leader = Some(src.clone());
+                    self.reset_election_timer();
+                    self.receive_snapshot(
+                        last_included_index,
+                        last_included_term,
+                        offset,
+                        data,
+                        done,
+                    )?
+                };
+                Message::new(
+                    self.node.clone(),
+                    src,
+                    id,
+                    RaftPayload::<S::Command>::InstallSnapshotOk {
+                        term: self.term,
+                        last_included_index,
+                        offset,
+                        done,
+                    },
+                )
+                .send_self(&mut *output)
+                .context("respond to install_snapshot")?;
+            }
+
+            RaftPayload::InstallSnapshotOk {
+                term,
+                last_included_index,
+                offset,
+                done,
+            } => {
+                self.observe_term(term);
+                if self.role == Role::Leader && term == self.term {
+                    if done {
+                        self.transfers.remove(&src);
+                        let known = self.match_index.entry(src.clone()).or_insert(0);
+                        *known = (*known).max(last_included_index);
+                        let next = self.next_index.entry(src).or_insert(1);
+                        *next = (*next).max(last_included_index + 1);
+                        self.advance_commit();
+                    } else if last_included_index == self.snapshot_index {
+                        //an ack for the chunk in flight (or the follower asking to resume
+                        //elsewhere) sends the next one, a repeated ack for it is ignored
+                        let in_flight = self
+                            .transfers
+                            .get(&src)
+                            .filter(|t| t.index == last_included_index)
+                            .map(|t| t.offset);
+                        if in_flight != Some(offset) {
+                            self.send_snapshot(&src, offset, id, output)?;
+                        }
+                    }
+                }
+            }
+        }
+        let applied = self.apply_committed();
+        self.maybe_compact()?;
+        Ok(applied)
+    }
+}
+
+fn random_timeout(config: &RaftConfig) -> Duration {
+    let (low, high) = config.election_timeout;
+    rand::thread_rng().gen_range(low..=high)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::replication::sim::{Appends, Sim};
+
+    use super::*;
+
+    //elections only happen when a test forces one, and every tick is a heartbeat
+    fn sim(n: usize, config: RaftConfig) -> Sim<Raft<Appends>> {
+        let config = RaftConfig {
+            election_timeout: (Duration::from_secs(3600), Duration::from_secs(3600)),
+            heartbeat_interval: Duration::ZERO,
+            ..config
+        };
+        Sim::new(n, |init| {
+            Raft::new(init, Appends::default(), config.clone())
+        })
+    }
+
+    fn elect(sim: &mut Sim<Raft<Appends>>, i: usize) {
+        sim.nodes[i].election_deadline = Instant::now();
+        sim.run(3);
+        assert!(sim.nodes[i].is_leader(), "n{i} should have won");
+    }
+
+    fn chunks(sim: &Sim<Raft<Appends>>) -> usize {
+        sim.delivered
+            .iter()
+            .filter(|m| matches!(m.body.payload, RaftPayload::InstallSnapshot { .. }))
+            .count()
+    }
+
+    #[test]
+    fn elects_a_leader_and_replicates() {
+        let mut sim = sim(3, RaftConfig::default());
+        elect(&mut sim, 0);
+        for i in 1..3 {
+            assert_eq!(sim.nodes[i].role(), Role::Follower);
+            assert_eq!(sim.nodes[i].leader(), Some("n0"));
+        }
+        for command in [1, 2, 3] {
+            sim.propose(0, command);
+        }
+        sim.run(3);
+        for i in 0..3 {
+            assert_eq!(sim.values(i), [1, 2, 3]);
+        }
+    }
+
+    #[test]
+    fn followers_do_not_propose() {
+        let mut sim = sim(3, RaftConfig::default());
+        elect(&mut sim, 0);
+        assert_eq!(sim.propose(1, 7), None);
+    }
+
+    #[test]
+    fn minority_leader_cannot_commit() {
+        let mut sim = sim(5, RaftConfig::default());
+        elect(&mut sim, 0);
+        sim.propose(0, 1);
+        sim.run(3);
+
+        sim.partition(&[0, 1]);
+        sim.propose(0, 2);
+        sim.run(5);
+        let stuck = sim.nodes[0].commit_index();
+        assert_eq!(sim.values(0), [1]);
+
+        //the majority side moves on without the old leader
+        elect(&mut sim, 2);
+        sim.propose(2, 3);
+        sim.run(3);
+        for i in 2..5 {
+            assert_eq!(sim.values(i), [1, 3]);
+        }
+        assert_eq!(sim.nodes[0].commit_index(), stuck);
+    }
+
+    #[test]
+    fn healed_partition_drops_the_uncommitted_suffix() {
+        let mut sim = sim(3, RaftConfig::default());
+        elect(&mut sim, 0);
+        sim.propose(0, 1);
+        sim.run(3);
+
+        sim.partition(&[0]);
+        sim.propose(0, 2);
+        sim.propose(0, 3);
+        sim.run(3);
+        elect(&mut sim, 1);
+        sim.propose(1, 4);
+        sim.run(3);
+
+        sim.blocked.clear();
+        sim.run(5);
+        assert_eq!(sim.nodes[0].role(), Role::Follower);
+        assert_eq!(sim.nodes[0].term(), sim.nodes[1].term());
+        for i in 0..3 {
+            assert_eq!(sim.values(i), [1, 4]);
+            assert_eq!(sim.nodes[i].last_index(), sim.nodes[1].last_index());
+        }
+    }
+
+    #[test]
+    fn lagging_follower_gets_one_snapshot_stream() {
+        let mut sim = sim(
+            3,
+            RaftConfig {
+                snapshot_threshold: 5,
+                snapshot_chunk: 8,
+                ..RaftConfig::default()
+            },
+        );
+        elect(&mut sim, 0);
+        sim.partition(&[2]);
+        for command in 0..20 {
+            sim.propose(0, command);
+        }
+        sim.run(3);
+        assert!(sim.nodes[0].snapshot_index > 0);
+
+        sim.blocked.clear();
+        //every round is a heartbeat, none of them may start another stream
+        sim.run(20);
+        let expected: Vec<u64> = (0..20).collect();
+        assert_eq!(sim.values(2), expected);
+        let snapshot = sim.nodes[0].snapshot.as_ref().unwrap();
+        assert_eq!(chunks(&sim), snapshot.len().div_ceil(8));
+    }
+
+    #[test]
+    fn unacked_snapshot_chunk_is_resent() {
+        let mut sim = sim(
+            3,
+            RaftConfig {
+                snapshot_threshold: 5,
+                snapshot_chunk: 8,
+                snapshot_retry: Duration::from_millis(20),
+                ..RaftConfig::default()
+            },
+        );
+        elect(&mut sim, 0);
+        sim.partition(&[2]);
+        for command in 0..20 {
+            sim.propose(0, command);
+        }
+        sim.run(3);
+        sim.blocked.clear();
+        //the first chunk is lost on the way, nothing else starts a new stream
+        let mut lost = false;
+        sim.drop = Box::new(move |m| {
+            let chunk = matches!(m.body.payload, RaftPayload::InstallSnapshot { .. });
+            let drop = chunk && !lost;
+            lost |= chunk;
+            drop
+        });
+        sim.run(5);
+        assert!(sim.values(2).is_empty());
+
+        std::thread::sleep(Duration::from_millis(30));
+        sim.run(20);
+        let expected: Vec<u64> = (0..20).collect();
+        assert_eq!(sim.values(2), expected);
+    }
+
+    #[test]
+    fn conflicting_entries_are_truncated() {
+        let mut sim = sim(3, RaftConfig::default());
+        let follower = &mut sim.nodes[1];
+        for term in [1, 1, 2] {
+            follower.log.push(Entry {
+                term,
+                command: Some(term as u64),
+            });
+        }
+        let entries = vec![Entry {
+            term: 3,
+            command: Some(9),
+        }];
+        assert_eq!(follower.append_from_leader(1, 1, entries, 0), (true, 2));
+        let terms: Vec<usize> = follower.log.iter().map(|e| e.term).collect();
+        assert_eq!(terms, [1, 3]);
+
+        //prev entry doesn't match: rejected, nothing changes
+        assert!(!follower.append_from_leader(2, 2, Vec::new(), 0).0);
+        assert_eq!(follower.log.len(), 2);
+    }
+
+    #[test]
+    fn only_current_term_entries_commit_by_counting() {
+        let mut sim = sim(3, RaftConfig::default());
+        let leader = &mut sim.nodes[0];
+        leader.role = Role::Leader;
+        leader.term = 3;
+        for term in [1, 2] {
+            leader.log.push(Entry {
+                term,
+                command: None,
+            });
+        }
+        leader.match_index = HashMap::from([("n1".to_string(), 2), ("n2".to_string(), 0)]);
+        leader.advance_commit();
+        //index 2 is on a majority but from an older term
+        assert_eq!(leader.commit_index(), 0);
+
+        leader.log.push(Entry {
+            term: 3,
+            command: None,
+        });
+        leader.match_index.insert("n1".to_string(), 3);
+        leader.advance_commit();
+        assert_eq!(leader.commit_index(), 3);
+    }
+}
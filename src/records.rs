@@ -0,0 +1,42 @@
+/*
+    Helpers shared by the kafka-style log nodes.
+    A poll can cap how many records (and roughly how many bytes) it wants back
+    per key, so responses stay a sensible size however long the log grows.
+*/
+
+use serde::{Deserialize, Serialize};
+
+//optional per-key limits on a poll, both absent means everything
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct PollLimits {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_messages: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_bytes: Option<usize>,
+}
+
+impl PollLimits {
+    //take records in offset order until a limit is hit. the first record always
+    //goes out even if it's over max_bytes, otherwise the consumer could never move on
+    pub fn window(&self, records: impl IntoIterator<Item = (usize, usize)>) -> Vec<(usize, usize)> {
+        let mut bytes = 0;
+        let mut window = Vec::new();
+        for (offset, msg) in records {
+            if self.max_messages.is_some_and(|max| window.len() >= max) {
+                break;
+            }
+            bytes += record_size(offset, msg);
+            if !window.is_empty() && self.max_bytes.is_some_and(|max| bytes > max) {
+                break;
+            }
+            window.push((offset, msg));
+        }
+        window
+    }
+}
+
+//size of the record as it goes out in a poll_ok, `[offset,msg],`
+fn record_size(offset: usize, msg: usize) -> usize {
+    let digits = |n: usize| n.checked_ilog10().unwrap_or(0) as usize + 1;
+    digits(offset) + digits(msg) + 4
+}
@@ -0,0 +1,443 @@
+/*
+    Common interface for the consensus engines (raft, multi-paxos), so a node
+    can pick its replication engine with a type parameter.
+    `ClientNode` serves maelstrom clients on top of either engine: followers
+    forward client ops to the leader, every op goes through the log and the
+    node the client asked answers once the op is applied there.
+*/
+
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    io::{StdoutLock, Write},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{ErrorCode, Event, Init, Message, Node};
+
+//deterministic state machine replicated by the log
+pub trait StateMachine {
+    type Command: Clone + Debug + Serialize + DeserializeOwned + Send + 'static;
+    type Output;
+
+    fn apply(&mut self, command: Self::Command) -> Self::Output;
+
+    //serialized state, used to compact the log
+    fn snapshot(&self) -> anyhow::Result<String>;
+
+    //replace the state with a snapshot taken by `snapshot`
+    fn restore(&mut self, snapshot: &str) -> anyhow::Result<()>;
+}
+
+//a committed command that has been applied, so the node can answer whoever proposed it.
+//output is None for engine no-ops
+#[derive(Debug)]
+pub struct Applied<O> {
+    pub index: usize,
+    pub output: Option<O>,
+}
+
+pub trait Replicator<S: StateMachine>: Sized {
+    //engine messages, embedded in the node's own payload
+    type Payload: Debug + Clone + Serialize + DeserializeOwned + Send + 'static;
+
+    fn from_init(init: &Init, state: S) -> Self;
+
+    fn is_leader(&self) -> bool;
+
+    //best known leader, for forwarding client requests
+    fn leader(&self) -> Option<&str>;
+
+    fn state(&self) -> &S;
+
+    //submit a command on the leader, returns the log index it will apply at if it commits
+    fn propose(
+        &mut self,
+        command: S::Command,
+        id: &mut usize,
+        output: &mut impl Write,
+    ) -> anyhow::Result<Option<usize>>;
+
+    //drive the engine's timers, call periodically from an injected event
+    fn tick(
+        &mut self,
+        id: &mut usize,
+        output: &mut impl Write,
+    ) -> anyhow::Result<Vec<Applied<S::Output>>>;
+
+    fn handle(
+        &mut self,
+        input: Message<Self::Payload>,
+        id: &mut usize,
+        output: &mut impl Write,
+    ) -> anyhow::Result<Vec<Applied<S::Output>>>;
+}
+
+//a state machine that maelstrom clients talk to through `ClientNode`
+pub trait Service: StateMachine {
+    //client requests and replies
+    type Payload: Debug + Clone + Serialize + DeserializeOwned + Send + 'static;
+
+    //the op to put in the log, a reply to send straight away, or None for
+    //messages that need no answer
+    fn request(payload: Self::Payload) -> Option<Result<Self::Command, Self::Payload>>;
+
+    fn reply(output: Self::Output) -> Self::Payload;
+
+    fn error(code: ErrorCode, text: String) -> Self::Payload;
+}
+
+//a client op tagged with the node and token of the request so the right node answers
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Tagged<T> {
+    pub origin: String,
+    pub token: usize,
+    pub op: T,
+}
+
+//what the engine replicates: the service's own state, ops tagged with who asked
+#[derive(Debug, Default)]
+pub struct Served<S>(pub S);
+
+impl<S: StateMachine> StateMachine for Served<S> {
+    type Command = Tagged<S::Command>;
+    type Output = Tagged<S::Output>;
+
+    fn apply(&mut self, command: Self::Command) -> Self::Output {
+        Tagged {
+            origin: command.origin,
+            token: command.token,
+            op: self.0.apply(command.op),
+        }
+    }
+
+    fn snapshot(&self) -> anyhow::Result<String> {
+        self.0.snapshot()
+    }
+
+    fn restore(&mut self, snapshot: &str) -> anyhow::Result<()> {
+        self.0.restore(snapshot)
+    }
+}
+
+//client messages, ops forwarded between nodes and the consensus engine's own messages
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ClientPayload<C, O, E> {
+    Client(C),
+    Forward(ForwardPayload<O>),
+    Engine(E),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum ForwardPayload<O> {
+    //client op handed to the leader, origin is the node the client is waiting on
+    Forward(Tagged<O>),
+}
+
+type Payload<S, R> = ClientPayload<
+    <S as Service>::Payload,
+    <S as StateMachine>::Command,
+    <R as Replicator<Served<S>>>::Payload,
+>;
+
+//the maelstrom client has given up on a request by then, stop tracking it
+const PENDING_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct ClientNode<S, R> {
+    node: String,
+    id: usize,
+    engine: R,
+    //requests from our own clients, by token, waiting for their op to apply
+    pending: HashMap<usize, (Message<()>, Instant)>,
+    next_token: usize,
+    service: std::marker::PhantomData<S>,
+}
+
+impl<S: Service, R: Replicator<Served<S>>> ClientNode<S, R> {
+    //propose on the leader, forward to it otherwise. false if there's no known leader
+    fn submit(
+        &mut self,
+        command: Tagged<S::Command>,
+        output: &mut StdoutLock,
+    ) -> anyhow::Result<bool> {
+        if self.engine.is_leader() {
+            self.engine
+                .propose(command, &mut self.id, &mut *output)
+                .context("propose client op")?;
+            return Ok(true);
+        }
+        let Some(leader) = self.engine.leader() else {
+            return Ok(false);
+        };
+        Message::new(
+            self.node.clone(),
+            leader.to_string(),
+            &mut self.id,
+            ForwardPayload::Forward(command),
+        )
+        .send_self(&mut *output)
+        .context("forward client op to leader")?;
+        Ok(true)
+    }
+
+    //answer our own clients whose ops just applied
+    fn respond_applied(
+        &mut self,
+        applied: Vec<Applied<Tagged<S::Output>>>,
+        output: &mut StdoutLock,
+    ) -> anyhow::Result<()> {
+        for applied in applied.into_iter().filter_map(|a| a.output) {
+            if applied.origin != self.node {
+                continue;
+            }
+            let Some((request, _)) = self.pending.remove(&applied.token) else {
+                continue;
+            };
+            self.reply(request, S::reply(applied.op), output)?;
+        }
+        Ok(())
+    }
+
+    fn reply(
+        &mut self,
+        request: Message<()>,
+        payload: S::Payload,
+        output: &mut StdoutLock,
+    ) -> anyhow::Result<()> {
+        request
+            .with_payload(payload)
+            .derive_response(Some(&mut self.id))
+            .send_self(&mut *output)
+            .context("respond to client")
+    }
+}
+
+impl<S, R> Node<(), Payload<S, R>> for ClientNode<S, R>
+where
+    S: Service + Default,
+    R: Replicator<Served<S>>,
+{
+    fn from_init(
+        _state: (),
+        init: Init,
+        tx: std::sync::mpsc::Sender<Event<Payload<S, R>>>,
+    ) -> anyhow::Result<Self>
+    where
+        Self: Sized,
+    {
+        //drives elections, heartbeats and retransmissions
+        std::thread::spawn(move || loop {
+            std::thread::sleep(Duration::from_millis(20));
+            if tx.send(Event::Injected(())).is_err() {
+                break;
+            }
+        });
+
+        Ok(Self {
+            engine: R::from_init(&init, Served(S::default())),
+            node: init.node_id,
+            id: 1,
+            pending: HashMap::new(),
+            next_token: 0,
+            service: std::marker::PhantomData,
+        })
+    }
+
+    fn handle_input(
+        &mut self,
+        input: Event<Payload<S, R>>,
+        output: &mut StdoutLock,
+    ) -> anyhow::Result<()> {
+        match input {
+            Event::EOF => {}
+
+            Event::Injected(()) => {
+                let applied = self.engine.tick(&mut self.id, &mut *output)?;
+                self.respond_applied(applied, output)?;
+                self.pending
+                    .retain(|_, (_, since)| since.elapsed() < PENDING_TIMEOUT);
+            }
+
+            Event::Message(input) => {
+                let (payload, input) = input.into_parts();
+                let op = match payload {
+                    ClientPayload::Engine(payload) => {
+                        let applied = self.engine.handle(
+                            input.with_payload(payload),
+                            &mut self.id,
+                            &mut *output,
+                        )?;
+                        return self.respond_applied(applied, output);
+                    }
+
+                    //a peer's client op, pass it on if we've lost leadership meanwhile
+                    ClientPayload::Forward(ForwardPayload::Forward(command)) => {
+                        self.submit(command, output)?;
+                        return Ok(());
+                    }
+
+                    ClientPayload::Client(payload) => match S::request(payload) {
+                        Some(Ok(op)) => op,
+                        Some(Err(reply)) => return self.reply(input, reply, output),
+                        None => return Ok(()),
+                    },
+                };
+
+                let token = self.next_token;
+                self.next_token += 1;
+                let command = Tagged {
+                    origin: self.node.clone(),
+                    token,
+                    op,
+                };
+                if self.submit(command, output)? {
+                    self.pending.insert(token, (input, Instant::now()));
+                } else {
+                    //nothing was sent anywhere, so this is a definite failure
+                    let error = S::error(
+                        ErrorCode::TemporarilyUnavailable,
+                        "no leader known".to_string(),
+                    );
+                    self.reply(input, error, output)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+//in-process cluster for the engines' tests: messages go through a queue
+//instead of maelstrom, blocked links and `drop` lose them
+#[cfg(test)]
+pub(crate) mod sim {
+    use std::collections::{HashSet, VecDeque};
+
+    use serde::Deserialize;
+
+    use super::*;
+
+    //appends every command, so replicas can be compared entry by entry
+    #[derive(Debug, Default, Serialize, Deserialize)]
+    pub struct Appends {
+        pub values: Vec<u64>,
+    }
+
+    impl StateMachine for Appends {
+        type Command = u64;
+        type Output = ();
+
+        fn apply(&mut self, command: u64) {
+            self.values.push(command);
+        }
+
+        fn snapshot(&self) -> anyhow::Result<String> {
+            Ok(serde_json::to_string(self)?)
+        }
+
+        fn restore(&mut self, snapshot: &str) -> anyhow::Result<()> {
+            *self = serde_json::from_str(snapshot)?;
+            Ok(())
+        }
+    }
+
+    type Filter<P> = Box<dyn FnMut(&Message<P>) -> bool>;
+
+    pub struct Sim<R: Replicator<Appends>> {
+        pub nodes: Vec<R>,
+        queue: VecDeque<Message<R::Payload>>,
+        pub blocked: HashSet<(String, String)>,
+        //true drops the message
+        pub drop: Filter<R::Payload>,
+        pub delivered: Vec<Message<R::Payload>>,
+        id: usize,
+    }
+
+    impl<R: Replicator<Appends>> Sim<R> {
+        //nodes are named n0..n{n-1}
+        pub fn new(n: usize, make: impl Fn(&Init) -> R) -> Self {
+            let names: Vec<String> = (0..n).map(|i| format!("n{i}")).collect();
+            let nodes = names
+                .iter()
+                .map(|name| {
+                    make(&Init {
+                        node_id: name.clone(),
+                        node_ids: names.clone(),
+                    })
+                })
+                .collect();
+            Self {
+                nodes,
+                queue: VecDeque::new(),
+                blocked: HashSet::new(),
+                drop: Box::new(|_| false),
+                delivered: Vec::new(),
+                id: 0,
+            }
+        }
+
+        fn collect(&mut self, output: Vec<u8>) {
+            for line in output.split(|b| *b == b'\n').filter(|l| !l.is_empty()) {
+                let msg = serde_json::from_slice(line).expect("engine message");
+                self.queue.push_back(msg);
+            }
+        }
+
+        //one tick on every node, then deliver until nothing is in flight
+        pub fn round(&mut self) {
+            for i in 0..self.nodes.len() {
+                let mut output = Vec::new();
+                self.nodes[i].tick(&mut self.id, &mut output).unwrap();
+                self.collect(output);
+            }
+            while let Some(msg) = self.queue.pop_front() {
+                if self.blocked.contains(&(msg.src.clone(), msg.dest.clone())) || (self.drop)(&msg)
+                {
+                    continue;
+                }
+                self.delivered.push(msg.clone());
+                let i = msg.dest[1..].parse::<usize>().unwrap();
+                let mut output = Vec::new();
+                self.nodes[i]
+                    .handle(msg, &mut self.id, &mut output)
+                    .unwrap();
+                self.collect(output);
+            }
+        }
+
+        pub fn run(&mut self, rounds: usize) {
+            for _ in 0..rounds {
+                self.round();
+            }
+        }
+
+        pub fn propose(&mut self, i: usize, command: u64) -> Option<usize> {
+            let mut output = Vec::new();
+            let index = self.nodes[i]
+                .propose(command, &mut self.id, &mut output)
+                .unwrap();
+            self.collect(output);
+            index
+        }
+
+        //cut `group` off from everyone else, both directions
+        pub fn partition(&mut self, group: &[usize]) {
+            for a in 0..self.nodes.len() {
+                for b in 0..self.nodes.len() {
+                    if group.contains(&a) != group.contains(&b) {
+                        self.blocked.insert((format!("n{a}"), format!("n{b}")));
+                    }
+                }
+            }
+        }
+
+        pub fn values(&self, i: usize) -> &[u64] {
+            &self.nodes[i].state().values
+        }
+    }
+}
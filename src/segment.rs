@@ -0,0 +1,776 @@
+/*
+    Append-only on-disk log split into segment files, for nodes that need to
+    come back from a restart with their data.
+    <dir>/<base offset>.log holds one `[offset, record]` json line per record and
+    rolls over to a new segment past a size limit. <base offset>.index holds
+    sparse `offset position` lines so a read can seek close to an offset
+    instead of scanning its segment from the top.
+    Opening a directory recovers it: segments are found again, a torn last
+    line from a crash mid-write is cut off and the next offset picks up where
+    the log ends. index entries are only trusted once the record they point
+    at turns out to have the offset they name.
+*/
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    marker::PhantomData,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    //fsync after every append, nothing that was appended is lost in a crash
+    Always,
+    //fsync from tick() at most this often, a machine crash loses the last interval
+    Interval(Duration),
+    //leave it to the os, still safe against the process dying
+    Never,
+}
+
+impl FsyncPolicy {
+    //"always", "never" or an interval in milliseconds
+    pub fn parse(policy: &str) -> Option<Self> {
+        match policy {
+            "always" => Some(FsyncPolicy::Always),
+            "never" => Some(FsyncPolicy::Never),
+            ms => ms
+                .parse()
+                .ok()
+                .map(|ms| FsyncPolicy::Interval(Duration::from_millis(ms))),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SegmentConfig {
+    //a segment that has reached this size is closed and a new one started
+    pub max_segment_bytes: u64,
+    //bytes of log between two index entries
+    pub index_interval_bytes: u64,
+    pub fsync: FsyncPolicy,
+}
+
+impl Default for SegmentConfig {
+    fn default() -> Self {
+        Self {
+            max_segment_bytes: 1 << 20,
+            index_interval_bytes: 4 << 10,
+            fsync: FsyncPolicy::Interval(Duration::from_millis(100)),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Segment {
+    base: usize,
+    //sparse offset -> byte position in the segment
+    index: Vec<(usize, u64)>,
+    size: u64,
+    //log bytes written since the last index entry
+    since_index: u64,
+}
+
+#[derive(Debug)]
+pub struct SegmentLog<T> {
+    dir: PathBuf,
+    config: SegmentConfig,
+    //oldest first, the last one is being appended to
+    segments: Vec<Segment>,
+    log: File,
+    index: File,
+    next_offset: usize,
+    dirty: bool,
+    last_sync: Instant,
+    _record: PhantomData<fn() -> T>,
+}
+
+const STATE_FILE: &str = "state.json";
+
+impl<T: Serialize + DeserializeOwned> SegmentLog<T> {
+    //open the log in `dir`, recovering whatever a previous run left there
+    pub fn open(dir: impl Into<PathBuf>, config: SegmentConfig) -> anyhow::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir).context(format!("create log directory {}", dir.display()))?;
+
+        let mut bases = Vec::new();
+        for entry in fs::read_dir(&dir).context("list log directory")? {
+            let path = entry.context("read log directory entry")?.path();
+            if path.extension().is_some_and(|ext| ext == "log") {
+                let base = path
+                    .file_stem()
+                    .and_then(|stem| stem.to_str()?.parse::<usize>().ok());
+                bases.extend(base);
+            }
+        }
+        bases.sort_unstable();
+        if bases.is_empty() {
+            bases.push(0);
+        }
+
+        let mut segments = Vec::new();
+        for base in bases {
+            let size = fs::metadata(log_path(&dir, base)).map_or(0, |meta| meta.len());
+            let index = read_index(&index_path(&dir, base))?;
+            segments.push(Segment {
+                base,
+                index,
+                size,
+                since_index: 0,
+            });
+        }
+        let active = segments.last_mut().expect("at least one segment");
+        let next_offset = recover(&dir, active)?;
+
+        let (log, index) = open_segment(&dir, active.base)?;
+        Ok(Self {
+            dir,
+            config,
+            segments,
+            log,
+            index,
+            next_offset,
+            dirty: false,
+            last_sync: Instant::now(),
+            _record: PhantomData,
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    //first offset still on disk, retention may have removed earlier segments
+    pub fn start_offset(&self) -> usize {
+        self.segments[0].base
+    }
+
+    pub fn next_offset(&self) -> usize {
+        self.next_offset
+    }
+
+    pub fn append(&mut self, record: &T) -> anyhow::Result<usize> {
+        let offset = self.next_offset;
+        let mut line = serde_json::to_vec(&(offset, record)).context("serialize log record")?;
+        line.push(b'\n');
+
+        let active = self.segments.last().expect("at least one segment");
+        if active.size > 0 && active.size + line.len() as u64 > self.config.max_segment_bytes {
+            self.roll()?;
+        }
+        let active = self.segments.last_mut().expect("at least one segment");
+        if active.index.is_empty() || active.since_index >= self.config.index_interval_bytes {
+            //one write per line, so a crash can only tear the last one
+            let entry = format!("{offset} {}\n", active.size);
+            self.index
+                .write_all(entry.as_bytes())
+                .context("write index entry")?;
+            active.index.push((offset, active.size));
+            active.since_index = 0;
+        }
+        self.log.write_all(&line).context("append log record")?;
+        active.size += line.len() as u64;
+        active.since_index += line.len() as u64;
+        self.next_offset += 1;
+        self.dirty = true;
+
+        if self.config.fsync == FsyncPolicy::Always {
+            self.sync()?;
+        }
+        Ok(offset)
+    }
+
+    //records from `from` on, at most `max` of them
+    pub fn read_from(&self, from: usize, max: usize) -> anyhow::Result<Vec<(usize, T)>> {
+        let first = self
+            .segments
+            .partition_point(|segment| segment.base <= from)
+            .saturating_sub(1);
+        let mut records = Vec::new();
+        for segment in &self.segments[first..] {
+            let position = segment
+                .index
+                .iter()
+                .take_while(|(offset, _)| *offset <= from)
+                .last()
+                .map_or(0, |(_, position)| *position);
+            let mut file = File::open(log_path(&self.dir, segment.base))
+                .context(format!("open segment {}", segment.base))?;
+            file.seek(SeekFrom::Start(position))
+                .context("seek in segment")?;
+            for line in BufReader::new(file).take(segment.size - position).lines() {
+                if records.len() >= max {
+                    return Ok(records);
+                }
+                let line = line.context("read segment")?;
+                let (offset, record): (usize, T) =
+                    serde_json::from_str(&line).context("deserialize log record")?;
+                if offset >= from {
+                    records.push((offset, record));
+                }
+            }
+        }
+        Ok(records)
+    }
+
+    //remove whole segments that only hold records below `offset`, the active one always stays
+    pub fn truncate_before(&mut self, offset: usize) -> anyhow::Result<()> {
+        while self.segments.len() > 1 && self.segments[1].base <= offset {
+            let segment = self.segments.remove(0);
+            remove_segment(&self.dir, segment.base)?;
+        }
+        Ok(())
+    }
+
+    //remove every record from `offset` on, e.g. a replica's tail that its leader never had
+    pub fn truncate_from(&mut self, offset: usize) -> anyhow::Result<()> {
+        if offset >= self.next_offset {
+            return Ok(());
+        }
+        if offset <= self.start_offset() {
+            return self.reset(offset);
+        }
+        while self
+            .segments
+            .last()
+            .is_some_and(|segment| segment.base >= offset)
+        {
+            let segment = self.segments.pop().expect("segment just looked at");
+            remove_segment(&self.dir, segment.base)?;
+        }
+        let active = self
+            .segments
+            .last_mut()
+            .expect("a segment starts below offset");
+        let path = log_path(&self.dir, active.base);
+        let mut good = active
+            .index
+            .iter()
+            .take_while(|(indexed, _)| *indexed <= offset)
+            .last()
+            .map_or(0, |(_, position)| *position);
+        let mut file = File::open(&path).context(format!("open {}", path.display()))?;
+        file.seek(SeekFrom::Start(good))
+            .context("seek to index entry")?;
+        let mut reader = BufReader::new(file);
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            let read = reader
+                .read_until(b'\n', &mut line)
+                .context("read segment to truncate")?;
+            if read == 0 {
+                break;
+            }
+            let (record, _): (usize, serde_json::Value) =
+                serde_json::from_slice(&line).context("deserialize log record")?;
+            if record >= offset {
+                break;
+            }
+            good += read as u64;
+        }
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .and_then(|file| file.set_len(good))
+            .context("cut segment")?;
+        active.size = good;
+        active.index.retain(|(indexed, _)| *indexed < offset);
+        write_index(&self.dir, active)?;
+        active.since_index = active.size - active.index.last().map_or(0, |(_, p)| *p);
+        (self.log, self.index) = open_segment(&self.dir, active.base)?;
+        self.next_offset = offset;
+        self.dirty = true;
+        Ok(())
+    }
+
+    //throw everything away and carry on from `start`
+    pub fn reset(&mut self, start: usize) -> anyhow::Result<()> {
+        for segment in std::mem::take(&mut self.segments) {
+            remove_segment(&self.dir, segment.base)?;
+        }
+        (self.log, self.index) = open_segment(&self.dir, start)?;
+        self.segments.push(Segment {
+            base: start,
+            index: Vec::new(),
+            size: 0,
+            since_index: 0,
+        });
+        self.next_offset = start;
+        Ok(())
+    }
+
+    pub fn sync(&mut self) -> anyhow::Result<()> {
+        self.log.sync_data().context("fsync segment")?;
+        self.index.sync_data().context("fsync index")?;
+        self.dirty = false;
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+
+    //call regularly, runs the interval fsync policy
+    pub fn tick(&mut self) -> anyhow::Result<()> {
+        match self.config.fsync {
+            FsyncPolicy::Interval(every) if self.dirty && self.last_sync.elapsed() >= every => {
+                self.sync()
+            }
+            _ => Ok(()),
+        }
+    }
+
+    //small side state kept next to the log (e.g. committed offsets), replaced atomically
+    pub fn save_state<S: Serialize>(&self, state: &S) -> anyhow::Result<()> {
+        let tmp = self.dir.join(format!("{STATE_FILE}.tmp"));
+        let mut file = File::create(&tmp).context("create state file")?;
+        serde_json::to_writer(&mut file, state).context("serialize log state")?;
+        if self.config.fsync != FsyncPolicy::Never {
+            file.sync_data().context("fsync state file")?;
+        }
+        fs::rename(&tmp, self.dir.join(STATE_FILE)).context("replace state file")
+    }
+
+    pub fn load_state<S: DeserializeOwned>(&self) -> anyhow::Result<Option<S>> {
+        match fs::read(self.dir.join(STATE_FILE)) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map(Some)
+                .context("deserialize log state"),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).context("read state file"),
+        }
+    }
+
+    fn roll(&mut self) -> anyhow::Result<()> {
+        if self.config.fsync != FsyncPolicy::Never {
+            self.sync()?;
+        }
+        (self.log, self.index) = open_segment(&self.dir, self.next_offset)?;
+        self.segments.push(Segment {
+            base: self.next_offset,
+            index: Vec::new(),
+            size: 0,
+            since_index: 0,
+        });
+        Ok(())
+    }
+}
+
+fn log_path(dir: &Path, base: usize) -> PathBuf {
+    dir.join(format!("{base:020}.log"))
+}
+
+fn index_path(dir: &Path, base: usize) -> PathBuf {
+    dir.join(format!("{base:020}.index"))
+}
+
+fn open_segment(dir: &Path, base: usize) -> anyhow::Result<(File, File)> {
+    let open = |path: PathBuf| {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .context(format!("open {}", path.display()))
+    };
+    Ok((open(log_path(dir, base))?, open(index_path(dir, base))?))
+}
+
+fn remove_segment(dir: &Path, base: usize) -> anyhow::Result<()> {
+    for path in [log_path(dir, base), index_path(dir, base)] {
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                return Err(e).context(format!("remove {}", path.display()));
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+//a missing index only costs seek precision. a last line without its newline was
+//torn by a crash and is dropped, whatever it parses as
+fn read_index(path: &Path) -> anyhow::Result<Vec<(usize, u64)>> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).context(format!("read {}", path.display())),
+    };
+    Ok(contents
+        .split_inclusive('\n')
+        .filter_map(|line| {
+            let (offset, position) = line.strip_suffix('\n')?.split_once(' ')?;
+            Some((offset.parse().ok()?, position.parse().ok()?))
+        })
+        .collect())
+}
+
+fn write_index(dir: &Path, segment: &Segment) -> anyhow::Result<()> {
+    let entries: String = segment
+        .index
+        .iter()
+        .map(|(offset, position)| format!("{offset} {position}\n"))
+        .collect();
+    fs::write(index_path(dir, segment.base), entries).context("rewrite index")
+}
+
+//offset of the complete record starting at `position`, None if there isn't one
+fn record_at(path: &Path, position: u64) -> anyhow::Result<Option<usize>> {
+    let mut file = File::open(path).context(format!("open {}", path.display()))?;
+    file.seek(SeekFrom::Start(position))
+        .context("seek to index entry")?;
+    let mut line = Vec::new();
+    BufReader::new(file)
+        .read_until(b'\n', &mut line)
+        .context("read indexed record")?;
+    if line.last() != Some(&b'\n') {
+        return Ok(None);
+    }
+    Ok(serde_json::from_slice::<(usize, serde_json::Value)>(&line)
+        .ok()
+        .map(|(offset, _)| offset))
+}
+
+//scan the active segment from its last index entry, cut off anything after
+//the last complete record and return the offset that comes next
+fn recover(dir: &Path, segment: &mut Segment) -> anyhow::Result<usize> {
+    let path = log_path(dir, segment.base);
+    segment
+        .index
+        .retain(|(_, position)| *position < segment.size);
+    //an entry that doesn't point at the record it names would send the scan
+    //into the middle of a record, drop it and scan from the one before
+    let mut checked = Vec::with_capacity(segment.index.len());
+    for (offset, position) in std::mem::take(&mut segment.index) {
+        if record_at(&path, position)? == Some(offset) {
+            checked.push((offset, position));
+        }
+    }
+    segment.index = checked;
+    let (mut next_offset, mut good) = match segment.index.last() {
+        Some((offset, position)) => (*offset, *position),
+        None => (segment.base, 0),
+    };
+
+    if segment.size > good {
+        let mut file = File::open(&path).context(format!("open {}", path.display()))?;
+        file.seek(SeekFrom::Start(good))
+            .context("seek to last index entry")?;
+        let mut reader = BufReader::new(file);
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            let read = reader
+                .read_until(b'\n', &mut line)
+                .context("read segment during recovery")?;
+            if read == 0 || line.last() != Some(&b'\n') {
+                break;
+            }
+            let Ok((offset, _)) = serde_json::from_slice::<(usize, serde_json::Value)>(&line)
+            else {
+                break;
+            };
+            next_offset = offset + 1;
+            good += read as u64;
+        }
+    }
+
+    if good < segment.size {
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .and_then(|file| file.set_len(good))
+            .context("cut off torn segment tail")?;
+        segment.size = good;
+    }
+    //only the checked entries survive, and a torn last line can't run into
+    //the next entry appended after it
+    write_index(dir, segment)?;
+    segment.since_index = segment.size - segment.index.last().map_or(0, |(_, p)| *p);
+    Ok(next_offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //a fresh directory per test, removed again when the test is done. the process id
+    //keeps parallel runs apart
+    struct TempDir(PathBuf);
+
+    impl std::ops::Deref for TempDir {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn dir(name: &str) -> TempDir {
+        let dir = std::env::temp_dir().join(format!("segment-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        TempDir(dir)
+    }
+
+    //a few records per segment and an index entry every other record
+    fn config() -> SegmentConfig {
+        SegmentConfig {
+            max_segment_bytes: 40,
+            index_interval_bytes: 10,
+            fsync: FsyncPolicy::Never,
+        }
+    }
+
+    fn open(dir: &Path) -> SegmentLog<usize> {
+        SegmentLog::open(dir, config()).unwrap()
+    }
+
+    fn offsets(log: &SegmentLog<usize>, from: usize) -> Vec<usize> {
+        let records = log.read_from(from, usize::MAX).unwrap();
+        records.into_iter().map(|(offset, _)| offset).collect()
+    }
+
+    fn active_log(log: &SegmentLog<usize>) -> PathBuf {
+        log_path(&log.dir, log.segments.last().unwrap().base)
+    }
+
+    //every index entry points at the start of the record it names
+    fn assert_index_matches(log: &SegmentLog<usize>) {
+        for segment in &log.segments {
+            let entries = read_index(&index_path(&log.dir, segment.base)).unwrap();
+            assert_eq!(entries, segment.index);
+            let bytes = fs::read(log_path(&log.dir, segment.base)).unwrap();
+            for (offset, position) in entries {
+                let position = position as usize;
+                assert!(position <= bytes.len());
+                if position < bytes.len() {
+                    let line = bytes[position..].split(|b| *b == b'\n').next().unwrap();
+                    let (found, _): (usize, usize) = serde_json::from_slice(line).unwrap();
+                    assert_eq!(found, offset);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn reopen_picks_up_where_the_log_ended() {
+        let dir = dir("reopen");
+        let mut log = open(&dir);
+        for msg in 0..10 {
+            assert_eq!(log.append(&(msg * 10)).unwrap(), msg);
+        }
+        let records = log.read_from(3, 2).unwrap();
+        assert_eq!(records, vec![(3, 30), (4, 40)]);
+        drop(log);
+
+        let mut log = open(&dir);
+        assert_eq!(log.next_offset(), 10);
+        assert_eq!(offsets(&log, 0), (0..10).collect::<Vec<_>>());
+        assert_eq!(log.append(&100).unwrap(), 10);
+        assert_index_matches(&log);
+    }
+
+    #[test]
+    fn rolls_over_into_new_segments() {
+        let dir = dir("roll");
+        let mut log = open(&dir);
+        for msg in 0..20 {
+            log.append(&msg).unwrap();
+        }
+        assert!(log.segments.len() > 1);
+        for segment in &log.segments[..log.segments.len() - 1] {
+            assert!(segment.size <= config().max_segment_bytes);
+        }
+        //reads start in whichever segment holds the offset and carry on into the next
+        let base = log.segments[1].base;
+        assert_eq!(offsets(&log, base - 1), (base - 1..20).collect::<Vec<_>>());
+        drop(log);
+
+        let log = open(&dir);
+        assert_eq!(log.next_offset(), 20);
+        assert_eq!(offsets(&log, 0), (0..20).collect::<Vec<_>>());
+        assert_index_matches(&log);
+    }
+
+    #[test]
+    fn torn_tail_is_cut_off() {
+        let dir = dir("torn");
+        let mut log = open(&dir);
+        for msg in 0..5 {
+            log.append(&msg).unwrap();
+        }
+        let path = active_log(&log);
+        let size = log.segments.last().unwrap().size;
+        drop(log);
+        //a crash halfway through writing record 5, after its index entry went out
+        let mut index = OpenOptions::new()
+            .append(true)
+            .open(path.with_extension("index"))
+            .unwrap();
+        writeln!(index, "5 {size}").unwrap();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"[5,12").unwrap();
+        drop(file);
+
+        let mut log = open(&dir);
+        assert_eq!(log.next_offset(), 5);
+        assert_eq!(fs::metadata(&path).unwrap().len(), size);
+        assert_index_matches(&log);
+        assert_eq!(log.append(&5).unwrap(), 5);
+        assert_eq!(offsets(&log, 0), (0..6).collect::<Vec<_>>());
+        assert_index_matches(&log);
+    }
+
+    #[test]
+    fn torn_index_line_is_not_trusted() {
+        let dir = dir("torn-index");
+        let mut log = open(&dir);
+        for msg in 0..5 {
+            log.append(&msg).unwrap();
+        }
+        let path = active_log(&log);
+        let size = log.segments.last().unwrap().size;
+        let (last, position) = *log.segments.last().unwrap().index.last().unwrap();
+        drop(log);
+        //an entry that parses but points into the middle of a record, then one
+        //torn inside its position digits by a crash
+        let mut index = OpenOptions::new()
+            .append(true)
+            .open(path.with_extension("index"))
+            .unwrap();
+        writeln!(index, "{} {}", last + 1, position + 1).unwrap();
+        write!(index, "5 {}", size / 10).unwrap();
+        drop(index);
+
+        let mut log = open(&dir);
+        assert_eq!(log.next_offset(), 5);
+        assert_eq!(fs::metadata(&path).unwrap().len(), size);
+        assert_index_matches(&log);
+        //entries appended after a torn line don't run into it
+        for msg in 5..8 {
+            assert_eq!(log.append(&msg).unwrap(), msg);
+        }
+        drop(log);
+
+        let log = open(&dir);
+        assert_eq!(log.next_offset(), 8);
+        assert_eq!(offsets(&log, 0), (0..8).collect::<Vec<_>>());
+        assert_index_matches(&log);
+    }
+
+    #[test]
+    fn index_entries_past_the_end_are_dropped() {
+        let dir = dir("index");
+        let mut log = open(&dir);
+        for msg in 0..3 {
+            log.append(&msg).unwrap();
+        }
+        let path = active_log(&log);
+        let size = log.segments.last().unwrap().size;
+        drop(log);
+        //index entries for a record that was torn and one that never got written
+        let mut index = OpenOptions::new()
+            .append(true)
+            .open(path.with_extension("index"))
+            .unwrap();
+        writeln!(index, "3 {size}").unwrap();
+        writeln!(index, "4 {}", size + 20).unwrap();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"[3,").unwrap();
+        drop(file);
+
+        let log = open(&dir);
+        assert_eq!(log.next_offset(), 3);
+        let entries = read_index(&path.with_extension("index")).unwrap();
+        assert!(entries.iter().all(|(_, position)| *position <= size));
+        assert_index_matches(&log);
+    }
+
+    #[test]
+    fn truncate_before_drops_whole_segments_only() {
+        let dir = dir("truncate-before");
+        let mut log = open(&dir);
+        for msg in 0..20 {
+            log.append(&msg).unwrap();
+        }
+        let second = log.segments[1].base;
+        log.truncate_before(second + 1).unwrap();
+        assert_eq!(log.start_offset(), second);
+        assert!(!log_path(&dir, 0).exists());
+        assert_eq!(offsets(&log, 0), (second..20).collect::<Vec<_>>());
+
+        //the active segment stays even when everything is below the offset
+        log.truncate_before(100).unwrap();
+        assert_eq!(log.segments.len(), 1);
+        assert_eq!(log.next_offset(), 20);
+        drop(log);
+
+        let log = open(&dir);
+        assert_eq!(log.start_offset(), log.segments[0].base);
+        assert_eq!(log.next_offset(), 20);
+    }
+
+    #[test]
+    fn truncate_from_cuts_the_tail() {
+        let dir = dir("truncate-from");
+        let mut log = open(&dir);
+        for msg in 0..20 {
+            log.append(&msg).unwrap();
+        }
+        log.truncate_from(7).unwrap();
+        assert_eq!(log.next_offset(), 7);
+        assert!(log.segments.iter().all(|segment| segment.base < 7));
+        assert!(log
+            .segments
+            .last()
+            .unwrap()
+            .index
+            .iter()
+            .all(|(offset, _)| *offset < 7));
+        assert_index_matches(&log);
+        assert_eq!(log.append(&70).unwrap(), 7);
+        drop(log);
+
+        let log = open(&dir);
+        assert_eq!(log.next_offset(), 8);
+        let records = log.read_from(5, usize::MAX).unwrap();
+        assert_eq!(records, vec![(5, 5), (6, 6), (7, 70)]);
+    }
+
+    #[test]
+    fn reset_starts_over_at_an_offset() {
+        let dir = dir("reset");
+        let mut log = open(&dir);
+        for msg in 0..20 {
+            log.append(&msg).unwrap();
+        }
+        log.reset(100).unwrap();
+        assert_eq!((log.start_offset(), log.next_offset()), (100, 100));
+        assert!(offsets(&log, 0).is_empty());
+        assert_eq!(log.append(&1).unwrap(), 100);
+        drop(log);
+
+        let log = open(&dir);
+        assert_eq!((log.start_offset(), log.next_offset()), (100, 101));
+        assert_eq!(offsets(&log, 0), vec![100]);
+        assert_eq!(fs::read_dir(&*dir).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn state_survives_reopen() {
+        let dir = dir("state");
+        let log = open(&dir);
+        assert_eq!(log.load_state::<Vec<usize>>().unwrap(), None);
+        log.save_state(&vec![1, 2]).unwrap();
+        drop(log);
+        let log = open(&dir);
+        assert_eq!(log.load_state::<Vec<usize>>().unwrap(), Some(vec![1, 2]));
+    }
+}
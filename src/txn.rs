@@ -0,0 +1,181 @@
+/*
+    Transaction micro-ops as maelstrom encodes them, `[op, key, value]` arrays:
+    ["r", k, null] reads (the reply fills in the value, null for a key never
+    written), ["w", k, v] writes and ["append", k, v] appends to a list.
+    `V` is what a read returns: a number for txn-rw-register, a list for
+    txn-list-append.
+*/
+
+use serde::{
+    de::{DeserializeOwned, Error},
+    ser::SerializeTuple,
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use serde_json::Value;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MicroOp<V> {
+    Read(usize, Option<V>),
+    Write(usize, usize),
+    Append(usize, usize),
+}
+
+impl<V> MicroOp<V> {
+    pub fn key(&self) -> usize {
+        match self {
+            MicroOp::Read(key, _) | MicroOp::Write(key, _) | MicroOp::Append(key, _) => *key,
+        }
+    }
+}
+
+impl<V: Serialize> Serialize for MicroOp<V> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut tuple = serializer.serialize_tuple(3)?;
+        match self {
+            MicroOp::Read(key, value) => {
+                tuple.serialize_element("r")?;
+                tuple.serialize_element(key)?;
+                tuple.serialize_element(value)?;
+            }
+            MicroOp::Write(key, value) => {
+                tuple.serialize_element("w")?;
+                tuple.serialize_element(key)?;
+                tuple.serialize_element(value)?;
+            }
+            MicroOp::Append(key, value) => {
+                tuple.serialize_element("append")?;
+                tuple.serialize_element(key)?;
+                tuple.serialize_element(value)?;
+            }
+        }
+        tuple.end()
+    }
+}
+
+impl<'de, V: DeserializeOwned> Deserialize<'de> for MicroOp<V> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let (op, key, value) = <(String, usize, Value)>::deserialize(deserializer)?;
+        Ok(match op.as_str() {
+            "r" => MicroOp::Read(key, value_of(&op, key, value)?),
+            "w" => MicroOp::Write(key, value_of(&op, key, value)?),
+            "append" => MicroOp::Append(key, value_of(&op, key, value)?),
+            _ => return Err(D::Error::custom(format!("unknown micro-op {op:?}"))),
+        })
+    }
+}
+
+fn value_of<T: DeserializeOwned, E: Error>(op: &str, key: usize, value: Value) -> Result<T, E> {
+    serde_json::from_value(value).map_err(|e| E::custom(format!("{op} on key {key}: {e}")))
+}
+
+//a field that failed to parse is kept as raw json with the reason, so the node
+//can answer malformed-request instead of the whole message being dropped
+#[derive(Debug, Clone)]
+pub enum Lenient<T> {
+    Valid(T),
+    Invalid { raw: Value, error: String },
+}
+
+impl<T: Serialize> Serialize for Lenient<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Lenient::Valid(value) => value.serialize(serializer),
+            Lenient::Invalid { raw, .. } => raw.serialize(serializer),
+        }
+    }
+}
+
+impl<'de, T: DeserializeOwned> Deserialize<'de> for Lenient<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let raw = Value::deserialize(deserializer)?;
+        Ok(match serde_json::from_value(raw.clone()) {
+            Ok(value) => Lenient::Valid(value),
+            Err(e) => Lenient::Invalid {
+                raw,
+                error: e.to_string(),
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn parse<V: DeserializeOwned>(op: Value) -> Result<MicroOp<V>, serde_json::Error> {
+        serde_json::from_value(op)
+    }
+
+    #[test]
+    fn round_trips_every_op() {
+        let ops: Vec<MicroOp<Vec<usize>>> = vec![
+            MicroOp::Read(1, None),
+            MicroOp::Read(2, Some(vec![3, 4])),
+            MicroOp::Write(5, 6),
+            MicroOp::Append(7, 8),
+        ];
+        let encoded = serde_json::to_value(&ops).unwrap();
+        assert_eq!(
+            encoded,
+            json!([
+                ["r", 1, null],
+                ["r", 2, [3, 4]],
+                ["w", 5, 6],
+                ["append", 7, 8]
+            ])
+        );
+        let decoded: Vec<MicroOp<Vec<usize>>> = serde_json::from_value(encoded).unwrap();
+        assert_eq!(decoded, ops);
+        assert_eq!(
+            decoded.iter().map(MicroOp::key).collect::<Vec<_>>(),
+            [1, 2, 5, 7]
+        );
+    }
+
+    #[test]
+    fn read_value_follows_the_workload() {
+        assert_eq!(
+            parse::<usize>(json!(["r", 1, 9])).unwrap(),
+            MicroOp::Read(1, Some(9))
+        );
+        //a register read can't hold a list and the other way round
+        assert!(parse::<usize>(json!(["r", 1, [9]])).is_err());
+        assert!(parse::<Vec<usize>>(json!(["r", 1, 9])).is_err());
+    }
+
+    #[test]
+    fn unknown_op_is_rejected() {
+        let error = parse::<usize>(json!(["x", 1, 2])).unwrap_err();
+        assert!(
+            error.to_string().contains(r#"unknown micro-op "x""#),
+            "{error}"
+        );
+        assert!(parse::<usize>(json!(["r", 1])).is_err());
+    }
+
+    #[test]
+    fn write_needs_a_value() {
+        let error = parse::<usize>(json!(["w", 3, null])).unwrap_err();
+        assert!(error.to_string().contains("w on key 3"), "{error}");
+        assert!(parse::<usize>(json!(["append", 3, null])).is_err());
+    }
+
+    #[test]
+    fn lenient_keeps_what_it_could_not_parse() {
+        let valid: Lenient<Vec<MicroOp<usize>>> =
+            serde_json::from_value(json!([["w", 1, 2]])).unwrap();
+        assert!(matches!(&valid, Lenient::Valid(ops) if ops == &[MicroOp::Write(1, 2)]));
+
+        let raw = json!([["w", 1, 2], ["x", 1, 2]]);
+        let invalid: Lenient<Vec<MicroOp<usize>>> = serde_json::from_value(raw.clone()).unwrap();
+        let Lenient::Invalid { raw: kept, error } = &invalid else {
+            panic!("{invalid:?} should be invalid");
+        };
+        assert_eq!(kept, &raw);
+        assert!(error.contains("unknown micro-op"), "{error}");
+        //echoed back as it came in
+        assert_eq!(serde_json::to_value(&invalid).unwrap(), raw);
+    }
+}